@@ -1,45 +1,134 @@
 use gst::prelude::*;
-use std::{sync::{Mutex, Arc}, path::Path};
+use std::{sync::{Mutex, Arc}, str::FromStr};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
 
-use crate::{State, hlscmaf, utils};
+use crate::{State, hlscmaf, source, utils};
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub(crate) struct AudioStream {
     pub name: String,
     pub lang: String,
     pub default: bool,
     pub wave: String,
+    /// Overrides the segment duration of the output, in seconds. Not with ad breaks, their
+    /// splice points need the segment boundaries of all renditions to line up
+    pub segment_duration: Option<f64>,
+    /// Overrides the window size of the output
+    pub window_size: Option<usize>,
+}
+
+impl Default for AudioStream {
+    fn default() -> Self {
+        AudioStream {
+            name: String::new(),
+            lang: "en".to_string(),
+            default: false,
+            wave: "sine".to_string(),
+            segment_duration: None,
+            window_size: None,
+        }
+    }
 }
 
 impl AudioStream {
+    pub fn default_ladder() -> Vec<Self> {
+        vec![
+            AudioStream {
+                name: "audio_0".to_string(),
+                lang: "en".to_string(),
+                default: true,
+                wave: "sine".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
     pub fn setup(
         &self,
         state: Arc<Mutex<State>>,
         pipeline: &gst::Pipeline,
-        path: &Path,
-    ) -> Result<(), Error> {
-        let src = gst::ElementFactory::make("audiotestsrc")
-            .property("is-live", true)
-            .property_from_str("wave", &self.wave)
-            .build()?;
+        source: &mut source::Source,
+        output: &hlscmaf::Output,
+    ) -> Result<utils::Branch, Error> {
+        let settings = &output
+            .settings
+            .with_overrides(self.segment_duration, self.window_size);
+
+        let queue = gst::ElementFactory::make("queue").build()?;
+        let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+        let audioresample = gst::ElementFactory::make("audioresample").build()?;
         let enc = gst::ElementFactory::make("avenc_aac").build()?;
-        let mux = gst::ElementFactory::make("cmafmux")
-            .property_from_str("header-update-mode", "update")
-            .property("write-mehd", true)
-            .property("fragment-duration", 2000.mseconds())
-            .build()?;
-        let appsink = gst_app::AppSink::builder().buffer_list(true).build();
 
-        pipeline.add_many([&src, &enc, &mux, appsink.upcast_ref()])?;
+        pipeline.add_many([&queue, &audioconvert, &audioresample, &enc])?;
+        gst::Element::link_many([&queue, &audioconvert, &audioresample, &enc])?;
+
+        utils::probe_encoder(state, enc.clone(), self.name.clone());
+
+        let mut elements = vec![queue, audioconvert, audioresample, enc.clone()];
+
+        // The encoded stream goes to the fMP4 muxer, the MPEG-TS muxer or both of them
+        let mut heads = vec![];
+        if output.format.has_cmaf() {
+            let mux = gst::ElementFactory::make("cmafmux")
+                .property_from_str("header-update-mode", "update")
+                .property("write-mehd", true)
+                .property("fragment-duration", settings.segment_duration)
+                .build()?;
+            // Low-Latency HLS parts
+            if let Some(part_duration) = settings.part_duration {
+                mux.set_property("chunk-duration", part_duration);
+            }
+            // Without a live source nothing would ever preroll the sink before its first
+            // fragment
+            let appsink = gst_app::AppSink::builder().buffer_list(true).async_(false).build();
 
-        gst::Element::link_many([&src, &enc, &mux, appsink.upcast_ref()])?;
+            pipeline.add_many([&mux, appsink.upcast_ref()])?;
+            mux.link(&appsink)?;
 
-        utils::probe_encoder(state, enc, self.name.clone());
+            hlscmaf::setup(&appsink, &self.name, output, settings);
 
-        hlscmaf::setup(&appsink, &self.name, path);
+            heads.push(mux.clone());
+            elements.extend([mux, appsink.upcast()]);
+        }
+        if output.format.has_ts() {
+            // The muxer adds the ADTS headers itself
+            let (ts_elements, appsink) = utils::ts_mux(pipeline, None)?;
 
-        Ok(())
+            hlscmaf::setup_ts(&appsink, &self.name, output, settings, false);
+
+            heads.push(ts_elements[0].clone());
+            elements.extend(ts_elements);
+        }
+        elements.extend(utils::fan_out(pipeline, &enc, &heads)?);
+
+        utils::Branch::new(source.audio_pad(&self.wave)?, elements)
     }
 }
 
+
+impl FromStr for AudioStream {
+    type Err = Error;
+
+    // Parses `lang=en,wave=sine,default=true`, every key is optional.
+    // An empty name is filled in by the caller once all renditions are known.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stream = AudioStream::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "name" => stream.name = value.to_string(),
+                "lang" => stream.lang = value.to_string(),
+                "default" => stream.default = value.parse()?,
+                "wave" => stream.wave = value.to_string(),
+                "segment_duration" => stream.segment_duration = Some(value.parse()?),
+                "window_size" => stream.window_size = Some(value.parse()?),
+                _ => return Err(anyhow!("unknown audio stream property '{}'", key)),
+            }
+        }
+
+        Ok(stream)
+    }
+}
@@ -0,0 +1,296 @@
+use gst::prelude::*;
+use std::{collections::VecDeque, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use m3u8_rs::{AlternativeMedia, AlternativeMediaType, InstreamId};
+use serde::Deserialize;
+
+use crate::utils;
+
+/// The CEA-608 channel the captions are sent on, `CC1`.
+pub(crate) const CHANNEL: u8 = 1;
+/// The CEA-708 service the captions are sent on, `SERVICE1`.
+pub(crate) const SERVICE: u8 = 1;
+// Characters of a caption row
+const ROW_LENGTH: usize = 32;
+
+// Control codes of channel 1, each sent twice as decoders drop the repetition
+const RESUME_CAPTION_LOADING: [u8; 2] = [0x14, 0x20];
+const ERASE_NON_DISPLAYED_MEMORY: [u8; 2] = [0x14, 0x2e];
+const END_OF_CAPTION: [u8; 2] = [0x14, 0x2f];
+// Preamble address code of row 15, white
+const BOTTOM_ROW: [u8; 2] = [0x14, 0x60];
+
+// CEA-708 DefineWindow0: visible with row and column lock, its bottom center at 90% of the
+// height, one row of 32 columns, window and pen style 1
+const DEFINE_WINDOW: [u8; 7] = [0x98, 0x38, 0x80 | 90, 50, 0x70, 31, 0x09];
+// ClearWindows of window 0, and SetPenLocation to its start
+const CLEAR_WINDOW: [u8; 2] = [0x88, 0x01];
+const PEN_TO_START: [u8; 3] = [0x92, 0x00, 0x00];
+// Largest service block of a DTVCC packet
+const MAX_BLOCK_SIZE: usize = 31;
+// `cc_data` triplet markers of DTVCC packet starts and the rest of them, and padding
+const DTVCC_PACKET_START: u8 = 0xff;
+const DTVCC_PACKET_DATA: u8 = 0xfe;
+const DTVCC_PADDING: [u8; 3] = [0xfa, 0x00, 0x00];
+// DTVCC pairs per frame, well within the 9600 bit/s of the caption channel at 30 fps
+const DTVCC_PAIRS: usize = 8;
+
+/// What the captions embedded into the video show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Captions {
+    /// The running time, same as the time overlay
+    #[default]
+    Clock,
+    /// The name of the rendition
+    Name,
+}
+
+impl FromStr for Captions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clock" => Ok(Captions::Clock),
+            "name" => Ok(Captions::Name),
+            _ => Err(anyhow!("unknown captions '{}', expected clock or name", s)),
+        }
+    }
+}
+
+/// The `EXT-X-MEDIA` entries of the CEA-608 channel and the CEA-708 service in the
+/// `CLOSED-CAPTIONS` group `group_id`.
+pub(crate) fn renditions(group_id: &str) -> [AlternativeMedia; 2] {
+    let rendition = |name: &str, instream_id: InstreamId| AlternativeMedia {
+        media_type: AlternativeMediaType::ClosedCaptions,
+        group_id: group_id.to_string(),
+        language: Some("en".to_string()),
+        name: name.to_string(),
+        autoselect: true,
+        instream_id: Some(instream_id),
+        ..Default::default()
+    };
+
+    [
+        AlternativeMedia {
+            default: true,
+            ..rendition("captions", InstreamId::CC(CHANNEL))
+        },
+        rendition("captions (CEA-708)", InstreamId::Service(SERVICE)),
+    ]
+}
+
+/// Attaches CEA-608 and CEA-708 captions to every frame going into `enc`, which x264enc and
+/// x265enc write into the SEI of the frames.
+///
+/// The caption is replaced every second of running time and shown pop-on at the bottom row,
+/// so players joining at any point pick it up within a second. The CEA-708 window is defined
+/// along with every caption for the same reason.
+pub(crate) fn setup(captions: Captions, name: &str, enc: &gst::Element) {
+    let name = name.to_string();
+    let mut writer = Writer::default();
+    let mut shown_second = None;
+
+    enc.static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        if let Some(second) = utils::running_time(pad, buffer).map(gst::ClockTime::seconds) {
+            if shown_second != Some(second) {
+                shown_second = Some(second);
+                let text = match captions {
+                    Captions::Clock => {
+                        format!("{:02}:{:02}:{:02}", second / 3600, second / 60 % 60, second % 60)
+                    }
+                    Captions::Name => name.clone(),
+                };
+                writer.pop_on(&text);
+            }
+        }
+
+        let cc_data = writer.next_frame();
+        gst_video::VideoCaptionMeta::add(
+            buffer.make_mut(),
+            gst_video::VideoCaptionType::Cea708Raw,
+            &cc_data,
+        );
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+// CEA-608 byte pairs waiting to be sent, one pair per frame, and the `cc_data` triplets of
+// the CEA-708 DTVCC packets
+#[derive(Debug, Default)]
+struct Writer {
+    pending: VecDeque<[u8; 2]>,
+    dtvcc: VecDeque<[u8; 3]>,
+    // Of the next DTVCC packet, counting modulo 4
+    sequence: u8,
+}
+
+impl Writer {
+    // Replaces whatever is still pending with a caption showing `text`
+    fn pop_on(&mut self, text: &str) {
+        self.pending.clear();
+
+        for code in [RESUME_CAPTION_LOADING, ERASE_NON_DISPLAYED_MEMORY, BOTTOM_ROW] {
+            self.pending.extend([code, code]);
+        }
+
+        let chars = text.chars().take(ROW_LENGTH).map(char_code).collect::<Vec<_>>();
+        for pair in chars.chunks(2) {
+            self.pending.push_back([pair[0], pair.get(1).copied().unwrap_or(0)]);
+        }
+
+        self.pending.extend([END_OF_CAPTION, END_OF_CAPTION]);
+
+        self.queue_packet(text);
+    }
+
+    // Replaces the pending DTVCC packet with one showing `text` in window 0 of the service
+    fn queue_packet(&mut self, text: &str) {
+        let mut commands =
+            vec![DEFINE_WINDOW.to_vec(), CLEAR_WINDOW.to_vec(), PEN_TO_START.to_vec()];
+        commands.extend(text.chars().take(ROW_LENGTH).map(|c| vec![g0_code(c)]));
+
+        // Commands aren't split across service blocks
+        let mut blocks: Vec<Vec<u8>> = vec![];
+        for command in commands {
+            match blocks.last_mut() {
+                Some(block) if block.len() + command.len() <= MAX_BLOCK_SIZE => {
+                    block.extend(command)
+                }
+                _ => blocks.push(command),
+            }
+        }
+
+        let mut packet = vec![0];
+        for block in blocks {
+            packet.push((SERVICE << 5) | block.len() as u8);
+            packet.extend(block);
+        }
+        // A whole number of pairs, the header gives the size in pairs
+        if packet.len() % 2 == 1 {
+            packet.push(0);
+        }
+        packet[0] = (self.sequence << 6) | (packet.len() / 2) as u8;
+        self.sequence = (self.sequence + 1) % 4;
+
+        self.dtvcc.clear();
+        for (idx, pair) in packet.chunks(2).enumerate() {
+            let marker = if idx == 0 { DTVCC_PACKET_START } else { DTVCC_PACKET_DATA };
+            self.dtvcc.push_back([marker, pair[0], pair[1]]);
+        }
+    }
+
+    // CEA-708 `cc_data` of the next frame: a CEA-608 pair for field 1, nothing for field 2,
+    // followed by the next pairs of the DTVCC packet and padding
+    fn next_frame(&mut self) -> Vec<u8> {
+        let [first, second] = self.pending.pop_front().unwrap_or([0, 0]);
+        let mut cc_data = vec![0xfc, odd_parity(first), odd_parity(second), 0xfd, 0x80, 0x80];
+        for _ in 0..DTVCC_PAIRS {
+            cc_data.extend(self.dtvcc.pop_front().unwrap_or(DTVCC_PADDING));
+        }
+
+        cc_data
+    }
+}
+
+// The basic character set is ASCII, except for a few codes used for accented letters
+fn char_code(c: char) -> u8 {
+    match c {
+        '*' | '\\' | '^' | '_' | '`' | '{' | '|' | '}' | '~' => b'-',
+        ' '..='~' => c as u8,
+        _ => b'?',
+    }
+}
+
+// CEA-708 G0 is ASCII, with a music note instead of DEL
+fn g0_code(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        _ => b'?',
+    }
+}
+
+fn odd_parity(byte: u8) -> u8 {
+    let byte = byte & 0x7f;
+    if byte.count_ones() % 2 == 0 {
+        byte | 0x80
+    } else {
+        byte
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_pop_on_captions() {
+        let mut writer = Writer::default();
+        writer.pop_on("h264_0");
+
+        let frames = (0..12).map(|_| writer.next_frame()).collect::<Vec<_>>();
+        // Resume caption loading, twice
+        assert_eq!(frames[0][..6], [0xfc, 0x94, 0x20, 0xfd, 0x80, 0x80]);
+        assert_eq!(frames[1][..6], frames[0][..6]);
+        assert_eq!(frames[6][..3], [0xfc, 0x68, 0x32]);
+        assert_eq!(frames[8][..3], [0xfc, 0xad, 0xb0]);
+        // End of caption, twice
+        assert_eq!(frames[9][..3], [0xfc, 0x94, 0x2f]);
+        assert_eq!(frames[10][..6], frames[9][..6]);
+        // Nothing left to send
+        assert_eq!(frames[11][..3], [0xfc, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn sends_dtvcc_packets() {
+        let mut writer = Writer::default();
+        writer.pop_on("h264_0");
+
+        let frames = (0..2).map(|_| writer.next_frame()).collect::<Vec<_>>();
+        assert!(frames.iter().all(|frame| frame.len() == 6 + 3 * DTVCC_PAIRS));
+
+        // Header and one block of the window commands and the text
+        let size = 1 + 1 + DEFINE_WINDOW.len() + CLEAR_WINDOW.len() + PEN_TO_START.len() + 6;
+        assert_eq!(frames[0][6..9], [DTVCC_PACKET_START, (size / 2) as u8, 0x20 | 18]);
+        assert_eq!(frames[0][9..12], [DTVCC_PACKET_DATA, 0x98, 0x38]);
+        let packet = frames
+            .iter()
+            .flat_map(|frame| frame[6..].chunks(3))
+            .take_while(|triplet| triplet[0] != DTVCC_PADDING[0])
+            .flat_map(|triplet| triplet[1..].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(packet.len(), size);
+        assert_eq!(&packet[14..20], b"h264_0");
+
+        // The next packet continues the sequence
+        writer.pop_on("h264_0");
+        assert_eq!(writer.next_frame()[7] >> 6, 1);
+    }
+
+    #[test]
+    fn splits_long_captions_into_blocks() {
+        let mut writer = Writer::default();
+        writer.pop_on(&"a".repeat(40));
+
+        let packet =
+            writer.dtvcc.iter().flat_map(|triplet| triplet[1..].to_vec()).collect::<Vec<_>>();
+        // The first block is full, the rest of the row follows in a second one
+        assert_eq!(packet[1], 0x20 | MAX_BLOCK_SIZE as u8);
+        assert_eq!(packet[2 + MAX_BLOCK_SIZE], 0x20 | (12 + ROW_LENGTH - MAX_BLOCK_SIZE) as u8);
+    }
+
+    #[test]
+    fn truncates_long_captions() {
+        let mut writer = Writer::default();
+        writer.pop_on(&"a".repeat(40));
+
+        // Six control codes, one row and end of caption
+        assert_eq!(writer.pending.len(), 6 + ROW_LENGTH / 2 + 2);
+    }
+}
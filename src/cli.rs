@@ -0,0 +1,225 @@
+use std::path::PathBuf;
+
+use anyhow::Error;
+use clap::Parser;
+
+use crate::{
+    audio,
+    config::{self, Config},
+    captions, dash, encryption, hlscmaf, interstitials, scte35, subtitles, thumbnails, video,
+};
+
+/// Generates a live HLS and DASH stream out of test sources or a looped file.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub(crate) struct Args {
+    /// TOML or JSON file describing the whole ladder
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["video_streams", "audio_streams", "subtitle_streams"]
+    )]
+    pub config: Option<PathBuf>,
+
+    /// File or URI to loop as the content instead of test sources
+    #[arg(short, long, value_name = "FILE|URI")]
+    pub input: Option<String>,
+
+    /// Directory the master playlist and the renditions are written to
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Segments of the video and audio renditions: cmaf, ts or both. Both writes the MPEG-TS
+    /// renditions and their master playlist to the ts sub-directory of the output
+    #[arg(long, value_name = "FORMAT")]
+    pub segment_format: Option<hlscmaf::SegmentFormat>,
+
+    /// Serve the output over HTTP on this address, e.g. `0.0.0.0:8080`
+    #[arg(long, value_name = "ADDRESS")]
+    pub serve: Option<String>,
+
+    /// Video rendition, e.g. `codec=h264,bitrate=1024000,width=640,height=360`.
+    /// Can be repeated, `name` is optional.
+    #[arg(long = "video", value_name = "SPEC")]
+    pub video_streams: Vec<video::VideoStream>,
+
+    /// Audio rendition, e.g. `lang=en,wave=sine,default=true`.
+    /// Can be repeated, `name` is optional.
+    #[arg(long = "audio", value_name = "SPEC")]
+    pub audio_streams: Vec<audio::AudioStream>,
+
+    /// Subtitle rendition, e.g. `lang=en,cues=clock,format=imsc1,default=true,forced=false`.
+    /// Can be repeated, `name` is optional.
+    #[arg(long = "subtitles", value_name = "SPEC")]
+    pub subtitle_streams: Vec<subtitles::SubtitleStream>,
+
+    /// Embed CEA-608 and CEA-708 captions into the h264 and h265 renditions: clock or name
+    #[arg(long, value_name = "WHAT")]
+    pub captions: Option<captions::Captions>,
+
+    /// SCTE-35 ad break, e.g. `start=60,duration=30` in seconds from the first frame.
+    /// Can be repeated, splice points are moved to the closest segment boundary
+    #[arg(long = "ad-break", value_name = "SPEC")]
+    pub ad_breaks: Vec<scte35::AdBreak>,
+
+    /// HLS interstitial, e.g. `start=30,duration=10,restrict=skip+jump,cue=once`. Can be
+    /// repeated, the asset is generated unless `asset_uri` or `asset_list` is given
+    #[arg(long = "interstitial", value_name = "SPEC")]
+    pub interstitials: Vec<interstitials::Interstitial>,
+
+    /// Thumbnail sprites of the video, e.g. `width=160,height=90,columns=5,rows=2,segments=5`.
+    /// Every key is optional
+    #[arg(long, value_name = "SPEC")]
+    pub thumbnails: Option<thumbnails::Thumbnails>,
+
+    /// Duration of each segment, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub segment_duration: Option<f64>,
+
+    /// Number of segments listed in the media playlists
+    #[arg(long, value_name = "SEGMENTS")]
+    pub window_size: Option<usize>,
+
+    /// How the media playlists evolve: live, event, dvr or live-to-vod
+    #[arg(long, value_name = "MODE")]
+    pub playlist: Option<hlscmaf::PlaylistMode>,
+
+    /// Length of the playlists in dvr mode, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub dvr_window: Option<f64>,
+
+    /// How the DASH manifest addresses the segments: number or timeline
+    #[arg(long, value_name = "MODE")]
+    pub dash_addressing: Option<dash::Addressing>,
+
+    /// Enable Low-Latency HLS and DASH with parts of this duration, in seconds.
+    /// Requires --serve
+    #[arg(long, value_name = "SECONDS")]
+    pub part_duration: Option<f64>,
+
+    /// Append the segments of each rendition to a single file, addressed by byte ranges
+    #[arg(long)]
+    pub single_file: bool,
+
+    /// Latency target of Low-Latency DASH players, in seconds. Only with number addressing
+    #[arg(long, value_name = "SECONDS")]
+    pub dash_target_latency: Option<f64>,
+
+    /// Encrypt the segments: aes-128 or sample-aes for HLS only, cenc or cbcs with Clear Key
+    /// for HLS and DASH. Requires --serve for the keys
+    #[arg(long, value_name = "METHOD")]
+    pub encryption: Option<encryption::Method>,
+
+    /// Number of segments encrypted with the same key
+    #[arg(long, value_name = "SEGMENTS", requires = "encryption")]
+    pub key_rotation: Option<u32>,
+
+    /// Accept timed metadata on the /metadata endpoint, with this scheme URI for DASH players.
+    /// Can be repeated, requests use the first one unless they name another. Requires --serve
+    #[arg(long = "metadata-scheme", value_name = "URI")]
+    pub metadata_schemes: Vec<String>,
+
+    /// Stop after this many seconds instead of running forever
+    #[arg(short, long, value_name = "SECONDS")]
+    pub duration: Option<u64>,
+}
+
+impl Args {
+    /// Loads the config file if one was given, otherwise builds the config from the
+    /// command line. Explicit options override the values of the file.
+    pub fn config(&self) -> Result<Config, Error> {
+        let mut config = match self.config {
+            Some(ref path) => Config::load(path)?,
+            None => Config {
+                video: self.video_streams.clone(),
+                audio: self.audio_streams.clone(),
+                subtitles: self.subtitle_streams.clone(),
+                ..Default::default()
+            },
+        };
+
+        if let Some(ref input) = self.input {
+            config.input = Some(input.clone());
+        }
+
+        if let Some(ref output) = self.output {
+            config.output.path = output.clone();
+        }
+
+        if let Some(segment_format) = self.segment_format {
+            config.output.format = segment_format;
+        }
+
+        if let Some(ref address) = self.serve {
+            config.server = Some(config::Server {
+                address: address.clone(),
+            });
+        }
+
+        if let Some(segment_duration) = self.segment_duration {
+            config.segment_duration = segment_duration;
+        }
+
+        if let Some(window_size) = self.window_size {
+            config.window_size = window_size;
+        }
+
+        if let Some(playlist) = self.playlist {
+            config.playlist = playlist;
+        }
+
+        if let Some(dvr_window) = self.dvr_window {
+            config.dvr_window = dvr_window;
+        }
+
+        if let Some(dash_addressing) = self.dash_addressing {
+            config.dash_addressing = dash_addressing;
+        }
+
+        if let Some(part_duration) = self.part_duration {
+            config.part_duration = Some(part_duration);
+        }
+
+        if self.single_file {
+            config.single_file = true;
+        }
+
+        if let Some(dash_target_latency) = self.dash_target_latency {
+            config.dash_target_latency = Some(dash_target_latency);
+        }
+
+        if let Some(captions) = self.captions {
+            config.captions = Some(captions);
+        }
+
+        if let Some(thumbnails) = self.thumbnails {
+            config.thumbnails = Some(thumbnails);
+        }
+
+        if !self.ad_breaks.is_empty() {
+            config.ad_breaks = self.ad_breaks.clone();
+        }
+
+        if !self.interstitials.is_empty() {
+            config.interstitials = self.interstitials.clone();
+        }
+
+        if !self.metadata_schemes.is_empty() {
+            config.metadata = Some(config::Metadata {
+                schemes: self.metadata_schemes.clone(),
+            });
+        }
+
+        if let Some(method) = self.encryption {
+            let encryption = config.encryption.get_or_insert_with(Default::default);
+            encryption.method = method;
+
+            if let Some(key_rotation) = self.key_rotation {
+                encryption.key_rotation = key_rotation;
+            }
+        }
+
+        config.finish()
+    }
+}
@@ -0,0 +1,862 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Error};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    audio, captions, dash, encryption, hlscmaf, interstitials, scte35, subtitles, thumbnails,
+    utils, video,
+};
+
+const VIDEO_CODECS: &[&str] = &["h264", "h265", "av1"];
+const AUDIO_WAVES: &[&str] = &[
+    "sine", "square", "saw", "triangle", "silence", "white-noise", "pink-noise", "sine-table",
+    "ticks", "gaussian-noise", "red-noise", "blue-noise", "violet-noise",
+];
+
+/// The full description of what yatta produces, either read from a TOML/JSON file or built
+/// from the command line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// File or URI looped as the content of all renditions, test sources when unset
+    pub input: Option<String>,
+    pub output: Output,
+    /// Serves the output over HTTP when set
+    pub server: Option<Server>,
+    /// Duration of each segment, in seconds
+    pub segment_duration: f64,
+    /// Number of segments listed in the media playlists
+    pub window_size: usize,
+    pub playlist: hlscmaf::PlaylistMode,
+    /// Length of the playlists in `dvr` mode, in seconds
+    pub dvr_window: f64,
+    /// How the DASH manifest addresses the segments
+    pub dash_addressing: dash::Addressing,
+    /// Latency target of Low-Latency DASH players, in seconds. Three parts when unset, only
+    /// with `Addressing::Number`
+    pub dash_target_latency: Option<f64>,
+    /// Duration of the Low-Latency HLS parts and DASH chunks, in seconds. Plain HLS and DASH
+    /// when unset
+    pub part_duration: Option<f64>,
+    /// Appends the segments of each video and audio rendition to a single file, addressed by
+    /// byte ranges
+    pub single_file: bool,
+    /// Encrypts the segments when set
+    pub encryption: Option<Encryption>,
+    /// CEA-608 and CEA-708 captions embedded into the H.264 and H.265 renditions when set
+    pub captions: Option<captions::Captions>,
+    /// SCTE-35 ad breaks, in order
+    pub ad_breaks: Vec<scte35::AdBreak>,
+    /// HLS interstitials, with their assets generated unless they point elsewhere
+    pub interstitials: Vec<interstitials::Interstitial>,
+    /// Accepts timed metadata for the segments when set
+    pub metadata: Option<Metadata>,
+    /// Thumbnail sprites of the video when set
+    pub thumbnails: Option<thumbnails::Thumbnails>,
+    pub video: Vec<video::VideoStream>,
+    pub audio: Vec<audio::AudioStream>,
+    /// WebVTT or IMSC1 renditions with generated cues
+    pub subtitles: Vec<subtitles::SubtitleStream>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Output {
+    /// Directory the master playlist and one sub-directory per rendition are written to
+    pub path: PathBuf,
+    /// File name of the master playlist inside `path`
+    pub master_playlist: String,
+    /// File name of the DASH manifest inside `path`
+    pub dash_manifest: String,
+    /// fMP4 segments, MPEG-TS segments or both, the latter with the TS renditions and their
+    /// own master playlist in a sub-directory
+    pub format: hlscmaf::SegmentFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Server {
+    /// Address to listen on, e.g. `0.0.0.0:8080`
+    pub address: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Encryption {
+    /// `aes-128` for whole segments, `sample-aes` for the media samples only, or `cenc` and
+    /// `cbcs` for common encryption with Clear Key
+    pub method: encryption::Method,
+    /// Number of segments encrypted with the same key before switching to a new one
+    pub key_rotation: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Metadata {
+    /// Schemes of the `emsg` boxes for DASH players. Injected events use the first one unless
+    /// they name another
+    pub schemes: Vec<String>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            path: PathBuf::from("hls_live_stream"),
+            master_playlist: "manifest.m3u8".to_string(),
+            dash_manifest: "manifest.mpd".to_string(),
+            format: hlscmaf::SegmentFormat::default(),
+        }
+    }
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Encryption {
+            method: encryption::Method::default(),
+            key_rotation: 5,
+        }
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            schemes: vec!["urn:yatta:metadata".to_string()],
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input: None,
+            output: Output::default(),
+            server: None,
+            segment_duration: 2.0,
+            window_size: 5,
+            playlist: hlscmaf::PlaylistMode::default(),
+            dvr_window: 3600.0,
+            dash_addressing: dash::Addressing::default(),
+            dash_target_latency: None,
+            part_duration: None,
+            single_file: false,
+            encryption: None,
+            captions: None,
+            ad_breaks: vec![],
+            interstitials: vec![],
+            metadata: None,
+            thumbnails: None,
+            video: vec![],
+            audio: vec![],
+            subtitles: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Reads a config file, JSON if the extension says so and TOML otherwise.
+    ///
+    /// The result still has to go through [`Config::finish`] before being used.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            _ => toml::from_str(&contents)
+                .with_context(|| format!("failed to parse {}", path.display())),
+        }
+    }
+
+    /// Fills in the default ladder and missing names, then validates the result.
+    pub fn finish(mut self) -> Result<Self, Error> {
+        if self.video.is_empty() && self.audio.is_empty() {
+            self.video = video::VideoStream::default_ladder();
+            self.audio = audio::AudioStream::default_ladder();
+
+            // Nothing but CMAF carries AV1
+            if !self.output.format.has_cmaf() {
+                self.video.retain(|stream| stream.codec != "av1");
+            }
+        }
+
+        for (idx, stream) in self.video.iter_mut().enumerate() {
+            if stream.name.is_empty() {
+                stream.name = format!("{}_{}", stream.codec, idx);
+            }
+        }
+
+        for (idx, stream) in self.audio.iter_mut().enumerate() {
+            if stream.name.is_empty() {
+                stream.name = format!("audio_{}", idx);
+            }
+        }
+
+        for (idx, stream) in self.subtitles.iter_mut().enumerate() {
+            if stream.name.is_empty() {
+                stream.name = format!("subtitles_{}", idx);
+            }
+        }
+
+        for (idx, interstitial) in self.interstitials.iter_mut().enumerate() {
+            if interstitial.id.is_empty() {
+                interstitial.id = format!("interstitial_{}", idx);
+            }
+        }
+
+        // Players need one default rendition in the audio group
+        if !self.audio.iter().any(|stream| stream.default) {
+            if let Some(stream) = self.audio.first_mut() {
+                stream.default = true;
+            }
+        }
+
+        self.validate()?;
+
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        validate_window(self.segment_duration, self.window_size, None)
+            .context("invalid output settings")?;
+
+        if self.playlist == hlscmaf::PlaylistMode::Dvr
+            && (self.dvr_window.is_nan() || self.dvr_window < self.segment_duration)
+        {
+            bail!("dvr_window must be at least one segment long, got {}", self.dvr_window);
+        }
+
+        if let Some(part_duration) = self.part_duration {
+            if part_duration.is_nan() || part_duration <= 0.0 {
+                bail!("part_duration must be positive, got {}", part_duration);
+            }
+
+            if part_duration >= self.segment_duration {
+                bail!("part_duration must be shorter than segment_duration");
+            }
+
+            // Blocking playlist reloads need our own origin
+            if self.server.is_none() {
+                bail!("part_duration requires a [server] to serve the playlists");
+            }
+        }
+
+        // Parts are files of their own
+        if self.single_file && self.part_duration.is_some() {
+            bail!("single_file can't be combined with part_duration");
+        }
+
+        if let Some(target_latency) = self.dash_target_latency {
+            if self.part_duration.is_none() {
+                bail!("dash_target_latency only applies with part_duration");
+            }
+
+            // A `SegmentTimeline` only lists complete segments, there are no chunks to play
+            if self.dash_addressing == dash::Addressing::Timeline {
+                bail!("dash_target_latency requires dash_addressing number");
+            }
+
+            if target_latency.is_nan() || target_latency <= 0.0 {
+                bail!("dash_target_latency must be positive, got {}", target_latency);
+            }
+        }
+
+        if let Some(ref encryption) = self.encryption {
+            // Players fetch the keys from our own origin
+            if self.server.is_none() {
+                bail!("encryption requires a [server] to serve the keys");
+            }
+
+            if self.part_duration.is_some() {
+                bail!("encryption can't be combined with part_duration");
+            }
+
+            if encryption.key_rotation == 0 {
+                bail!("encryption.key_rotation must be at least 1");
+            }
+
+            // Only the NAL units of H.264 and H.265 are protected
+            if encryption.method != encryption::Method::Aes128 {
+                if let Some(stream) = self.video.iter().find(|stream| stream.codec == "av1") {
+                    bail!(
+                        "sample encryption doesn't support the av1 video stream '{}'",
+                        stream.name
+                    );
+                }
+            }
+        }
+
+        if let Some(ref metadata) = self.metadata {
+            // Events are injected through our own origin
+            if self.server.is_none() {
+                bail!("metadata requires a [server] to accept the events");
+            }
+
+            if metadata.schemes.is_empty() {
+                bail!("metadata.schemes needs at least one scheme");
+            }
+        }
+
+        let format = self.output.format;
+        if format.has_ts() {
+            // Whole segments are all MPEG-TS can be encrypted as here
+            if let Some(ref encryption) = self.encryption {
+                if encryption.method != encryption::Method::Aes128 {
+                    bail!("MPEG-TS segments can only be encrypted with aes-128");
+                }
+            }
+        }
+
+        if !format.has_cmaf() {
+            if let Some(stream) = self.video.iter().find(|stream| stream.codec == "av1") {
+                bail!("MPEG-TS segments can't carry the av1 video stream '{}'", stream.name);
+            }
+
+            if self.part_duration.is_some() {
+                bail!("part_duration requires CMAF segments");
+            }
+
+            if self.metadata.is_some() {
+                bail!("metadata requires CMAF segments to carry the events");
+            }
+
+            if self.thumbnails.is_some() {
+                bail!("thumbnails require CMAF segments");
+            }
+
+            if let Some(stream) = self
+                .subtitles
+                .iter()
+                .find(|stream| stream.format == subtitles::Format::Imsc1)
+            {
+                bail!("the imsc1 subtitle stream '{}' requires CMAF segments", stream.name);
+            }
+        }
+
+        if let Some(thumbnails) = self.thumbnails {
+            if self.video.is_empty() {
+                bail!("thumbnails need at least one video stream");
+            }
+
+            if thumbnails.width == 0
+                || thumbnails.height == 0
+                || thumbnails.columns == 0
+                || thumbnails.rows == 0
+                || thumbnails.segments == 0
+            {
+                bail!("thumbnails need a non-zero width, height, columns, rows and segments");
+            }
+
+            // Shares the output directory with the renditions
+            let name = thumbnails::NAME;
+            if self.video.iter().any(|stream| stream.name == name)
+                || self.audio.iter().any(|stream| stream.name == name)
+                || self.subtitles.iter().any(|stream| stream.name == name)
+            {
+                bail!("stream name '{}' is taken by the thumbnails", name);
+            }
+        }
+
+        // The splice points are on the segment boundaries of the output settings
+        if !self.ad_breaks.is_empty() {
+            let overridden = self
+                .video
+                .iter()
+                .map(|stream| (&stream.name, stream.segment_duration))
+                .chain(self.audio.iter().map(|stream| (&stream.name, stream.segment_duration)))
+                .find(|(_, segment_duration)| segment_duration.is_some());
+            if let Some((name, _)) = overridden {
+                bail!("ad breaks can't be combined with the segment_duration of stream '{}'", name);
+            }
+        }
+
+        let mut previous_end = 0.0;
+        for ad_break in &self.ad_breaks {
+            if !(ad_break.start >= 0.0 && ad_break.duration > 0.0) {
+                bail!("ad breaks need a start of at least 0 and a positive duration");
+            }
+
+            if ad_break.start < previous_end {
+                bail!(
+                    "ad breaks must be in order and must not overlap, got one at {}",
+                    ad_break.start
+                );
+            }
+            previous_end = ad_break.start + ad_break.duration;
+        }
+
+        let mut ids = HashSet::new();
+        for interstitial in &self.interstitials {
+            // The ID is used as the directory of a generated asset and quoted in the playlists
+            let id = &interstitial.id;
+            if id.contains(['/', '\\', '"']) || id == "." || id == ".." {
+                bail!("interstitial ID '{}' is not a valid directory name", id);
+            }
+
+            if !ids.insert(&interstitial.id) {
+                bail!("duplicate interstitial ID '{}'", interstitial.id);
+            }
+
+            if !(interstitial.start >= 0.0 && interstitial.duration > 0.0) {
+                bail!(
+                    "interstitial '{}' needs a start of at least 0 and a positive duration",
+                    interstitial.id
+                );
+            }
+
+            if interstitial.asset_uri.is_some() && interstitial.asset_list.is_some() {
+                bail!(
+                    "interstitial '{}' can't have both asset_uri and asset_list",
+                    interstitial.id
+                );
+            }
+
+            // Quoted in the date range as they are
+            let mut uris = interstitial.asset_uri.iter().chain(&interstitial.asset_list);
+            if uris.any(|uri| uri.contains(['"', '\r', '\n'])) {
+                bail!(
+                    "asset_uri and asset_list of interstitial '{}' can't contain quotes or line \
+                     breaks",
+                    interstitial.id
+                );
+            }
+
+            if interstitial.serve_list && !interstitial.generates_asset() {
+                bail!(
+                    "serve_list only applies to generated assets, see interstitial '{}'",
+                    interstitial.id
+                );
+            }
+
+            if let Some(offset) = interstitial.resume_offset {
+                if offset.is_nan() || offset < 0.0 {
+                    bail!("resume_offset of interstitial '{}' can't be negative", interstitial.id);
+                }
+            }
+        }
+
+        if self.output.master_playlist.is_empty() {
+            bail!("output.master_playlist can't be empty");
+        }
+
+        if self.output.dash_manifest.is_empty() {
+            bail!("output.dash_manifest can't be empty");
+        }
+
+        let generates_assets =
+            self.interstitials.iter().any(|interstitial| interstitial.generates_asset());
+        let mut names = HashSet::new();
+        let all_names = self
+            .video
+            .iter()
+            .map(|stream| &stream.name)
+            .chain(self.audio.iter().map(|stream| &stream.name))
+            .chain(self.subtitles.iter().map(|stream| &stream.name));
+        for name in all_names {
+            // The name is used as the directory of the rendition
+            if name.contains(['/', '\\']) || name == "." || name == ".." {
+                bail!("stream name '{}' is not a valid directory name", name);
+            }
+
+            if format == hlscmaf::SegmentFormat::Both && name == hlscmaf::TS_DIR {
+                bail!("stream name '{}' is taken by the MPEG-TS renditions", name);
+            }
+
+            if generates_assets && name == interstitials::DIR {
+                bail!("stream name '{}' is taken by the interstitial assets", name);
+            }
+
+            if !names.insert(name) {
+                bail!("duplicate stream name '{}'", name);
+            }
+        }
+
+        for stream in &self.video {
+            if !VIDEO_CODECS.contains(&stream.codec.as_str()) {
+                bail!(
+                    "unknown codec '{}' for video stream '{}', expected one of {}",
+                    stream.codec,
+                    stream.name,
+                    VIDEO_CODECS.join(", ")
+                );
+            }
+
+            if stream.bitrate == 0 || stream.width == 0 || stream.height == 0 {
+                bail!(
+                    "video stream '{}' needs a non-zero bitrate, width and height",
+                    stream.name
+                );
+            }
+
+            validate_window(
+                stream.segment_duration.unwrap_or(self.segment_duration),
+                stream.window_size.unwrap_or(self.window_size),
+                self.part_duration,
+            )
+            .with_context(|| format!("invalid settings for video stream '{}'", stream.name))?;
+        }
+
+        for stream in &self.audio {
+            if !AUDIO_WAVES.contains(&stream.wave.as_str()) {
+                bail!(
+                    "unknown wave '{}' for audio stream '{}', expected one of {}",
+                    stream.wave,
+                    stream.name,
+                    AUDIO_WAVES.join(", ")
+                );
+            }
+
+            validate_window(
+                stream.segment_duration.unwrap_or(self.segment_duration),
+                stream.window_size.unwrap_or(self.window_size),
+                self.part_duration,
+            )
+            .with_context(|| format!("invalid settings for audio stream '{}'", stream.name))?;
+        }
+
+        if self.audio.iter().filter(|stream| stream.default).count() > 1 {
+            bail!("only one audio stream can be the default");
+        }
+
+        if self.subtitles.iter().filter(|stream| stream.default).count() > 1 {
+            bail!("only one subtitle stream can be the default");
+        }
+
+        Ok(())
+    }
+
+    pub fn master_playlist_path(&self) -> PathBuf {
+        self.output.path.join(&self.output.master_playlist)
+    }
+
+    /// Where the master playlist of the MPEG-TS renditions goes, if there are any.
+    pub fn ts_master_playlist_path(&self) -> PathBuf {
+        match self.output.format {
+            hlscmaf::SegmentFormat::Both => {
+                self.output.path.join(hlscmaf::TS_DIR).join(&self.output.master_playlist)
+            }
+            _ => self.master_playlist_path(),
+        }
+    }
+
+    pub fn dash_manifest_path(&self) -> PathBuf {
+        self.output.path.join(&self.output.dash_manifest)
+    }
+
+    /// The ad breaks, moved to the segment boundaries of the output.
+    pub fn splices(&self) -> Vec<scte35::Splice> {
+        scte35::Splice::schedule(&self.ad_breaks, self.hls_settings().segment_duration)
+    }
+
+    /// The schemes timed metadata is accepted for, none when disabled.
+    pub fn metadata_schemes(&self) -> Vec<String> {
+        self.metadata.as_ref().map_or_else(Vec::new, |metadata| metadata.schemes.clone())
+    }
+
+    pub fn hls_settings(&self) -> hlscmaf::Settings {
+        hlscmaf::Settings {
+            segment_duration: utils::seconds_to_clock_time(self.segment_duration),
+            window_size: self.window_size,
+            mode: self.playlist,
+            dvr_window: utils::seconds_to_clock_time(self.dvr_window),
+            part_duration: self.part_duration.map(utils::seconds_to_clock_time),
+            encryption: self.encryption.as_ref().map(|encryption| encryption::Settings {
+                method: encryption.method,
+                key_rotation: encryption.key_rotation,
+            }),
+            single_file: self.single_file,
+        }
+    }
+
+    pub fn dash_settings(&self) -> dash::Settings {
+        let target_latency = self
+            .dash_target_latency
+            .or(self.part_duration.map(|part_duration| 3.0 * part_duration))
+            .unwrap_or(self.segment_duration);
+
+        dash::Settings {
+            addressing: self.dash_addressing,
+            target_latency: utils::seconds_to_clock_time(target_latency),
+        }
+    }
+}
+
+fn validate_window(
+    segment_duration: f64,
+    window_size: usize,
+    part_duration: Option<f64>,
+) -> Result<(), Error> {
+    if segment_duration.is_nan() || segment_duration <= 0.0 {
+        bail!("segment_duration must be positive, got {}", segment_duration);
+    }
+
+    if part_duration.map_or(false, |part_duration| part_duration >= segment_duration) {
+        bail!("part_duration must be shorter than segment_duration");
+    }
+
+    if window_size == 0 {
+        bail!("window_size must be at least 1");
+    }
+
+    Ok(())
+}
+
+/// Name of the application message posted on the bus when the config file changed.
+pub(crate) const CONFIG_CHANGED: &str = "yatta-config-changed";
+
+/// Polls the modification time of the config file and posts a [`CONFIG_CHANGED`] message on
+/// `bus` whenever it changes, so the reload happens on the thread that owns the pipeline.
+pub(crate) fn watch(path: PathBuf, bus: gst::Bus) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+    std::thread::spawn(move || {
+        let mut last_modified = modified(&path);
+
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+
+            let current = modified(&path);
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            info!("config {} changed", path.display());
+            let msg = gst::message::Application::new(gst::Structure::new_empty(CONFIG_CHANGED));
+            if bus.post(msg).is_err() {
+                // The pipeline is gone
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, Error> {
+        toml::from_str::<Config>(toml)?.finish()
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let config = parse("").unwrap();
+
+        assert_eq!(config.video.len(), 3);
+        assert_eq!(config.audio.len(), 1);
+        assert_eq!(config.window_size, 5);
+        assert_eq!(config.hls_settings().segment_duration, gst::ClockTime::from_seconds(2));
+    }
+
+    #[test]
+    fn names_unnamed_streams() {
+        let config = parse(
+            r#"
+            [[video]]
+            codec = "h265"
+
+            [[audio]]
+            lang = "pt"
+
+            [[subtitles]]
+            cues = "counter"
+            format = "imsc1"
+            forced = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.video[0].name, "h265_0");
+        assert_eq!(config.audio[0].name, "audio_0");
+        assert!(config.audio[0].default);
+        assert_eq!(config.subtitles[0].name, "subtitles_0");
+        assert_eq!(config.subtitles[0].cues, subtitles::Cues::Counter);
+        assert_eq!(config.subtitles[0].format, subtitles::Format::Imsc1);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let err = parse(
+            r#"
+            [[video]]
+            name = "main"
+
+            [[audio]]
+            name = "main"
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "duplicate stream name 'main'");
+    }
+
+    #[test]
+    fn rejects_unknown_codec() {
+        let err = parse(
+            r#"
+            [[video]]
+            codec = "vp9"
+            "#,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().starts_with("unknown codec 'vp9' for video stream 'vp9_0'"));
+    }
+
+    #[test]
+    fn parses_playlist_mode() {
+        let config = parse(
+            r#"
+            playlist = "live-to-vod"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.hls_settings().mode, hlscmaf::PlaylistMode::LiveToVod);
+        assert!(parse(r#"playlist = "dvr""#).is_ok());
+        assert!(parse(r#"playlist = "vod""#).is_err());
+    }
+
+    #[test]
+    fn low_latency_needs_server() {
+        assert!(parse("part_duration = 0.5").is_err());
+        assert!(parse("part_duration = 2.0\n[server]\naddress = \"127.0.0.1:8080\"").is_err());
+
+        let config =
+            parse("part_duration = 0.5\n[server]\naddress = \"127.0.0.1:8080\"").unwrap();
+        assert_eq!(
+            config.hls_settings().part_duration,
+            Some(gst::ClockTime::from_mseconds(500))
+        );
+
+        let server = "[server]\naddress = \"127.0.0.1:8080\"\n";
+        let low_latency = format!("part_duration = 0.5\ndash_target_latency = 1.5\n{}", server);
+        assert!(parse(&low_latency).is_ok());
+        assert!(parse(&format!("dash_addressing = \"timeline\"\n{}", low_latency)).is_err());
+        assert!(parse(&format!("{}[[video]]\nsegment_duration = 1.0", low_latency)).is_ok());
+        assert!(parse(&format!("{}[[audio]]\nsegment_duration = 0.5", low_latency)).is_err());
+    }
+
+    #[test]
+    fn parses_encryption() {
+        let server = "[server]\naddress = \"127.0.0.1:8080\"\n";
+        assert!(parse("[encryption]\nmethod = \"aes-128\"").is_err());
+        assert!(parse(&format!("{}[encryption]\nkey_rotation = 0", server)).is_err());
+        assert!(parse(&format!(
+            "{}[encryption]\nmethod = \"sample-aes\"\n[[video]]\ncodec = \"av1\"",
+            server
+        ))
+        .is_err());
+
+        let config =
+            parse(&format!("{}[encryption]\nmethod = \"sample-aes\"", server)).unwrap();
+        assert_eq!(
+            config.hls_settings().encryption,
+            Some(encryption::Settings {
+                method: encryption::Method::SampleAes,
+                key_rotation: 5,
+            })
+        );
+        assert!(parse(&format!("{}[encryption]\nmethod = \"cenc\"", server)).is_ok());
+    }
+
+    #[test]
+    fn parses_captions() {
+        assert_eq!(parse("").unwrap().captions, None);
+        assert_eq!(parse("captions = \"name\"").unwrap().captions, Some(captions::Captions::Name));
+        assert!(parse("captions = \"ticker\"").is_err());
+    }
+
+    #[test]
+    fn validates_ad_breaks() {
+        let config = parse("[[ad_breaks]]\nstart = 61.0\nduration = 30.0").unwrap();
+        assert_eq!(config.splices()[0].out, gst::ClockTime::from_seconds(62));
+
+        assert!(parse("[[ad_breaks]]\nstart = 10.0").is_err());
+        assert!(parse(
+            "[[ad_breaks]]\nstart = 10.0\nduration = 30.0\n[[ad_breaks]]\nstart = 30.0\nduration = 10.0"
+        )
+        .is_err());
+
+        // The breaks are on the segment boundaries of the output
+        let ad_break = "[[ad_breaks]]\nstart = 10.0\nduration = 30.0\n";
+        assert!(parse(&format!("{}[[audio]]\nwindow_size = 10", ad_break)).is_ok());
+        assert!(parse(&format!("{}[[audio]]\nsegment_duration = 4.0", ad_break)).is_err());
+    }
+
+    #[test]
+    fn validates_interstitials() {
+        let config = parse("[[interstitials]]\nstart = 30.0\nrestrict = [\"skip\"]").unwrap();
+        assert_eq!(config.interstitials[0].id, "interstitial_0");
+        assert_eq!(config.interstitials[0].restrict, vec![interstitials::Restriction::Skip]);
+
+        assert!(parse("[[interstitials]]\nstart = 30.0\nduration = 0.0").is_err());
+        assert!(parse(
+            "[[interstitials]]\nid = \"ad\"\n[[interstitials]]\nid = \"ad\"\nstart = 60.0"
+        )
+        .is_err());
+        assert!(parse("[[interstitials]]\nasset_uri = \"a.m3u8\"\nserve_list = true").is_err());
+        assert!(parse("[[interstitials]]\nasset_uri = \"a.m3u8\\\",X=\\\"\"").is_err());
+        assert!(parse("[[interstitials]]\n[[video]]\nname = \"interstitials\"").is_err());
+    }
+
+    #[test]
+    fn validates_segment_formats() {
+        let config = parse("[output]\nformat = \"both\"").unwrap();
+        assert_eq!(
+            config.ts_master_playlist_path(),
+            PathBuf::from("hls_live_stream/ts/manifest.m3u8")
+        );
+
+        // Without the av1 rendition of the default ladder
+        assert_eq!(parse("[output]\nformat = \"ts\"").unwrap().video.len(), 2);
+        assert!(parse("[output]\nformat = \"ts\"\n[[video]]\ncodec = \"av1\"").is_err());
+
+        assert!(parse(
+            "[output]\nformat = \"both\"\n[server]\naddress = \"[::]:8080\"\n\
+             [encryption]\nmethod = \"cbcs\"\n[[video]]\ncodec = \"h264\""
+        )
+        .is_err());
+        assert!(parse("[output]\nformat = \"both\"\n[[audio]]\nname = \"ts\"").is_err());
+    }
+
+    #[test]
+    fn validates_single_file() {
+        assert!(parse("single_file = true").unwrap().hls_settings().single_file);
+
+        let server = "[server]\naddress = \"127.0.0.1:8080\"\n";
+        assert!(parse(&format!("single_file = true\npart_duration = 0.5\n{}", server)).is_err());
+    }
+
+    #[test]
+    fn validates_thumbnails() {
+        let config = parse("[thumbnails]\ncolumns = 4").unwrap();
+        assert_eq!(config.thumbnails.unwrap().tiles(), 8);
+
+        assert!(parse("[thumbnails]\nsegments = 0").is_err());
+        assert!(parse("[thumbnails]\n[[video]]\nname = \"thumbnails\"").is_err());
+    }
+
+    #[test]
+    fn enables_metadata() {
+        assert!(parse("").unwrap().metadata_schemes().is_empty());
+        assert!(parse("[metadata]").is_err());
+
+        let server = "[server]\naddress = \"127.0.0.1:8080\"\n";
+        let config = parse(&format!("{}[metadata]", server)).unwrap();
+        assert_eq!(config.metadata_schemes(), vec!["urn:yatta:metadata".to_string()]);
+        assert!(parse(&format!("{}[metadata]\nschemes = []", server)).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(parse("segment_duraton = 4.0").is_err());
+    }
+}
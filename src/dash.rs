@@ -0,0 +1,1324 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    audio, encryption, hlscmaf, hlscmaf::PlaylistMode, metadata, scte35, subtitles, thumbnails,
+    utils, video,
+};
+
+// Bitrate `avenc_aac` encodes at by default
+const AUDIO_BANDWIDTH: u64 = 128_000;
+// Roughly what the generated IMSC1 documents take
+const TEXT_BANDWIDTH: u64 = 2_000;
+// Exact for the frame durations of the video and of AAC at 48kHz
+const TIMESCALE: u64 = 90_000;
+
+/// How the segments of each representation are addressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Addressing {
+    /// `$Number$` template with the nominal segment duration
+    #[default]
+    Number,
+    /// `SegmentTimeline` with the exact duration of every segment
+    Timeline,
+}
+
+impl FromStr for Addressing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(Addressing::Number),
+            "timeline" => Ok(Addressing::Timeline),
+            _ => Err(anyhow!("unknown DASH addressing '{}', expected number or timeline", s)),
+        }
+    }
+}
+
+/// DASH specific settings of an output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Settings {
+    pub addressing: Addressing,
+    /// Latency players aim for in Low-Latency mode
+    pub target_latency: gst::ClockTime,
+}
+
+/// A segment as listed in the media playlist of a rendition.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Segment {
+    pub number: u64,
+    pub date_time: DateTime<Utc>,
+    /// Media time of the segment
+    pub time: gst::ClockTime,
+    pub duration: gst::ClockTime,
+    /// File name of the header of the segment
+    pub init: String,
+    /// The segment doesn't continue the timeline of the previous one
+    pub discontinuity: bool,
+    /// Default key ID of the header when the samples are protected
+    pub default_kid: Option<[u8; 16]>,
+    /// File name, offset and length inside it in single file mode
+    pub media_range: Option<(String, u64, u64)>,
+}
+
+/// The segments a rendition currently lists, as reported by the HLS packager.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Timeline {
+    /// Wall clock time of the origin of the output, which all renditions number from
+    pub start_date_time: DateTime<Utc>,
+    /// Never empty
+    pub segments: Vec<Segment>,
+    pub ended: bool,
+}
+
+impl Timeline {
+    fn end_date_time(&self) -> DateTime<Utc> {
+        let last = self.segments.last().unwrap();
+        last.date_time + nanoseconds(last.duration)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Video { codec: String, width: u64, height: u64 },
+    Audio { lang: String, default: bool },
+    Text { lang: String, default: bool, forced: bool },
+    /// Thumbnail sprites of `width` by `height`, tiled as `layout`
+    Image { width: u64, height: u64, layout: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Representation {
+    id: String,
+    kind: Kind,
+    codecs: String,
+    bandwidth: u64,
+    segment_duration: gst::ClockTime,
+    // How far back its live playlist reaches
+    window: gst::ClockTime,
+}
+
+/// Dynamic DASH manifest over the very same `init.mp4` and `segment_N.fmp4` files as the HLS
+/// playlists. Subtitles are only listed when packaged as IMSC1, WebVTT segments aren't fMP4.
+///
+/// Every discontinuity of the renditions starts a new Period, so each Period has a single
+/// continuous timeline and header per representation. The manifest is rewritten with every
+/// segment, which also keeps the `UTCTiming` fresh.
+///
+/// When the HLS packager writes parts, the `$Number$` templates announce each segment as
+/// soon as its first chunk is out and the server sends it with chunked transfer encoding
+/// while it grows. A `SegmentTimeline` only ever lists complete segments.
+///
+/// Renditions appending their segments to a single file are listed as `SegmentList` with the
+/// byte range of each segment.
+pub(crate) struct Mpd {
+    path: PathBuf,
+    settings: hlscmaf::Settings,
+    dash_settings: Settings,
+    representations: Vec<Representation>,
+    timelines: HashMap<String, Timeline>,
+    // Wall clock start of every Period after the first one
+    period_starts: Vec<DateTime<Utc>>,
+    // Ad breaks, relative to the availability start time
+    splices: Vec<scte35::Splice>,
+    // Schemes of the `emsg` boxes in the video and audio segments
+    event_schemes: Vec<String>,
+    thumbnails: Option<thumbnails::Thumbnails>,
+}
+
+impl Mpd {
+    pub fn new(path: PathBuf, settings: hlscmaf::Settings, dash_settings: Settings) -> Self {
+        Mpd {
+            path,
+            settings,
+            dash_settings,
+            representations: vec![],
+            timelines: HashMap::new(),
+            period_starts: vec![],
+            splices: vec![],
+            event_schemes: vec![],
+            thumbnails: None,
+        }
+    }
+
+    /// Signals `splices` as SCTE-35 events in the Periods they start in.
+    pub fn set_splices(&mut self, splices: Vec<scte35::Splice>) {
+        self.splices = splices;
+    }
+
+    /// Announces the timed metadata of `schemes` as in-band events of the video and audio
+    /// renditions, along with the ID3 tags meant for HLS players.
+    pub fn set_event_schemes(&mut self, schemes: &[String]) {
+        self.event_schemes = schemes.to_vec();
+    }
+
+    /// Lists the thumbnail sprites along with the next ladder set.
+    pub fn set_thumbnails(&mut self, thumbnails: Option<thumbnails::Thumbnails>) {
+        self.thumbnails = thumbnails;
+    }
+
+    /// Replaces the listed renditions with those of the ladder whose codecs are known, plus
+    /// the thumbnails if enabled.
+    pub fn set_ladder(
+        &mut self,
+        video_streams: &[video::VideoStream],
+        audio_streams: &[audio::AudioStream],
+        subtitle_streams: &[subtitles::SubtitleStream],
+        mimes: &HashMap<String, String>,
+    ) {
+        let window = |settings: hlscmaf::Settings| {
+            settings.segment_duration * settings.window_size as u64
+        };
+
+        let video = video_streams.iter().filter_map(|stream| {
+            let settings =
+                self.settings.with_overrides(stream.segment_duration, stream.window_size);
+            Some(Representation {
+                id: stream.name.clone(),
+                kind: Kind::Video {
+                    codec: stream.codec.clone(),
+                    width: stream.width,
+                    height: stream.height,
+                },
+                codecs: mimes.get(&stream.name)?.clone(),
+                bandwidth: stream.bitrate,
+                segment_duration: settings.segment_duration,
+                window: window(settings),
+            })
+        });
+        let audio = audio_streams.iter().filter_map(|stream| {
+            let settings =
+                self.settings.with_overrides(stream.segment_duration, stream.window_size);
+            Some(Representation {
+                id: stream.name.clone(),
+                kind: Kind::Audio {
+                    lang: stream.lang.clone(),
+                    default: stream.default,
+                },
+                codecs: mimes.get(&stream.name)?.clone(),
+                bandwidth: AUDIO_BANDWIDTH,
+                segment_duration: settings.segment_duration,
+                window: window(settings),
+            })
+        });
+
+        let text = subtitle_streams
+            .iter()
+            .filter(|stream| stream.format == subtitles::Format::Imsc1)
+            .map(|stream| Representation {
+                id: stream.name.clone(),
+                kind: Kind::Text {
+                    lang: stream.lang.clone(),
+                    default: stream.default,
+                    forced: stream.forced,
+                },
+                codecs: subtitles::IMSC1_CODECS.to_string(),
+                bandwidth: TEXT_BANDWIDTH,
+                segment_duration: self.settings.segment_duration,
+                window: window(self.settings),
+            });
+
+        let segment_duration = self.settings.segment_duration;
+        let image = self.thumbnails.map(|thumbnails| {
+            let (width, height) = thumbnails.sprite_size();
+            Representation {
+                id: thumbnails::NAME.to_string(),
+                kind: Kind::Image {
+                    width,
+                    height,
+                    layout: thumbnails.layout(),
+                },
+                codecs: "jpeg".to_string(),
+                bandwidth: thumbnails.bandwidth(segment_duration),
+                segment_duration: thumbnails.sprite_duration(segment_duration),
+                // The sprites cover at least the window of the renditions
+                window: window(self.settings),
+            }
+        });
+
+        let representations = video.chain(audio).chain(text).chain(image).collect::<Vec<_>>();
+        if representations != self.representations {
+            self.representations = representations;
+            self.write();
+        }
+    }
+
+    /// Called by the HLS packager of rendition `name` whenever its playlist was updated.
+    pub fn update(&mut self, name: &str, timeline: Timeline) {
+        for segment in timeline.segments.iter().filter(|segment| segment.discontinuity) {
+            self.add_period_start(segment.date_time);
+        }
+
+        // A rendition that was set up again after it ended, e.g. with another codec, starts
+        // from scratch
+        if let Some(previous) = self.timelines.get(name) {
+            if previous.ended && !timeline.ended {
+                self.add_period_start(timeline.segments[0].date_time);
+            }
+        }
+
+        self.timelines.insert(name.to_string(), timeline);
+        self.write();
+    }
+
+    // The renditions hit the same discontinuity at slightly different times, all of them
+    // start the same Period.
+    fn add_period_start(&mut self, date_time: DateTime<Utc>) {
+        let tolerance = self.tolerance();
+        if self
+            .period_starts
+            .iter()
+            .any(|start| *start - date_time < tolerance && date_time - *start < tolerance)
+        {
+            return;
+        }
+
+        self.period_starts.push(date_time);
+        self.period_starts.sort();
+    }
+
+    // Duration of the chunks segments are announced early by
+    fn chunk_duration(&self) -> Option<gst::ClockTime> {
+        match self.dash_settings.addressing {
+            Addressing::Number => self.settings.part_duration,
+            Addressing::Timeline => None,
+        }
+    }
+
+    // Half the shortest segment, renditions may override the segment duration
+    fn tolerance(&self) -> Duration {
+        let segment_duration = self
+            .representations
+            .iter()
+            .map(|representation| representation.segment_duration)
+            .min()
+            .unwrap_or(self.settings.segment_duration);
+        Duration::nanoseconds(segment_duration.nseconds() as i64 / 2)
+    }
+
+    fn write(&self) {
+        let Some(mpd) = self.render(Utc::now()) else {
+            return;
+        };
+
+        utils::write_atomically(&self.path, |file| file.write_all(mpd.as_bytes()))
+            .expect("Failed to write MPD");
+        info!("wrote MPD to {}", self.path.display());
+    }
+
+    // Renditions are only listed once their first segment is out, before that there is no
+    // availability start time to address their segments from.
+    fn listed(&self) -> impl Iterator<Item = (&Representation, &Timeline)> {
+        self.representations.iter().filter_map(|representation| {
+            Some((representation, self.timelines.get(&representation.id)?))
+        })
+    }
+
+    fn render(&self, now: DateTime<Utc>) -> Option<String> {
+        let availability_start_time =
+            self.listed().map(|(_, timeline)| timeline.start_date_time).min()?;
+        let end_date_time = self.listed().map(|(_, timeline)| timeline.end_date_time()).max()?;
+        let ended = self.listed().all(|(_, timeline)| timeline.ended);
+        let is_static = ended && self.settings.mode == PlaylistMode::LiveToVod;
+        // Players buffer by the longest segments and refresh by the shortest, the sprites
+        // don't count
+        let segment_durations = || {
+            self.listed()
+                .filter(|(representation, _)| !matches!(representation.kind, Kind::Image { .. }))
+                .map(|(representation, _)| representation.segment_duration)
+        };
+        let longest = segment_durations().max().unwrap_or(self.settings.segment_duration);
+        let shortest = segment_durations().min().unwrap_or(self.settings.segment_duration);
+        let (longest, shortest) =
+            (utils::clock_time_to_seconds(longest), utils::clock_time_to_seconds(shortest));
+
+        let mut mpd = String::new();
+        writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        write!(
+            mpd,
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011,urn:mpeg:dash:profile:cmaf:2019""#
+        )
+        .unwrap();
+        if self.protection_scheme().is_some() {
+            write!(
+                mpd,
+                r#" xmlns:cenc="urn:mpeg:cenc:2013" xmlns:dashif="https://dashif.org/CPS""#
+            )
+            .unwrap();
+        }
+        write!(mpd, r#" minBufferTime="{}""#, duration(longest)).unwrap();
+
+        if is_static {
+            // Everything from the first segment on was archived, see `PlaylistMode::LiveToVod`
+            write!(mpd, r#" type="static""#).unwrap();
+        } else {
+            write!(mpd, r#" type="dynamic""#).unwrap();
+            write!(mpd, r#" availabilityStartTime="{}""#, date_time(availability_start_time))
+                .unwrap();
+            write!(mpd, r#" publishTime="{}""#, date_time(now)).unwrap();
+
+            let time_shift_buffer_depth = match self.settings.mode {
+                // As far as every representation reaches, some might override the window
+                PlaylistMode::Live | PlaylistMode::LiveToVod => self
+                    .listed()
+                    .map(|(representation, _)| representation.window)
+                    .min()
+                    .map(utils::clock_time_to_seconds),
+                PlaylistMode::Dvr => Some(utils::clock_time_to_seconds(self.settings.dvr_window)),
+                // Nothing is ever removed
+                PlaylistMode::Event => None,
+            };
+            if let Some(depth) = time_shift_buffer_depth {
+                write!(mpd, r#" timeShiftBufferDepth="{}""#, duration(depth)).unwrap();
+            }
+
+            if !ended {
+                let presentation_delay = match self.chunk_duration() {
+                    Some(_) => utils::clock_time_to_seconds(self.dash_settings.target_latency),
+                    None => 3.0 * longest,
+                };
+                write!(mpd, r#" minimumUpdatePeriod="{}""#, duration(shortest)).unwrap();
+                write!(
+                    mpd,
+                    r#" suggestedPresentationDelay="{}""#,
+                    duration(presentation_delay)
+                )
+                .unwrap();
+            }
+        }
+
+        if ended {
+            let total = seconds_between(availability_start_time, end_date_time);
+            write!(mpd, r#" mediaPresentationDuration="{}""#, duration(total)).unwrap();
+        }
+        writeln!(mpd, ">").unwrap();
+
+        if self.chunk_duration().is_some() && !ended {
+            let target = self.dash_settings.target_latency.mseconds();
+            writeln!(mpd, r#"  <ServiceDescription id="0">"#).unwrap();
+            writeln!(
+                mpd,
+                r#"    <Latency referenceId="0" target="{}" min="{}" max="{}"/>"#,
+                target,
+                target / 2,
+                target * 2
+            )
+            .unwrap();
+            writeln!(mpd, r#"    <PlaybackRate min="0.96" max="1.04"/>"#).unwrap();
+            writeln!(mpd, "  </ServiceDescription>").unwrap();
+        }
+
+        let starts = std::iter::once(availability_start_time)
+            .chain(
+                self.period_starts
+                    .iter()
+                    .copied()
+                    .filter(|start| *start - availability_start_time >= self.tolerance()),
+            )
+            .collect::<Vec<_>>();
+        for (idx, start) in starts.iter().enumerate() {
+            self.render_period(&mut mpd, availability_start_time, *start, starts.get(idx + 1).copied());
+        }
+
+        if !is_static {
+            writeln!(
+                mpd,
+                r#"  <UTCTiming schemeIdUri="urn:mpeg:dash:utc:direct:2014" value="{}"/>"#,
+                date_time(now)
+            )
+            .unwrap();
+        }
+        writeln!(mpd, "</MPD>").unwrap();
+
+        Some(mpd)
+    }
+
+    // Renders the Period between the wall clock times `start` and `end`, unless all its
+    // segments left the window already.
+    fn render_period(
+        &self,
+        mpd: &mut String,
+        availability_start_time: DateTime<Utc>,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) {
+        let tolerance = self.tolerance();
+        let sets = self.adaptation_sets(|segment| {
+            segment.date_time >= start - tolerance
+                && end.map_or(true, |end| segment.date_time < end - tolerance)
+        });
+        if sets.is_empty() {
+            return;
+        }
+
+        // The offset doesn't change when the MPD is updated, unlike the position of the
+        // Period in the manifest
+        let offset = seconds_between(availability_start_time, start);
+        writeln!(
+            mpd,
+            r#"  <Period id="{}" start="{}">"#,
+            (offset * 1000.0).round() as u64,
+            duration(offset)
+        )
+        .unwrap();
+
+        let segments = sets
+            .iter()
+            .flat_map(|(_, representations)| representations.iter())
+            .flat_map(|(_, segments)| segments.iter().copied())
+            .collect::<Vec<_>>();
+        self.render_events(mpd, availability_start_time, start, end, &segments);
+
+        for (id, (kind, representations)) in sets.iter().enumerate() {
+            match kind {
+                Kind::Video { .. } => writeln!(
+                    mpd,
+                    r#"    <AdaptationSet id="{}" contentType="video" mimeType="video/mp4" segmentAlignment="true" startWithSAP="1">"#,
+                    id
+                ),
+                Kind::Audio { lang, .. } => writeln!(
+                    mpd,
+                    r#"    <AdaptationSet id="{}" contentType="audio" mimeType="audio/mp4" lang="{}" segmentAlignment="true" startWithSAP="1">"#,
+                    id,
+                    escape(lang)
+                ),
+                Kind::Text { lang, .. } => writeln!(
+                    mpd,
+                    r#"    <AdaptationSet id="{}" contentType="text" mimeType="application/mp4" lang="{}" segmentAlignment="true" startWithSAP="1">"#,
+                    id,
+                    escape(lang)
+                ),
+                Kind::Image { .. } => writeln!(
+                    mpd,
+                    r#"    <AdaptationSet id="{}" contentType="image" mimeType="image/jpeg">"#,
+                    id
+                ),
+            }
+            .unwrap();
+
+            let carries_events = matches!(kind, Kind::Video { .. } | Kind::Audio { .. });
+            if !self.event_schemes.is_empty() && carries_events {
+                let schemes = std::iter::once(metadata::ID3_SCHEME_ID_URI)
+                    .chain(self.event_schemes.iter().map(String::as_str));
+                for scheme in schemes {
+                    writeln!(
+                        mpd,
+                        r#"      <InbandEventStream schemeIdUri="{}"/>"#,
+                        escape(scheme)
+                    )
+                    .unwrap();
+                }
+            }
+
+            let role = match kind {
+                Kind::Audio { default: true, .. } => Some("main"),
+                Kind::Text { forced: true, .. } => Some("forced-subtitle"),
+                Kind::Text { .. } => Some("subtitle"),
+                _ => None,
+            };
+            if let Some(role) = role {
+                writeln!(
+                    mpd,
+                    r#"      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="{}"/>"#,
+                    role
+                )
+                .unwrap();
+            }
+            if let Kind::Text { default: true, .. } = kind {
+                writeln!(mpd, r#"      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>"#)
+                    .unwrap();
+            }
+
+            for (representation, segments) in representations {
+                write!(
+                    mpd,
+                    r#"      <Representation id="{}" bandwidth="{}" codecs="{}""#,
+                    escape(&representation.id),
+                    representation.bandwidth,
+                    escape(&representation.codecs)
+                )
+                .unwrap();
+                if let Kind::Video { width, height, .. } = representation.kind {
+                    write!(
+                        mpd,
+                        r#" width="{}" height="{}" frameRate="{}""#,
+                        width,
+                        height,
+                        video::FRAMERATE
+                    )
+                    .unwrap();
+                }
+                if let Kind::Image { width, height, .. } = representation.kind {
+                    write!(mpd, r#" width="{}" height="{}""#, width, height).unwrap();
+                }
+                writeln!(mpd, ">").unwrap();
+
+                if let Kind::Image { ref layout, .. } = representation.kind {
+                    writeln!(
+                        mpd,
+                        r#"        <EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="{}"/>"#,
+                        layout
+                    )
+                    .unwrap();
+                }
+
+                self.render_content_protection(mpd, segments[0]);
+                self.render_template(mpd, representation, segments, start);
+
+                writeln!(mpd, "      </Representation>").unwrap();
+            }
+
+            writeln!(mpd, "    </AdaptationSet>").unwrap();
+        }
+
+        writeln!(mpd, "  </Period>").unwrap();
+    }
+
+    // The ad breaks starting inside the Period between `start` and `end`, as long as they
+    // overlap with `segments`
+    fn render_events(
+        &self,
+        mpd: &mut String,
+        availability_start_time: DateTime<Utc>,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+        segments: &[&Segment],
+    ) {
+        let Some(first) = segments.iter().min_by_key(|segment| segment.date_time) else {
+            return;
+        };
+        let last_end = segments
+            .iter()
+            .map(|segment| segment.date_time + nanoseconds(segment.duration))
+            .max()
+            .unwrap();
+
+        let tolerance = self.tolerance();
+        let events = self
+            .splices
+            .iter()
+            .filter_map(|splice| {
+                let out = availability_start_time + nanoseconds(splice.out);
+                let back_in = out + nanoseconds(splice.duration);
+                let in_period =
+                    out >= start - tolerance && end.map_or(true, |end| out < end - tolerance);
+                (in_period && out < last_end && back_in > first.date_time).then_some((splice, out))
+            })
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return;
+        }
+
+        writeln!(
+            mpd,
+            r#"    <EventStream schemeIdUri="{}" timescale="{}">"#,
+            scte35::SCHEME_ID_URI,
+            TIMESCALE
+        )
+        .unwrap();
+        for (splice, out) in events {
+            // Media time of the splice point on the timeline of the segments
+            let into_segments = (out - first.date_time).num_nanoseconds().unwrap();
+            let time = gst::ClockTime::from_nseconds(
+                (first.time.nseconds() as i64 + into_segments).max(0) as u64,
+            );
+            let presentation_time = seconds_between(start, out).max(0.0) * TIMESCALE as f64;
+
+            writeln!(
+                mpd,
+                r#"      <Event presentationTime="{}" duration="{}" id="{}">"#,
+                presentation_time.round() as u64,
+                ticks(splice.duration),
+                splice.id
+            )
+            .unwrap();
+            writeln!(mpd, r#"        <Signal xmlns="http://www.scte.org/schemas/35/2016">"#).unwrap();
+            writeln!(mpd, "          <Binary>{}</Binary>", STANDARD.encode(splice.out_section(time)))
+                .unwrap();
+            writeln!(mpd, "        </Signal>").unwrap();
+            writeln!(mpd, "      </Event>").unwrap();
+        }
+        writeln!(mpd, "    </EventStream>").unwrap();
+    }
+
+    // Each representation has keys of its own, signalled along with the Clear Key license
+    // endpoint of the built-in server
+    fn render_content_protection(&self, mpd: &mut String, first: &Segment) {
+        let (Some(scheme), Some(default_kid)) = (self.protection_scheme(), first.default_kid)
+        else {
+            return;
+        };
+
+        writeln!(
+            mpd,
+            r#"        <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="{}" cenc:default_KID="{}"/>"#,
+            scheme,
+            encryption::uuid(&default_kid)
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            r#"        <ContentProtection schemeIdUri="{}" value="ClearKey1.0">"#,
+            encryption::CLEARKEY_URN
+        )
+        .unwrap();
+        writeln!(mpd, "          <dashif:Laurl>{}</dashif:Laurl>", encryption::LICENSE_PATH)
+            .unwrap();
+        writeln!(mpd, "        </ContentProtection>").unwrap();
+    }
+
+    // `cenc` or `cbcs` when the segments are protected with common encryption
+    fn protection_scheme(&self) -> Option<&'static str> {
+        self.settings
+            .encryption
+            .filter(|encryption| encryption.method.is_common())
+            .and_then(|encryption| encryption.method.scheme())
+    }
+
+    // The template of a representation inside the Period starting at `period_start`, with
+    // `segments` being the ones of that Period still in the window
+    fn render_template(
+        &self,
+        mpd: &mut String,
+        representation: &Representation,
+        segments: &[&Segment],
+        period_start: DateTime<Utc>,
+    ) {
+        let first = segments[0];
+        if first.media_range.is_some() {
+            self.render_segment_list(mpd, representation, segments, period_start);
+            return;
+        }
+        let into_period = seconds_between(period_start, first.date_time).max(0.0);
+
+        match representation.kind {
+            // Every sprite is a complete JPEG
+            Kind::Image { .. } => write!(
+                mpd,
+                r#"        <SegmentTemplate timescale="{}" media="$RepresentationID$/sprite_$Number$.jpg""#,
+                TIMESCALE
+            ),
+            _ => write!(
+                mpd,
+                r#"        <SegmentTemplate timescale="{}" initialization="$RepresentationID$/{}" media="$RepresentationID$/segment_$Number$.fmp4""#,
+                TIMESCALE,
+                escape(&first.init)
+            ),
+        }
+        .unwrap();
+
+        match self.dash_settings.addressing {
+            Addressing::Number => {
+                // Available once the first chunk is, instead of when the whole segment is.
+                // Subtitle segments and sprites are always written in one go.
+                let chunk_duration = self.chunk_duration().filter(|_| {
+                    !matches!(representation.kind, Kind::Text { .. } | Kind::Image { .. })
+                });
+                if let Some(chunk_duration) = chunk_duration {
+                    let offset = representation.segment_duration.saturating_sub(chunk_duration);
+                    write!(
+                        mpd,
+                        r#" availabilityTimeOffset="{:.3}" availabilityTimeComplete="false""#,
+                        utils::clock_time_to_seconds(offset)
+                    )
+                    .unwrap();
+                }
+
+                // The Period might have started with segments that left the window already,
+                // `startNumber` and the offset are those of its very first segment. Renditions
+                // set up later number theirs from the origin of the output as well, so this
+                // resolves to the same segment boundaries for all of them.
+                let duration = ticks(representation.segment_duration);
+                let skipped = (into_period * TIMESCALE as f64 / duration as f64).round() as u64;
+                let skipped = skipped.min(first.number);
+                writeln!(
+                    mpd,
+                    r#" duration="{}" startNumber="{}" presentationTimeOffset="{}"/>"#,
+                    duration,
+                    first.number - skipped,
+                    ticks(first.time).saturating_sub(skipped * duration),
+                )
+                .unwrap();
+            }
+            Addressing::Timeline => {
+                let offset = (into_period * TIMESCALE as f64).round() as u64;
+                writeln!(
+                    mpd,
+                    r#" startNumber="{}" presentationTimeOffset="{}">"#,
+                    first.number,
+                    ticks(first.time).saturating_sub(offset),
+                )
+                .unwrap();
+
+                writeln!(mpd, "          <SegmentTimeline>").unwrap();
+                for (time, duration, repeat) in timeline(segments) {
+                    write!(mpd, "            <S").unwrap();
+                    if let Some(time) = time {
+                        write!(mpd, r#" t="{}""#, time).unwrap();
+                    }
+                    write!(mpd, r#" d="{}""#, duration).unwrap();
+                    if repeat > 0 {
+                        write!(mpd, r#" r="{}""#, repeat).unwrap();
+                    }
+                    writeln!(mpd, "/>").unwrap();
+                }
+                writeln!(mpd, "          </SegmentTimeline>").unwrap();
+                writeln!(mpd, "        </SegmentTemplate>").unwrap();
+            }
+        }
+    }
+
+    // The byte ranges of the segments inside the single files of the representation. Always
+    // with a `SegmentTimeline`, the list only covers the segments in the window.
+    fn render_segment_list(
+        &self,
+        mpd: &mut String,
+        representation: &Representation,
+        segments: &[&Segment],
+        period_start: DateTime<Utc>,
+    ) {
+        let first = segments[0];
+        let into_period = seconds_between(period_start, first.date_time).max(0.0);
+        let offset = (into_period * TIMESCALE as f64).round() as u64;
+
+        writeln!(
+            mpd,
+            r#"        <SegmentList timescale="{}" startNumber="{}" presentationTimeOffset="{}">"#,
+            TIMESCALE,
+            first.number,
+            ticks(first.time).saturating_sub(offset),
+        )
+        .unwrap();
+        writeln!(
+            mpd,
+            r#"          <Initialization sourceURL="{}/{}"/>"#,
+            escape(&representation.id),
+            escape(&first.init)
+        )
+        .unwrap();
+
+        writeln!(mpd, "          <SegmentTimeline>").unwrap();
+        for (time, duration, repeat) in timeline(segments) {
+            write!(mpd, "            <S").unwrap();
+            if let Some(time) = time {
+                write!(mpd, r#" t="{}""#, time).unwrap();
+            }
+            write!(mpd, r#" d="{}""#, duration).unwrap();
+            if repeat > 0 {
+                write!(mpd, r#" r="{}""#, repeat).unwrap();
+            }
+            writeln!(mpd, "/>").unwrap();
+        }
+        writeln!(mpd, "          </SegmentTimeline>").unwrap();
+
+        for segment in segments {
+            let (file, offset, length) =
+                segment.media_range.as_ref().expect("segment without byte range");
+            writeln!(
+                mpd,
+                r#"          <SegmentURL media="{}/{}" mediaRange="{}-{}"/>"#,
+                escape(&representation.id),
+                escape(file),
+                offset,
+                offset + length - 1
+            )
+            .unwrap();
+        }
+        writeln!(mpd, "        </SegmentList>").unwrap();
+    }
+
+    // Video renditions are grouped by codec and segment duration, players can only switch
+    // seamlessly within one and its segments have to be aligned.
+    // Every audio and subtitle rendition gets its own set for its language, the thumbnails
+    // get one too.
+    //
+    // Only the segments matching `filter` are considered, renditions without any are left out.
+    #[allow(clippy::type_complexity)]
+    fn adaptation_sets(
+        &self,
+        filter: impl Fn(&Segment) -> bool,
+    ) -> Vec<(&Kind, Vec<(&Representation, Vec<&Segment>)>)> {
+        let mut sets: Vec<(&Kind, Vec<(&Representation, Vec<&Segment>)>)> = vec![];
+
+        for (representation, timeline) in self.listed() {
+            let segments = timeline.segments.iter().filter(|segment| filter(segment)).collect::<Vec<_>>();
+            if segments.is_empty() {
+                continue;
+            }
+
+            let set = match representation.kind {
+                Kind::Video { ref codec, .. } => sets.iter_mut().find(|(kind, representations)| {
+                    matches!(kind, Kind::Video { codec: other, .. } if other == codec)
+                        && representations[0].0.segment_duration
+                            == representation.segment_duration
+                }),
+                Kind::Audio { .. } | Kind::Text { .. } | Kind::Image { .. } => None,
+            };
+
+            match set {
+                Some((_, representations)) => representations.push((representation, segments)),
+                None => sets.push((&representation.kind, vec![(representation, segments)])),
+            }
+        }
+
+        sets
+    }
+}
+
+// `S` elements as (t, d, r), with `t` only where the timeline doesn't simply continue
+fn timeline(segments: &[&Segment]) -> Vec<(Option<u64>, u64, u64)> {
+    let mut entries: Vec<(Option<u64>, u64, u64)> = vec![];
+    let mut next = None;
+
+    for segment in segments {
+        let time = ticks(segment.time);
+        let duration = ticks(segment.time + segment.duration) - time;
+        let continues = next == Some(time);
+        next = Some(time + duration);
+
+        match entries.last_mut() {
+            Some((_, last_duration, repeat)) if continues && *last_duration == duration => {
+                *repeat += 1
+            }
+            _ => entries.push((if continues { None } else { Some(time) }, duration, 0)),
+        }
+    }
+
+    entries
+}
+
+fn ticks(time: gst::ClockTime) -> u64 {
+    (time.nseconds() as u128 * TIMESCALE as u128 / gst::ClockTime::SECOND.nseconds() as u128)
+        as u64
+}
+
+fn nanoseconds(time: gst::ClockTime) -> Duration {
+    Duration::nanoseconds(time.nseconds() as i64)
+}
+
+fn seconds_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_microseconds().unwrap() as f64 / 1_000_000.0
+}
+
+fn date_time(date_time: DateTime<Utc>) -> String {
+    date_time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// xs:duration
+fn duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds)
+}
+
+/// Escapes `value` for XML text and attributes.
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpd(mode: PlaylistMode, addressing: Addressing) -> Mpd {
+        low_latency_mpd(mode, addressing, None)
+    }
+
+    fn low_latency_mpd(
+        mode: PlaylistMode,
+        addressing: Addressing,
+        part_duration: Option<gst::ClockTime>,
+    ) -> Mpd {
+        let settings = hlscmaf::Settings {
+            segment_duration: gst::ClockTime::from_seconds(2),
+            window_size: 5,
+            mode,
+            dvr_window: gst::ClockTime::from_seconds(3600),
+            part_duration,
+            encryption: None,
+            single_file: false,
+        };
+        let dash_settings = Settings {
+            addressing,
+            target_latency: gst::ClockTime::from_mseconds(1500),
+        };
+        let mut mpd = Mpd::new(PathBuf::from("manifest.mpd"), settings, dash_settings);
+
+        // Nothing is written as long as no rendition has segments
+        mpd.set_ladder(
+            &video::VideoStream::default_ladder(),
+            &audio::AudioStream::default_ladder(),
+            &[],
+            &mimes(),
+        );
+
+        mpd
+    }
+
+    // Codecs of the default ladder
+    fn mimes() -> HashMap<String, String> {
+        video::VideoStream::default_ladder()
+            .iter()
+            .map(|stream| (stream.name.clone(), "avc1.4d401e".to_string()))
+            .chain(std::iter::once(("audio_0".to_string(), "mp4a.40.2".to_string())))
+            .collect()
+    }
+
+    fn start_date_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    // Five 2s segments starting at running time 1s, those from `discontinuity_at` on after a
+    // jump by 100s
+    fn five_segments(ended: bool, discontinuity_at: Option<u64>) -> Timeline {
+        let segments = (0..5u64)
+            .map(|number| {
+                let jump = match discontinuity_at {
+                    Some(at) if number >= at => 100,
+                    _ => 0,
+                };
+                Segment {
+                    number,
+                    date_time: start_date_time() + Duration::seconds((2 * number + jump) as i64),
+                    time: gst::ClockTime::from_seconds(1 + 2 * number + jump),
+                    duration: gst::ClockTime::from_seconds(2),
+                    init: "init.mp4".to_string(),
+                    discontinuity: discontinuity_at == Some(number),
+                    default_kid: None,
+                    media_range: None,
+                }
+            })
+            .collect();
+
+        Timeline {
+            start_date_time: start_date_time(),
+            segments,
+            ended,
+        }
+    }
+
+    impl Mpd {
+        fn update_all(&mut self, timeline: Timeline) {
+            let ids = self
+                .representations
+                .iter()
+                .map(|representation| representation.id.clone())
+                .collect::<Vec<_>>();
+
+            for id in ids {
+                for segment in timeline.segments.iter().filter(|segment| segment.discontinuity) {
+                    self.add_period_start(segment.date_time);
+                }
+                self.timelines.insert(id, timeline.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_to_write_before_first_segment() {
+        assert!(mpd(PlaylistMode::Live, Addressing::Number).render(Utc::now()).is_none());
+    }
+
+    #[test]
+    fn renders_live_mpd() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        let parsed = dash_mpd::parse(&xml).unwrap();
+
+        assert_eq!(parsed.mpdtype.as_deref(), Some("dynamic"));
+        assert!(parsed.availabilityStartTime.is_some());
+        assert_eq!(parsed.timeShiftBufferDepth, Some(std::time::Duration::from_secs(10)));
+        assert_eq!(parsed.UTCTiming.len(), 1);
+
+        // One set per video codec plus the audio
+        let adaptations = &parsed.periods[0].adaptations;
+        assert_eq!(adaptations.len(), 4);
+
+        let template = adaptations[0].representations[0].SegmentTemplate.as_ref().unwrap();
+        assert_eq!(template.media.as_deref(), Some("$RepresentationID$/segment_$Number$.fmp4"));
+        assert_eq!(template.duration, Some(180_000.0));
+        assert_eq!(template.startNumber, Some(0));
+        assert_eq!(template.presentationTimeOffset, Some(90_000));
+    }
+
+    #[test]
+    fn limits_time_shift_buffer_to_shortest_window() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        let mut video = video::VideoStream::default_ladder();
+        video[0].window_size = Some(3);
+        mpd.set_ladder(&video, &audio::AudioStream::default_ladder(), &[], &mimes());
+        mpd.update_all(five_segments(false, None));
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+        assert_eq!(parsed.timeShiftBufferDepth, Some(std::time::Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn groups_video_by_segment_duration() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        let mut video = video::VideoStream::default_ladder();
+        for stream in &mut video {
+            stream.codec = "h264".to_string();
+        }
+        video[0].segment_duration = Some(4.0);
+        mpd.set_ladder(&video, &audio::AudioStream::default_ladder(), &[], &mimes());
+        mpd.update_all(five_segments(false, None));
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+        let sizes = parsed.periods[0]
+            .adaptations
+            .iter()
+            .map(|adaptation| adaptation.representations.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![1, 2, 1]);
+        assert_eq!(parsed.minBufferTime, Some(std::time::Duration::from_secs(4)));
+        assert_eq!(parsed.minimumUpdatePeriod, Some(std::time::Duration::from_secs(2)));
+        assert_eq!(parsed.suggestedPresentationDelay, Some(std::time::Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn addresses_late_renditions_from_origin() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.update_all(five_segments(false, None));
+
+        // Set up three segments later, numbered from the same origin
+        let mut late = five_segments(false, None);
+        late.segments.drain(..3);
+        mpd.timelines.insert("audio_0".to_string(), late);
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+        let audio = parsed.periods[0]
+            .adaptations
+            .iter()
+            .find(|adaptation| adaptation.contentType.as_deref() == Some("audio"))
+            .unwrap();
+        let template = audio.representations[0].SegmentTemplate.as_ref().unwrap();
+        assert_eq!(template.startNumber, Some(0));
+        assert_eq!(template.presentationTimeOffset, Some(90_000));
+    }
+
+    #[test]
+    fn renders_static_mpd_after_live_to_vod() {
+        let mut mpd = mpd(PlaylistMode::LiveToVod, Addressing::Number);
+        mpd.update_all(five_segments(true, None));
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+
+        assert_eq!(parsed.mpdtype.as_deref(), Some("static"));
+        assert_eq!(parsed.mediaPresentationDuration, Some(std::time::Duration::from_secs(10)));
+        assert!(parsed.minimumUpdatePeriod.is_none());
+    }
+
+    #[test]
+    fn renders_segment_timeline() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Timeline);
+        mpd.update_all(five_segments(false, None));
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+        let template = parsed.periods[0].adaptations[0].representations[0]
+            .SegmentTemplate
+            .as_ref()
+            .unwrap();
+        let entries = &template.SegmentTimeline.as_ref().unwrap().segments;
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].t, Some(90_000));
+        assert_eq!(entries[0].d, 180_000);
+        assert_eq!(entries[0].r, Some(4));
+    }
+
+    #[test]
+    fn starts_period_on_discontinuity() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Timeline);
+        mpd.update_all(five_segments(false, Some(3)));
+
+        let parsed = dash_mpd::parse(&mpd.render(Utc::now()).unwrap()).unwrap();
+        assert_eq!(parsed.periods.len(), 2);
+        assert_eq!(parsed.periods[1].start, Some(std::time::Duration::from_secs(106)));
+
+        let template = parsed.periods[1].adaptations[0].representations[0]
+            .SegmentTemplate
+            .as_ref()
+            .unwrap();
+        assert_eq!(template.startNumber, Some(3));
+        assert_eq!(template.presentationTimeOffset, Some(107 * 90_000));
+    }
+
+    #[test]
+    fn signals_content_protection() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.settings.encryption = Some(encryption::Settings {
+            method: encryption::Method::Cbcs,
+            key_rotation: 5,
+        });
+
+        let mut timeline = five_segments(false, None);
+        for segment in &mut timeline.segments {
+            segment.default_kid = Some([0xab; 16]);
+        }
+        mpd.update_all(timeline);
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        assert!(dash_mpd::parse(&xml).is_ok());
+        assert!(xml.contains(
+            r#"value="cbcs" cenc:default_KID="abababab-abab-abab-abab-abababababab"/>"#
+        ));
+        assert!(xml.contains("<dashif:Laurl>/license</dashif:Laurl>"));
+    }
+
+    #[test]
+    fn signals_ad_breaks() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.set_splices(vec![scte35::Splice {
+            id: 1,
+            out: gst::ClockTime::from_seconds(4),
+            duration: gst::ClockTime::from_seconds(4),
+        }]);
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        assert!(dash_mpd::parse(&xml).is_ok());
+        assert!(xml.contains(
+            r#"<EventStream schemeIdUri="urn:scte:scte35:2014:xml+bin" timescale="90000">"#
+        ));
+        assert!(xml.contains(r#"<Event presentationTime="360000" duration="360000" id="1">"#));
+    }
+
+    #[test]
+    fn announces_inband_events() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.set_event_schemes(&["urn:example:poll".to_string()]);
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        assert!(dash_mpd::parse(&xml).is_ok());
+        assert!(xml.contains(r#"<InbandEventStream schemeIdUri="https://aomedia.org/emsg/ID3"/>"#));
+        assert!(xml.contains(r#"<InbandEventStream schemeIdUri="urn:example:poll"/>"#));
+    }
+
+    #[test]
+    fn lists_byte_ranges_of_single_files() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        let mut timeline = five_segments(false, None);
+        for segment in &mut timeline.segments {
+            let file = format!("media_{}.fmp4", segment.number / 3);
+            segment.media_range = Some((file, 1000 * (segment.number % 3), 1000));
+        }
+        mpd.update_all(timeline);
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        assert!(dash_mpd::parse(&xml).is_ok());
+        assert!(xml.contains(
+            r#"<SegmentList timescale="90000" startNumber="0" presentationTimeOffset="90000">"#
+        ));
+        assert!(xml.contains(r#"<Initialization sourceURL="h264_0/init.mp4"/>"#));
+        assert!(xml.contains(r#"<S t="90000" d="180000" r="4"/>"#));
+        assert!(xml.contains(
+            r#"<SegmentURL media="h264_0/media_0.fmp4" mediaRange="2000-2999"/>"#
+        ));
+        assert!(xml.contains(
+            r#"<SegmentURL media="h264_0/media_1.fmp4" mediaRange="1000-1999"/>"#
+        ));
+        assert!(!xml.contains("SegmentTemplate"));
+    }
+
+    #[test]
+    fn lists_thumbnail_tiles() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        mpd.set_thumbnails(Some(thumbnails::Thumbnails::default()));
+        mpd.set_ladder(
+            &video::VideoStream::default_ladder(),
+            &audio::AudioStream::default_ladder(),
+            &[],
+            &mimes(),
+        );
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        let parsed = dash_mpd::parse(&xml).unwrap();
+        let image = parsed.periods[0].adaptations.last().unwrap();
+        let template = image.representations[0].SegmentTemplate.as_ref().unwrap();
+        assert_eq!(template.media.as_deref(), Some("$RepresentationID$/sprite_$Number$.jpg"));
+        assert_eq!(template.duration, Some(900_000.0));
+
+        assert!(xml.contains(r#"contentType="image" mimeType="image/jpeg">"#));
+        assert!(xml.contains(
+            r#"id="thumbnails" bandwidth="14400" codecs="jpeg" width="800" height="180">"#
+        ));
+        assert!(xml.contains(
+            r#"<EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="5x2"/>"#
+        ));
+    }
+
+    #[test]
+    fn lists_imsc1_subtitles() {
+        let mut mpd = mpd(PlaylistMode::Live, Addressing::Number);
+        let subtitles = vec![
+            subtitles::SubtitleStream {
+                name: "subtitles_0".to_string(),
+                format: subtitles::Format::Imsc1,
+                ..Default::default()
+            },
+            // WebVTT segments can't be listed
+            subtitles::SubtitleStream {
+                name: "subtitles_1".to_string(),
+                ..Default::default()
+            },
+        ];
+        mpd.set_ladder(
+            &video::VideoStream::default_ladder(),
+            &audio::AudioStream::default_ladder(),
+            &subtitles,
+            &mimes(),
+        );
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        let parsed = dash_mpd::parse(&xml).unwrap();
+        let text = parsed.periods[0]
+            .adaptations
+            .iter()
+            .find(|set| set.contentType.as_deref() == Some("text"))
+            .unwrap();
+        assert_eq!(text.representations.len(), 1);
+        assert_eq!(text.representations[0].codecs.as_deref(), Some("stpp.ttml.im1t"));
+        assert!(xml.contains(r#"<Role schemeIdUri="urn:mpeg:dash:role:2011" value="subtitle"/>"#));
+    }
+
+    #[test]
+    fn announces_chunks_in_low_latency_mode() {
+        let mut mpd = low_latency_mpd(
+            PlaylistMode::Live,
+            Addressing::Number,
+            Some(gst::ClockTime::from_mseconds(500)),
+        );
+        mpd.update_all(five_segments(false, None));
+
+        let xml = mpd.render(Utc::now()).unwrap();
+        let parsed = dash_mpd::parse(&xml).unwrap();
+
+        let template = parsed.periods[0].adaptations[0].representations[0]
+            .SegmentTemplate
+            .as_ref()
+            .unwrap();
+        assert_eq!(template.availabilityTimeOffset, Some(1.5));
+        assert_eq!(template.availabilityTimeComplete, Some(false));
+        assert!(xml.contains(r#"<Latency referenceId="0" target="1500""#));
+    }
+}
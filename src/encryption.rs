@@ -0,0 +1,762 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use anyhow::{anyhow, bail, Context, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::mp4::{self, Mp4Box};
+
+// The first bytes of each video NAL unit stay in clear, as for Apple's SAMPLE-AES. They cover
+// the NAL unit header and the slice header the player needs before decrypting.
+const CLEAR_LEADER: usize = 32;
+// cbcs pattern of the video, one encrypted block followed by nine clear ones
+const VIDEO_PATTERN: (u8, u8) = (1, 9);
+// Size of the per-sample IVs of `cenc`, `cbcs` uses a constant IV instead
+const CENC_IV_SIZE: usize = 8;
+
+/// System ID of W3C Clear Key in `pssh` boxes and `ContentProtection` elements.
+pub(crate) const CLEARKEY_SYSTEM_ID: [u8; 16] = [
+    0xe2, 0x71, 0x9d, 0x58, 0xa9, 0x85, 0xb3, 0xc9, 0x78, 0x1a, 0xb0, 0x30, 0xaf, 0x78, 0xd3, 0x0e,
+];
+/// The same as URN, for `KEYFORMAT` and `schemeIdUri`.
+pub(crate) const CLEARKEY_URN: &str = "urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e";
+/// Path of the Clear Key license endpoint on the built-in server.
+pub(crate) const LICENSE_PATH: &str = "/license";
+
+/// How the segments are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Method {
+    /// The whole segment, AES-128 in CBC mode with PKCS7 padding
+    #[default]
+    Aes128,
+    /// The media samples only, as CENC `cbcs` with the key served by the built-in server
+    SampleAes,
+    /// Common encryption in AES-CTR mode, with Clear Key as DRM system
+    Cenc,
+    /// Common encryption in AES-CBC mode with the 1:9 pattern, with Clear Key as DRM system
+    Cbcs,
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-128" => Ok(Method::Aes128),
+            "sample-aes" => Ok(Method::SampleAes),
+            "cenc" => Ok(Method::Cenc),
+            "cbcs" => Ok(Method::Cbcs),
+            _ => Err(anyhow!(
+                "unknown encryption method '{}', expected one of aes-128, sample-aes, cenc, cbcs",
+                s
+            )),
+        }
+    }
+}
+
+impl Method {
+    /// Whether keys are delivered through the Clear Key license endpoint, which DASH can
+    /// signal as well.
+    pub fn is_common(&self) -> bool {
+        matches!(self, Method::Cenc | Method::Cbcs)
+    }
+
+    /// The scheme of the protected samples, `cenc` or `cbcs`.
+    pub fn scheme(&self) -> Option<&'static str> {
+        match self {
+            Method::Aes128 => None,
+            Method::Cenc => Some("cenc"),
+            Method::SampleAes | Method::Cbcs => Some("cbcs"),
+        }
+    }
+}
+
+/// Encryption settings of an output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Settings {
+    pub method: Method,
+    /// Number of segments encrypted with the same key
+    pub key_rotation: u32,
+}
+
+/// A content key with its ID and the IV used with it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Key {
+    pub kid: [u8; 16],
+    pub key: [u8; 16],
+    pub iv: [u8; 16],
+}
+
+impl Key {
+    /// Path of the key on the built-in server.
+    pub fn uri(&self) -> String {
+        format!("/keys/{}", hex(&self.kid))
+    }
+}
+
+/// The keys of the segments players might still request, which the server hands out by key
+/// ID.
+#[derive(Default)]
+pub(crate) struct KeyStore {
+    keys: Mutex<HashMap<String, [u8; 16]>>,
+}
+
+#[derive(Deserialize)]
+struct LicenseRequest {
+    kids: Vec<String>,
+    #[serde(rename = "type", default)]
+    session_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct License {
+    keys: Vec<JsonWebKey>,
+    #[serde(rename = "type")]
+    session_type: String,
+}
+
+#[derive(Serialize)]
+struct JsonWebKey {
+    kty: &'static str,
+    kid: String,
+    k: String,
+}
+
+impl KeyStore {
+    /// Generates a new random key and makes it available to players.
+    pub fn generate(&self) -> Key {
+        let mut random = [0; 48];
+        getrandom::getrandom(&mut random).expect("no random numbers");
+
+        let key = Key {
+            kid: random[0..16].try_into().unwrap(),
+            key: random[16..32].try_into().unwrap(),
+            iv: random[32..48].try_into().unwrap(),
+        };
+        self.keys.lock().unwrap().insert(hex(&key.kid), key.key);
+
+        key
+    }
+
+    /// Forgets the key `kid` once no segment encrypted with it is around anymore.
+    pub fn remove(&self, kid: &[u8; 16]) {
+        self.keys.lock().unwrap().remove(&hex(kid));
+    }
+
+    /// Looks up a key by its hex encoded ID.
+    pub fn get(&self, kid: &str) -> Option<[u8; 16]> {
+        self.keys.lock().unwrap().get(kid).copied()
+    }
+
+    /// Answers a Clear Key license request of the EME, a JSON object with the base64url encoded
+    /// IDs of the keys it needs. Unknown keys are left out of the license.
+    pub fn license(&self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let request: LicenseRequest =
+            serde_json::from_slice(request).context("invalid license request")?;
+
+        let keys = request
+            .kids
+            .into_iter()
+            .filter_map(|kid| {
+                let key = self.get(&hex(&URL_SAFE_NO_PAD.decode(&kid).ok()?))?;
+                Some(JsonWebKey {
+                    kty: "oct",
+                    kid,
+                    k: URL_SAFE_NO_PAD.encode(key),
+                })
+            })
+            .collect();
+
+        let license = License {
+            keys,
+            session_type: request.session_type.unwrap_or_else(|| "temporary".to_string()),
+        };
+        Ok(serde_json::to_vec(&license)?)
+    }
+}
+
+/// What kind of samples a track has, which decides which parts of them get encrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Track {
+    /// H.264 or H.265 with NAL units prefixed by their length
+    Video { length_size: usize, hevc: bool },
+    Audio,
+}
+
+/// Encrypts a whole segment for `METHOD=AES-128`.
+pub(crate) fn encrypt_segment(key: &Key, data: &[u8]) -> Vec<u8> {
+    // PKCS7 always pads, by a whole block if needed
+    let padding = 16 - data.len() % 16;
+    let mut out = Vec::with_capacity(data.len() + padding);
+    out.extend_from_slice(data);
+    out.resize(data.len() + padding, padding as u8);
+
+    let cipher = aes::Aes128::new(&key.key.into());
+    encrypt_cbc(&cipher, &key.iv, &mut out, (0, 0));
+    out
+}
+
+/// A Clear Key `pssh` box listing `kids`.
+pub(crate) fn pssh(kids: &[[u8; 16]]) -> Mp4Box {
+    let mut data = CLEARKEY_SYSTEM_ID.to_vec();
+    data.extend_from_slice(&(kids.len() as u32).to_be_bytes());
+    for kid in kids {
+        data.extend_from_slice(kid);
+    }
+    // No system specific data
+    data.extend_from_slice(&0u32.to_be_bytes());
+
+    Mp4Box::full(b"pssh", 1, 0, &data)
+}
+
+/// Turns the media header into the one of a protected stream with `key` as default key.
+pub(crate) fn protect_header(key: &Key, method: Method, data: &[u8]) -> Result<(Vec<u8>, Track), Error> {
+    let Some(scheme) = method.scheme() else {
+        bail!("{:?} doesn't protect samples", method);
+    };
+
+    let mut boxes = Mp4Box::parse_all(data)?;
+    let mut track = None;
+
+    let Some(moov) = boxes.iter_mut().find(|mp4_box| &mp4_box.fourcc == b"moov") else {
+        bail!("header without moov");
+    };
+
+    for trak in moov.children.iter_mut().filter(|child| &child.fourcc == b"trak") {
+        let Some(stsd) = trak.find_mut(&[b"mdia", b"minf", b"stbl", b"stsd"]) else {
+            bail!("trak without stsd");
+        };
+
+        for entry in stsd.children.iter_mut() {
+            let format = entry.fourcc;
+            let (protected_format, entry_track) = match &format {
+                b"avc1" | b"avc3" => (b"encv", video_track(entry, b"avcC", 4, false)?),
+                b"hvc1" | b"hev1" => (b"encv", video_track(entry, b"hvcC", 21, true)?),
+                b"mp4a" => (b"enca", Track::Audio),
+                _ => bail!("can't encrypt {} samples", String::from_utf8_lossy(&format)),
+            };
+
+            entry.fourcc = *protected_format;
+            entry.children.push(sinf(key, scheme, &format, entry_track));
+            track = Some(entry_track);
+        }
+    }
+
+    // Lets the EME of browsers ask for the license right away
+    if method.is_common() {
+        moov.children.push(pssh(&[key.kid]));
+    }
+
+    let Some(track) = track else {
+        bail!("header without tracks");
+    };
+
+    Ok((mp4::write_all(&boxes), track))
+}
+
+/// Encrypts the samples of a fragment with `key` and adds the sample encryption boxes.
+pub(crate) fn protect_fragment(
+    key: &Key,
+    method: Method,
+    track: Track,
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let Some(scheme) = method.scheme() else {
+        bail!("{:?} doesn't protect samples", method);
+    };
+
+    let mut boxes = Mp4Box::parse_all(data)?;
+    let cipher = aes::Aes128::new(&key.key.into());
+
+    for idx in 0..boxes.len() {
+        if &boxes[idx].fourcc != b"moof" {
+            continue;
+        }
+
+        let Some(mdat_idx) = (idx + 1..boxes.len()).find(|idx| &boxes[*idx].fourcc == b"mdat")
+        else {
+            bail!("moof without mdat");
+        };
+
+        // Sample positions are relative to the start of the moof, the mdat follows it
+        let moof_size = boxes[idx].size();
+        let between = boxes[idx + 1..mdat_idx].iter().map(Mp4Box::size).sum::<usize>();
+        let mdat_start = moof_size + between + 8;
+
+        let (moof, rest) = boxes[idx..].split_at_mut(1);
+        let moof = &mut moof[0];
+        let mdat = &mut rest[mdat_idx - idx - 1];
+
+        let mut senc_positions = vec![];
+        for traf_idx in 0..moof.children.len() {
+            if &moof.children[traf_idx].fourcc != b"traf" {
+                continue;
+            }
+
+            let traf = &mut moof.children[traf_idx];
+            let mut entries = vec![];
+            for (offset, size) in mp4::sample_ranges(traf)? {
+                let Some(sample) = offset
+                    .checked_sub(mdat_start)
+                    .and_then(|start| mdat.data.get_mut(start..start + size))
+                else {
+                    bail!("sample outside of mdat");
+                };
+
+                // Sample auxiliary information, the IV and the subsamples
+                let mut entry = vec![];
+                let subsamples = if scheme == "cenc" {
+                    let mut iv = [0; CENC_IV_SIZE];
+                    getrandom::getrandom(&mut iv).expect("no random numbers");
+                    entry.extend_from_slice(&iv);
+
+                    // The counter runs on through all protected ranges of the sample
+                    let mut ctr = Ctr::new(&cipher, &iv);
+                    protect_sample(track, sample, |range| ctr.apply(range))
+                } else {
+                    // Each range starts over with the constant IV
+                    let pattern = pattern(scheme, track);
+                    protect_sample(track, sample, |range| {
+                        encrypt_cbc(&cipher, &key.iv, range, pattern)
+                    })
+                };
+
+                if let Some(subsamples) = subsamples {
+                    entry.extend_from_slice(&(subsamples.len() as u16).to_be_bytes());
+                    for (clear, protected) in subsamples {
+                        entry.extend_from_slice(&clear.to_be_bytes());
+                        entry.extend_from_slice(&protected.to_be_bytes());
+                    }
+                }
+                entries.push(entry);
+            }
+
+            let sample_count = entries.len() as u32;
+            traf.children.extend(sample_group(key, scheme, track, sample_count));
+
+            // Without subsamples and per-sample IVs there is no auxiliary information
+            if entries.iter().any(|entry| !entry.is_empty()) {
+                senc_positions.push((traf_idx, traf.children.len()));
+                traf.children.extend(auxiliary_information(track, &entries)?);
+            }
+        }
+
+        // Announces rotated keys to the EME
+        if method.is_common() {
+            moof.children.push(pssh(&[key.kid]));
+        }
+
+        let delta = moof.size() as i32 - moof_size as i32;
+        mp4::shift_data_offsets(moof, delta);
+
+        // Point the saio boxes to the first entry of their senc, relative to the moof
+        for (traf_idx, senc_idx) in senc_positions {
+            let mut position = 8;
+            position += moof.children[..traf_idx].iter().map(Mp4Box::size).sum::<usize>();
+            let traf = &mut moof.children[traf_idx];
+            position += 8 + traf.children[..senc_idx].iter().map(Mp4Box::size).sum::<usize>();
+            // Box header, version and flags, sample count
+            position += 16;
+
+            traf.children[senc_idx + 2].write_u32(8, position as u32);
+        }
+    }
+
+    Ok(mp4::write_all(&boxes))
+}
+
+// The size of the NAL unit lengths is in the lowest bits of the byte at `pos` of the decoder
+// configuration
+fn video_track(
+    entry: &Mp4Box,
+    config: &mp4::FourCC,
+    pos: usize,
+    hevc: bool,
+) -> Result<Track, Error> {
+    let Some(length_size) = entry
+        .child(config)
+        .and_then(|config| config.data.get(pos))
+        .map(|byte| (byte & 0x3) as usize + 1)
+    else {
+        bail!(
+            "{} without {}",
+            String::from_utf8_lossy(&entry.fourcc),
+            String::from_utf8_lossy(config)
+        );
+    };
+
+    Ok(Track::Video { length_size, hevc })
+}
+
+// Blocks encrypted and skipped in turn, `cenc` and `cbcs` audio encrypt every block
+fn pattern(scheme: &str, track: Track) -> (u8, u8) {
+    match (scheme, track) {
+        ("cbcs", Track::Video { .. }) => VIDEO_PATTERN,
+        _ => (0, 0),
+    }
+}
+
+// Protection scheme information of a sample entry
+fn sinf(key: &Key, scheme: &str, format: &mp4::FourCC, track: Track) -> Mp4Box {
+    let frma = Mp4Box::new(b"frma", format.to_vec());
+
+    let mut schm = scheme.as_bytes().to_vec();
+    schm.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    let schm = Mp4Box::full(b"schm", 0, 0, &schm);
+
+    // Only `cbcs` needs the version with the pattern
+    let version = if scheme == "cbcs" { 1 } else { 0 };
+    let tenc = Mp4Box::full(b"tenc", version, 0, &encryption_info(key, scheme, track));
+
+    let mut schi = Mp4Box::new(b"schi", vec![]);
+    schi.children.push(tenc);
+
+    let mut sinf = Mp4Box::new(b"sinf", vec![]);
+    sinf.children = vec![frma, schm, schi];
+    sinf
+}
+
+// Pattern, protected flag, IV size, KID and the constant IV of `cbcs`, as in `tenc` and `seig`
+// entries
+fn encryption_info(key: &Key, scheme: &str, track: Track) -> Vec<u8> {
+    let (crypt, skip) = pattern(scheme, track);
+    let mut info = vec![0, (crypt << 4) | skip, 1];
+
+    if scheme == "cenc" {
+        info.push(CENC_IV_SIZE as u8);
+        info.extend_from_slice(&key.kid);
+    } else {
+        info.push(0);
+        info.extend_from_slice(&key.kid);
+        info.push(16);
+        info.extend_from_slice(&key.iv);
+    }
+
+    info
+}
+
+// Maps all samples of the fragment to `key`, which rotates away from the default of the header
+fn sample_group(key: &Key, scheme: &str, track: Track, sample_count: u32) -> [Mp4Box; 2] {
+    let entry = encryption_info(key, scheme, track);
+
+    let mut sgpd = b"seig".to_vec();
+    sgpd.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+    sgpd.extend_from_slice(&1u32.to_be_bytes());
+    sgpd.extend_from_slice(&entry);
+
+    let mut sbgp = b"seig".to_vec();
+    sbgp.extend_from_slice(&1u32.to_be_bytes());
+    sbgp.extend_from_slice(&sample_count.to_be_bytes());
+    // First group description of this fragment
+    sbgp.extend_from_slice(&0x0001_0001u32.to_be_bytes());
+
+    [Mp4Box::full(b"sbgp", 0, 0, &sbgp), Mp4Box::full(b"sgpd", 1, 0, &sgpd)]
+}
+
+// The senc, saiz and saio boxes for the auxiliary information `entries` of all samples. The
+// offset in the saio is only known once the moof is complete.
+fn auxiliary_information(track: Track, entries: &[Vec<u8>]) -> Result<[Mp4Box; 3], Error> {
+    let sample_count = (entries.len() as u32).to_be_bytes();
+
+    let mut senc = sample_count.to_vec();
+    for entry in entries {
+        senc.extend_from_slice(entry);
+    }
+    // Video has subsample information
+    let flags = if let Track::Video { .. } = track { 0x2 } else { 0 };
+
+    // No default size, each sample has its own and there's a byte for it
+    let mut saiz = vec![0];
+    saiz.extend_from_slice(&sample_count);
+    for entry in entries {
+        let Ok(size) = u8::try_from(entry.len()) else {
+            bail!("auxiliary information of {} bytes, too many subsamples", entry.len());
+        };
+        saiz.push(size);
+    }
+
+    Ok([
+        Mp4Box::full(b"senc", 0, flags, &senc),
+        Mp4Box::full(b"saiz", 0, 0, &saiz),
+        Mp4Box::full(b"saio", 0, 0, &[0, 0, 0, 1, 0, 0, 0, 0]),
+    ])
+}
+
+// Passes the protected ranges of a sample to `encrypt` and returns its subsamples as pairs of
+// clear and protected bytes. Audio samples are protected as a whole and have no subsamples.
+fn protect_sample(
+    track: Track,
+    sample: &mut [u8],
+    mut encrypt: impl FnMut(&mut [u8]),
+) -> Option<Vec<(u16, u32)>> {
+    let Track::Video { length_size, hevc } = track else {
+        encrypt(sample);
+        return None;
+    };
+
+    let mut subsamples = vec![];
+    let mut clear = 0;
+    let mut pos = 0;
+
+    while pos + length_size < sample.len() {
+        let nal_size = sample[pos..pos + length_size]
+            .iter()
+            .fold(0, |size, byte| (size << 8) | *byte as usize);
+        let nal_start = pos + length_size;
+        let nal_end = (nal_start + nal_size).min(sample.len());
+
+        let vcl = if hevc {
+            ((sample[nal_start] >> 1) & 0x3f) < 32
+        } else {
+            (1..=5).contains(&(sample[nal_start] & 0x1f))
+        };
+
+        if vcl && nal_end - nal_start >= CLEAR_LEADER + 16 {
+            let protected_start = nal_start + CLEAR_LEADER;
+            // Whole blocks only, the remainder is in clear with the next NAL unit
+            let protected_end = protected_start + (nal_end - protected_start) / 16 * 16;
+
+            encrypt(&mut sample[protected_start..protected_end]);
+            push_subsample(
+                &mut subsamples,
+                clear + protected_start - pos,
+                protected_end - protected_start,
+            );
+            clear = nal_end - protected_end;
+        } else {
+            clear += nal_end - pos;
+        }
+
+        pos = nal_end;
+    }
+
+    clear += sample.len() - pos;
+    if clear > 0 || subsamples.is_empty() {
+        push_subsample(&mut subsamples, clear, 0);
+    }
+
+    Some(subsamples)
+}
+
+// Clear ranges longer than 64k are split up
+fn push_subsample(subsamples: &mut Vec<(u16, u32)>, mut clear: usize, protected: usize) {
+    while clear > u16::MAX as usize {
+        subsamples.push((u16::MAX, 0));
+        clear -= u16::MAX as usize;
+    }
+
+    subsamples.push((clear as u16, protected as u32));
+}
+
+// AES-CBC over the whole blocks of `data`, with `pattern` blocks encrypted and skipped in turn.
+// The chain only runs through the encrypted blocks.
+fn encrypt_cbc(cipher: &aes::Aes128, iv: &[u8; 16], data: &mut [u8], pattern: (u8, u8)) {
+    let (crypt, skip) = match pattern {
+        (0, 0) => (1, 0),
+        (crypt, skip) => (crypt as usize, skip as usize),
+    };
+
+    let mut previous = *iv;
+    for (idx, block) in data.chunks_exact_mut(16).enumerate() {
+        if idx % (crypt + skip) >= crypt {
+            continue;
+        }
+
+        for (byte, previous) in block.iter_mut().zip(previous.iter()) {
+            *byte ^= previous;
+        }
+        cipher.encrypt_block(aes::Block::from_mut_slice(block));
+        previous.copy_from_slice(block);
+    }
+}
+
+// AES-CTR with the 8 byte IV in the upper and the block counter in the lower half of the
+// counter block
+struct Ctr<'a> {
+    cipher: &'a aes::Aes128,
+    counter: [u8; 16],
+    keystream: [u8; 16],
+    // Bytes of `keystream` used up
+    used: usize,
+}
+
+impl<'a> Ctr<'a> {
+    fn new(cipher: &'a aes::Aes128, iv: &[u8; CENC_IV_SIZE]) -> Self {
+        let mut counter = [0; 16];
+        counter[..CENC_IV_SIZE].copy_from_slice(iv);
+
+        Ctr {
+            cipher,
+            counter,
+            keystream: [0; 16],
+            used: 16,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 16 {
+                self.keystream = self.counter;
+                self.cipher.encrypt_block(aes::Block::from_mut_slice(&mut self.keystream));
+
+                let block = u64::from_be_bytes(self.counter[8..].try_into().unwrap());
+                self.counter[8..].copy_from_slice(&block.wrapping_add(1).to_be_bytes());
+                self.used = 0;
+            }
+
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A key ID in UUID notation, as in `cenc:default_KID`.
+pub(crate) fn uuid(kid: &[u8; 16]) -> String {
+    let kid = hex(kid);
+    format!("{}-{}-{}-{}-{}", &kid[0..8], &kid[8..12], &kid[12..16], &kid[16..20], &kid[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key {
+            kid: [1; 16],
+            key: [2; 16],
+            iv: [3; 16],
+        }
+    }
+
+    #[test]
+    fn pads_whole_segments() {
+        assert_eq!(encrypt_segment(&key(), &[0; 20]).len(), 32);
+        assert_eq!(encrypt_segment(&key(), &[0; 32]).len(), 48);
+    }
+
+    #[test]
+    fn leaves_slice_headers_in_clear() {
+        let track = Track::Video { length_size: 4, hevc: false };
+        let cipher = aes::Aes128::new(&key().key.into());
+
+        // SPS, then an IDR slice of 100 bytes
+        let mut sample = vec![0, 0, 0, 4, 0x67, 1, 2, 3, 0, 0, 0, 100, 0x65];
+        sample.extend_from_slice(&[0xaa; 99]);
+        let original = sample.clone();
+
+        let subsamples = protect_sample(track, &mut sample, |range| {
+            encrypt_cbc(&cipher, &key().iv, range, VIDEO_PATTERN)
+        });
+
+        // 8 bytes SPS, 4 bytes length and 32 bytes leader in clear, 64 bytes protected and
+        // the remaining 4 bytes in clear again
+        assert_eq!(subsamples, Some(vec![(44, 64), (4, 0)]));
+        assert_eq!(sample[..44], original[..44]);
+        assert_ne!(sample[44..60], original[44..60]);
+        // Nine blocks skipped after the first one
+        assert_eq!(sample[60..108], original[60..108]);
+    }
+
+    #[test]
+    fn continues_counter_across_ranges() {
+        let cipher = aes::Aes128::new(&key().key.into());
+        let mut whole = [0; 40];
+        Ctr::new(&cipher, &[4; 8]).apply(&mut whole);
+
+        let mut split = [0; 40];
+        let mut ctr = Ctr::new(&cipher, &[4; 8]);
+        ctr.apply(&mut split[..10]);
+        ctr.apply(&mut split[10..]);
+
+        assert_eq!(whole, split);
+        assert_ne!(whole[..16], whole[16..32]);
+    }
+
+    #[test]
+    fn protects_cenc_fragments() {
+        let tfhd = Mp4Box::full(b"tfhd", 0, 0x20000, &1u32.to_be_bytes());
+        let mut trun = vec![0, 0, 0, 2, 0, 0, 0, 0];
+        trun.extend_from_slice(&[0, 0, 0, 20, 0, 0, 0, 20]);
+        let mut traf = Mp4Box::new(b"traf", vec![]);
+        traf.children = vec![tfhd, Mp4Box::full(b"trun", 0, 0x201, &trun)];
+        let mut moof = Mp4Box::new(b"moof", vec![]);
+        moof.children = vec![traf];
+
+        // The samples start right after the mdat header
+        let offset = moof.size() as u32 + 8;
+        moof.children[0].children[1].write_u32(8, offset);
+        let data = mp4::write_all(&[moof, Mp4Box::new(b"mdat", vec![0xaa; 40])]);
+
+        let protected = protect_fragment(&key(), Method::Cenc, Track::Audio, &data).unwrap();
+        let boxes = Mp4Box::parse_all(&protected).unwrap();
+        let moof = &boxes[0];
+        let traf = moof.child(b"traf").unwrap();
+        assert!(moof.child(b"pssh").is_some());
+
+        // The data offset follows the grown moof
+        let ranges = mp4::sample_ranges(traf).unwrap();
+        assert_eq!(ranges, vec![(moof.size() + 8, 20), (moof.size() + 28, 20)]);
+        assert_ne!(boxes[1].data, vec![0xaa; 40]);
+
+        // The saio points to the IV of the first sample
+        let senc = traf.child(b"senc").unwrap();
+        let saio = traf.child(b"saio").unwrap().read_u32(8).unwrap() as usize;
+        assert_eq!(protected[saio..saio + 16], senc.data[8..24]);
+    }
+
+    #[test]
+    fn answers_license_requests() {
+        let keys = KeyStore::default();
+        let key = keys.generate();
+        let kid = URL_SAFE_NO_PAD.encode(key.kid);
+
+        let request = format!(r#"{{"kids":["{}","AAAAAAAAAAAAAAAAAAAAAA"],"type":"temporary"}}"#, kid);
+        let license: serde_json::Value =
+            serde_json::from_slice(&keys.license(request.as_bytes()).unwrap()).unwrap();
+
+        assert_eq!(
+            license,
+            serde_json::json!({
+                "keys": [{"kty": "oct", "kid": kid, "k": URL_SAFE_NO_PAD.encode(key.key)}],
+                "type": "temporary",
+            })
+        );
+        assert!(keys.license(b"{}").is_err());
+
+        keys.remove(&key.kid);
+        assert!(keys.get(&hex(&key.kid)).is_none());
+    }
+
+    #[test]
+    fn refuses_oversized_auxiliary_information() {
+        let track = Track::Video {
+            length_size: 4,
+            hevc: false,
+        };
+
+        assert!(auxiliary_information(track, &[vec![0; 255]]).is_ok());
+        assert!(auxiliary_information(track, &[vec![0; 14], vec![0; 8 + 2 + 6 * 42]]).is_err());
+    }
+
+    #[test]
+    fn formats_key_ids() {
+        assert_eq!(uuid(&key().kid), "01010101-0101-0101-0101-010101010101");
+    }
+
+    #[test]
+    fn splits_long_clear_ranges() {
+        let mut subsamples = vec![];
+        push_subsample(&mut subsamples, 70_000, 16);
+        assert_eq!(subsamples, vec![(u16::MAX, 0), (4465, 16)]);
+    }
+}
@@ -1,55 +1,512 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
+    io::Write,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
-use m3u8_rs::{MediaPlaylist, MediaSegment};
+use anyhow::{anyhow, Context, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use m3u8_rs::{MediaPlaylist, MediaPlaylistType, MediaSegment};
+use serde::Deserialize;
 
-use chrono::{Duration, Utc, DateTime};
+use chrono::{Duration, SecondsFormat, Utc, DateTime};
 use gst::prelude::*;
-use log::info;
+use log::{info, warn};
+
+use crate::{
+    captions, dash,
+    encryption::{self, Key, KeyStore},
+    interstitials,
+    metadata::MetadataStore,
+    mp4,
+    progress::{Position, Progress},
+    scte35, subtitles, utils,
+};
+
+/// Prefix of the files the segments of a rendition go into with [`Settings::single_file`],
+/// `media_N.fmp4` or `media_N.ts`.
+pub(crate) const SINGLE_FILE: &str = "media";
+
+// Larger differences between the end of a fragment and the start of the next one are
+// signalled as discontinuities
+const MAX_GAP: gst::ClockTime = gst::ClockTime::from_mseconds(500);
+// How far ahead of a segment boundary MPEG-TS segments may be cut, the frame durations are
+// rounded to nanoseconds and don't add up to it exactly
+const TS_TOLERANCE: gst::ClockTime = gst::ClockTime::from_mseconds(10);
+
+/// How the media playlists evolve over time and which segments are kept around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PlaylistMode {
+    /// Sliding window of `window_size` segments, older segments get deleted
+    #[default]
+    Live,
+    /// `EXT-X-PLAYLIST-TYPE:EVENT`, segments are only ever appended
+    Event,
+    /// Sliding window covering `dvr_window` worth of segments
+    Dvr,
+    /// Sliding window like `Live`, but no segment is deleted and a VOD playlist covering the
+    /// whole session replaces the live one at the end
+    LiveToVod,
+}
+
+impl FromStr for PlaylistMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "live" => Ok(PlaylistMode::Live),
+            "event" => Ok(PlaylistMode::Event),
+            "dvr" => Ok(PlaylistMode::Dvr),
+            "live-to-vod" => Ok(PlaylistMode::LiveToVod),
+            _ => Err(anyhow!(
+                "unknown playlist mode '{}', expected one of live, event, dvr, live-to-vod",
+                s
+            )),
+        }
+    }
+}
+
+/// Sub-directory of the output the MPEG-TS renditions and their master playlist are written to
+/// when they come along with the CMAF ones.
+pub(crate) const TS_DIR: &str = "ts";
+
+/// Container of the segments of the video and audio renditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SegmentFormat {
+    /// fMP4 segments as listed in both the HLS playlists and the DASH manifest
+    #[default]
+    Cmaf,
+    /// MPEG-TS segments for legacy HLS players, without any DASH manifest
+    Ts,
+    /// Both of them out of the same encoders, the TS renditions below [`TS_DIR`]
+    Both,
+}
+
+impl SegmentFormat {
+    pub fn has_cmaf(self) -> bool {
+        self != SegmentFormat::Ts
+    }
+
+    pub fn has_ts(self) -> bool {
+        self != SegmentFormat::Cmaf
+    }
+}
+
+impl FromStr for SegmentFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cmaf" => Ok(SegmentFormat::Cmaf),
+            "ts" => Ok(SegmentFormat::Ts),
+            "both" => Ok(SegmentFormat::Both),
+            _ => Err(anyhow!("unknown segment format '{}', expected one of cmaf, ts, both", s)),
+        }
+    }
+}
+
+/// Packaging settings shared by the renditions of an output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Settings {
+    pub segment_duration: gst::ClockTime,
+    pub window_size: usize,
+    pub mode: PlaylistMode,
+    pub dvr_window: gst::ClockTime,
+    /// Enables Low-Latency HLS and DASH with parts of this duration
+    pub part_duration: Option<gst::ClockTime>,
+    /// Encrypts the segments when set
+    pub encryption: Option<encryption::Settings>,
+    /// Appends the segments of each rendition to a single file, addressed by byte ranges
+    pub single_file: bool,
+}
+
+impl Settings {
+    /// Applies the overrides a single rendition might have on top of the output settings.
+    pub fn with_overrides(&self, segment_duration: Option<f64>, window_size: Option<usize>) -> Self {
+        Settings {
+            segment_duration: segment_duration
+                .map(utils::seconds_to_clock_time)
+                .unwrap_or(self.segment_duration),
+            window_size: window_size.unwrap_or(self.window_size),
+            ..*self
+        }
+    }
+}
+
+/// Where and how the renditions of an output are written.
+pub(crate) struct Output {
+    pub path: PathBuf,
+    pub settings: Settings,
+    pub progress: Arc<Progress>,
+    /// The DASH manifest listing the same segments
+    pub mpd: Arc<Mutex<dash::Mpd>>,
+    /// Where the keys of encrypted outputs go, for the server to hand them out
+    pub keys: Arc<KeyStore>,
+    /// Embedded into the video of the H.264 and H.265 renditions when set
+    pub captions: Option<captions::Captions>,
+    /// Ad breaks signalled in the playlists and the DASH manifest
+    pub splices: Vec<scte35::Splice>,
+    /// Interstitial events signalled in the media playlists
+    pub interstitials: Vec<interstitials::Interstitial>,
+    /// Timed metadata carried in the fragments
+    pub metadata: Arc<MetadataStore>,
+    /// Whether the renditions are written as fMP4, MPEG-TS or both
+    pub format: SegmentFormat,
+    /// Packaging state of the renditions, which outlives their branches
+    pub streams: Arc<Streams>,
+    /// Where the timelines of all renditions start
+    pub origin: Arc<Origin>,
+}
+
+impl Output {
+    /// Directory the MPEG-TS renditions and their master playlist are written to.
+    pub fn ts_path(&self) -> PathBuf {
+        match self.format {
+            SegmentFormat::Both => self.path.join(TS_DIR),
+            _ => self.path.clone(),
+        }
+    }
+}
+
+/// The running time the first rendition of an output started at, and the wall clock time that
+/// corresponds to.
+///
+/// Every rendition dates and numbers its segments from it, including those set up later on, so
+/// the DASH manifest can address all of them from the same availability start time.
+#[derive(Default)]
+pub(crate) struct Origin(Mutex<Option<(gst::ClockTime, DateTime<Utc>)>>);
+
+impl Origin {
+    /// The origin, anchored to running time `time` of `element` unless that already happened.
+    pub fn anchor(
+        &self,
+        element: &gst::Element,
+        time: gst::ClockTime,
+    ) -> (gst::ClockTime, DateTime<Utc>) {
+        *self.0.lock().unwrap().get_or_insert_with(|| {
+            let now_utc = Utc::now();
+            let now_gst = element.clock().unwrap().time().unwrap();
+            let diff = now_gst.saturating_sub(time + element.base_time().unwrap());
+
+            (time, now_utc - Duration::nanoseconds(diff.nseconds() as i64))
+        })
+    }
+}
+
+/// The packaging state of every rendition, by the directory it's written to.
+///
+/// A rendition set up again under the same name, e.g. because its settings changed on reload,
+/// takes over the state of the previous one. Its playlists continue after a discontinuity
+/// instead of starting over.
+#[derive(Default)]
+pub(crate) struct Streams {
+    states: Mutex<HashMap<PathBuf, Arc<Mutex<StreamState>>>>,
+}
 
 struct StreamState {
+    // Increased whenever another branch takes over, anything the previous one still outputs
+    // is dropped
+    generation: u64,
+    name: String,
     path: PathBuf,
+    settings: Settings,
+    progress: Arc<Progress>,
+    mpd: Arc<Mutex<dash::Mpd>>,
+    keys: Arc<KeyStore>,
+    // Key of the newest segment when encrypting
+    key: Option<Key>,
+    // Default key of the current header, which keeps it when rewritten at the end
+    header_key: Option<Key>,
+    // What the samples look like, known once the header was protected for SAMPLE-AES
+    track: Option<encryption::Track>,
     segments: VecDeque<Segment>,
+    // The segment parts are currently written to in Low-Latency mode
+    open_segment: Option<OpenSegment>,
+    // Segments that left the live window but are kept for the live-to-VOD playlist
+    archived_segments: Vec<Segment>,
     trimmed_segments: VecDeque<UnreffedSegment>,
     start_date_time: Option<DateTime<Utc>>,
     start_time: Option<gst::ClockTime>,
+    // Running time the next fragment is expected at
+    next_time: Option<gst::ClockTime>,
+    // Header of the muxer that can only be placed once the next fragment arrives, see below
+    pending_header: Option<Vec<u8>>,
+    // File name of the header the current segments refer to
+    init: String,
+    init_index: u32,
+    // The next segment starts a new timeline
+    discontinuity: bool,
     media_sequence: u64,
+    discontinuity_sequence: u64,
     segment_index: u32,
+    part_index: u64,
+    max_segment_duration: gst::ClockTime,
+    max_part_duration: gst::ClockTime,
+    ended: bool,
+    // WebVTT segments have no header and aren't listed in the MPD
+    webvtt: bool,
+    // Writes `iframes.m3u8` along with the media playlist
+    iframes: bool,
+    iframe_sequence: IFrameSequence,
+    // Peak bit rate of the I-frame playlist, and whether it rose since it was last reported
+    iframe_peak: u64,
+    iframe_peak_rose: bool,
+    // MPEG-TS segments, or the WebVTT ones of the MPEG-TS renditions, which have no header
+    // either and aren't listed in the MPD
+    ts: bool,
+    // The MPEG-TS segment packets are currently collected for
+    open_ts_segment: Option<(Segment, Vec<u8>)>,
+    // The file segments are currently appended to in single file mode, its name and size
+    single_file: Option<(String, std::fs::File, u64)>,
+    // Keys of the segments that left the playlist while their file was still in use, which
+    // go along with it
+    unlisted_kids: Vec<[u8; 16]>,
+    splices: Vec<scte35::Splice>,
+    interstitials: Vec<interstitials::Interstitial>,
+    metadata: Arc<MetadataStore>,
+    origin: Arc<Origin>,
+}
+
+// Sequence numbers of the I-frame playlist, which leaves out segments without keyframe
+#[derive(Clone, Copy, Default)]
+struct IFrameSequence {
+    media: u64,
+    discontinuity: u64,
+    // The discontinuity of an unlisted segment, which the next listed one got
+    carried: bool,
 }
 
 struct Segment {
+    number: u32,
     date_time: DateTime<Utc>,
+    // Running time, which is also the media time of the muxer
+    time: gst::ClockTime,
     duration: gst::ClockTime,
     path: String,
+    init: String,
+    discontinuity: bool,
+    key: Option<Key>,
+    // Default key ID of the header, for the DASH manifest
+    default_kid: Option<[u8; 16]>,
+    // Offset and length of the keyframe the segment starts with, for the I-frame playlist
+    iframe: Option<(u64, u64)>,
+    // Offset and length inside the file of the rendition in single file mode
+    byte_range: Option<(u64, u64)>,
+    parts: Vec<Part>,
+}
+
+struct Part {
+    duration: gst::ClockTime,
+    path: String,
+    independent: bool,
+}
+
+struct OpenSegment {
+    segment: Segment,
+    file: std::fs::File,
+    // Bytes written to `file` so far
+    written: u64,
 }
 
 struct UnreffedSegment {
     removal_time: DateTime<Utc>,
     path: String,
+    parts: Vec<String>,
+    // Keys the files were encrypted with, which go along with them unless still needed
+    kids: Vec<[u8; 16]>,
 }
 
-pub(crate) fn setup(appsink: &gst_app::AppSink, name: &str, path: &Path) {
-    let mut path: PathBuf = path.into();
+fn new_state(name: &str, output: &Output, settings: &Settings) -> StreamState {
+    let mut path: PathBuf = output.path.clone();
     path.push(name);
 
-    let state = Arc::new(Mutex::new(StreamState {
+    StreamState {
+        generation: 0,
         segments: VecDeque::new(),
+        open_segment: None,
         trimmed_segments: VecDeque::new(),
+        archived_segments: Vec::new(),
+        name: name.to_string(),
         path,
+        settings: *settings,
+        progress: output.progress.clone(),
+        mpd: output.mpd.clone(),
+        keys: output.keys.clone(),
+        key: settings.encryption.map(|_| output.keys.generate()),
+        header_key: None,
+        track: None,
         start_date_time: None,
         start_time: gst::ClockTime::NONE,
+        next_time: gst::ClockTime::NONE,
+        pending_header: None,
+        init: "init.mp4".to_string(),
+        init_index: 0,
+        discontinuity: false,
         media_sequence: 0,
+        discontinuity_sequence: 0,
         segment_index: 0,
-    }));
+        part_index: 0,
+        max_segment_duration: gst::ClockTime::ZERO,
+        max_part_duration: gst::ClockTime::ZERO,
+        ended: false,
+        webvtt: false,
+        iframes: false,
+        iframe_sequence: IFrameSequence::default(),
+        iframe_peak: 0,
+        iframe_peak_rose: false,
+        ts: false,
+        open_ts_segment: None,
+        single_file: None,
+        unlisted_kids: Vec::new(),
+        splices: output.splices.clone(),
+        interstitials: output.interstitials.clone(),
+        metadata: output.metadata.clone(),
+        origin: output.origin.clone(),
+    }
+}
+
+/// Told the peak bit rate of the I-frame playlist of a video rendition whenever it rose.
+pub(crate) type IFramePeak = Box<dyn Fn(u64) + Send + Sync>;
+
+pub(crate) fn setup(appsink: &gst_app::AppSink, name: &str, output: &Output, settings: &Settings) {
+    setup_fragments(appsink, output, new_state(name, output, settings), None);
+}
+
+/// Like [`setup`], with an I-frame playlist next to the media playlist. Segments encrypted as
+/// a whole have no keyframes to point at, and segments without keyframe are left out.
+pub(crate) fn setup_video(
+    appsink: &gst_app::AppSink,
+    name: &str,
+    output: &Output,
+    settings: &Settings,
+    on_iframe_peak: IFramePeak,
+) {
+    let mut state = new_state(name, output, settings);
+    state.iframes = has_iframe_playlist(settings);
+    setup_fragments(appsink, output, state, Some(on_iframe_peak));
+}
+
+// Whether video renditions with `settings` get an I-frame playlist
+fn has_iframe_playlist(settings: &Settings) -> bool {
+    settings.encryption.map_or(true, |encryption| encryption.method != encryption::Method::Aes128)
+}
+
+/// Deletes the files of the rendition `name`, which was removed from the ladder, once players
+/// had the time to see its finished playlists. Nothing is deleted if a rendition of the same
+/// name took them over meanwhile.
+pub(crate) fn retire(output: &Output, name: &str) {
+    let mut paths = vec![output.path.join(name)];
+    if output.format == SegmentFormat::Both {
+        paths.push(output.ts_path().join(name));
+    }
+
+    for path in paths {
+        let Some(shared) = output.streams.states.lock().unwrap().get(&path).cloned() else {
+            continue;
+        };
+        let (generation, delay) = {
+            let state = shared.lock().unwrap();
+            let delay = playlist_duration(&state.segments) + state.max_segment_duration;
+            (state.generation, std::time::Duration::from_nanos(delay.nseconds()))
+        };
+
+        let streams = output.streams.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+
+            let mut states = streams.states.lock().unwrap();
+            match states.get(&path) {
+                Some(state) if state.lock().unwrap().generation == generation => (),
+                _ => return,
+            }
+            if let Some(state) = states.remove(&path) {
+                let state = state.lock().unwrap();
+                for kid in kids_in_use(&state) {
+                    state.keys.remove(&kid);
+                }
+            }
+
+            info!("deleting {}", path.display());
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                warn!("failed to delete {}: {}", path.display(), err);
+            }
+        });
+    }
+}
+
+// Registers `state` for its directory, unless the state of a previous rendition written there
+// is still around. That one is handed over instead, the returned generation tells the samples
+// of the new branch apart from those the previous one might still output.
+fn claim_state(output: &Output, state: StreamState) -> (Arc<Mutex<StreamState>>, u64) {
+    let mut states = output.streams.states.lock().unwrap();
+    if let Some(shared) = states.get(&state.path) {
+        let mut previous = shared.lock().unwrap();
+        info!("continuing the playlists in {}", previous.path.display());
+        hand_over(&mut previous, state);
+        return (shared.clone(), previous.generation);
+    }
+
+    let path = state.path.clone();
+    let shared = Arc::new(Mutex::new(state));
+    states.insert(path, shared.clone());
+    (shared, 0)
+}
+
+// Continues the playlists of `state` with the rendition `new` describes. Whatever the previous
+// branch had in progress is listed as it is, the output of the new one follows after a
+// discontinuity and with a header of its own.
+fn hand_over(state: &mut StreamState, new: StreamState) {
+    finish_segment(state);
+    if let Some(newest) = state.next_time {
+        if let Err(err) = finish_ts_segment(state, newest) {
+            warn!("failed to finish {}: {:#}", state.path.display(), err);
+        }
+    }
+
+    // WebVTT and IMSC1 segments can't be listed along with each other
+    if state.webvtt != new.webvtt {
+        let now = Utc::now();
+        while !state.segments.is_empty() {
+            unlist_oldest(state, now);
+        }
+        state.webvtt = new.webvtt;
+        state.init = new.init;
+    }
+
+    state.generation += 1;
+    state.settings = new.settings;
+    state.iframes = new.iframes;
+    // The master playlist forgot the peak along with the previous branch
+    state.iframe_peak_rose = state.iframes && state.iframe_peak > 0;
+    state.pending_header = None;
+    state.header_key = None;
+    state.track = None;
+    state.discontinuity = true;
+    state.ended = false;
+}
+
+fn setup_fragments(
+    appsink: &gst_app::AppSink,
+    output: &Output,
+    state: StreamState,
+    on_iframe_peak: Option<IFramePeak>,
+) {
+    let (state, generation) = claim_state(output, state);
+    let eos_state = state.clone();
 
     appsink.set_callbacks(
         gst_app::AppSinkCallbacks::builder()
             .new_sample(move |sink| {
                 let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
                 let mut state = state.lock().unwrap();
+                // Replaced by another branch, which lists its own fragments
+                if state.generation != generation {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
 
                 // The muxer only outputs non-empty buffer lists
                 let mut buffer_list = sample.buffer_list_owned().expect("no buffer list");
@@ -57,24 +514,29 @@ pub(crate) fn setup(appsink: &gst_app::AppSink, name: &str, path: &Path) {
 
                 let mut first = buffer_list.get(0).unwrap();
 
-                // Each list contains a full segment, i.e. does not start with a DELTA_UNIT
-                assert!(!first.flags().contains(gst::BufferFlags::DELTA_UNIT));
+                // Each list contains a full segment, i.e. does not start with a DELTA_UNIT,
+                // unless the muxer outputs chunks for Low-Latency HLS. Then only the first
+                // chunk of each segment doesn't start with a DELTA_UNIT.
+                let starts_segment = !first.flags().contains(gst::BufferFlags::DELTA_UNIT);
+                assert!(starts_segment || state.settings.part_duration.is_some());
 
                 // If the buffer has the DISCONT and HEADER flag set then it contains the media
                 // header, i.e. the `ftyp`, `moov` and other media boxes.
                 //
-                // This might be the initial header or the updated header at the end of the stream.
+                // This might be the initial header, the updated header at the end of the stream
+                // or a new header because the caps changed. The latter two can only be told
+                // apart by whether another fragment follows.
                 if first
                     .flags()
                     .contains(gst::BufferFlags::DISCONT | gst::BufferFlags::HEADER)
                 {
-                    let mut path = state.path.clone();
-                    std::fs::create_dir_all(&path).expect("failed to create directory");
-                    path.push("init.mp4");
-
-                    info!("writing header to {}", path.display());
                     let map = first.map_readable().unwrap();
-                    std::fs::write(path, &map).expect("failed to write header");
+                    if state.segments.is_empty() && state.open_segment.is_none() {
+                        std::fs::create_dir_all(&state.path).expect("failed to create directory");
+                        write_header(&mut state, &map).map_err(|err| fail(sink, err))?;
+                    } else {
+                        state.pending_header = Some(map.to_vec());
+                    }
                     drop(map);
 
                     // Remove the header from the buffer list
@@ -94,11 +556,6 @@ pub(crate) fn setup(appsink: &gst_app::AppSink, name: &str, path: &Path) {
                 // followed by one or more actual media buffers.
                 assert!(first.flags().contains(gst::BufferFlags::HEADER));
 
-                let mut path = state.path.clone();
-                let basename = format!("segment_{}.fmp4", state.segment_index);
-                state.segment_index += 1;
-                path.push(&basename);
-
                 let segment = sample
                     .segment()
                     .expect("no segment")
@@ -108,132 +565,1473 @@ pub(crate) fn setup(appsink: &gst_app::AppSink, name: &str, path: &Path) {
                     .to_running_time(first.pts().unwrap())
                     .expect("can't get running time");
 
-                if state.start_time.is_none() {
-                    state.start_time = Some(pts);
+                set_start(&mut state, sink, pts);
+
+                let duration = first.duration().unwrap();
+
+                // The muxer started over with new caps, the following segments need the new
+                // header. The one of the previous segments stays around for them.
+                if let Some(header) = state.pending_header.take() {
+                    switch_header(&mut state, &header).map_err(|err| fail(sink, err))?;
                 }
 
-                if state.start_date_time.is_none() {
-                    let now_utc = Utc::now();
-                    let now_gst = sink.clock().unwrap().time().unwrap();
-                    let pts_clock_time = pts + sink.base_time().unwrap();
+                // Timestamps jumping, e.g. because the source restarted
+                if let Some(next_time) = state.next_time {
+                    let gap = pts.max(next_time) - pts.min(next_time);
+                    if gap > MAX_GAP {
+                        info!("{} jumped by {} in {}", pts, gap, state.path.display());
+                        state.discontinuity = true;
+                    }
+                }
+                state.next_time = Some(pts + duration);
 
-                    let diff = now_gst.checked_sub(pts_clock_time).unwrap();
-                    let pts_utc = now_utc.checked_sub_signed(Duration::nanoseconds(diff.nseconds() as i64)).unwrap();
+                if state.settings.part_duration.is_some() {
+                    add_part(&mut state, &buffer_list, starts_segment, pts, duration);
+                } else {
+                    add_segment(&mut state, &buffer_list, pts, duration)
+                        .map_err(|err| fail(sink, err))?;
+                }
 
-                    state.start_date_time = Some(pts_utc);
+                update_manifest(&mut state);
+
+                let iframe_peak = std::mem::take(&mut state.iframe_peak_rose)
+                    .then_some(state.iframe_peak);
+                drop(state);
+                // Not under the lock, the master playlist is written along with reloads
+                if let (Some(peak), Some(on_iframe_peak)) = (iframe_peak, &on_iframe_peak) {
+                    on_iframe_peak(peak);
                 }
 
-                let duration = first.duration().unwrap();
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .eos(move |_sink| {
+                let mut state = eos_state.lock().unwrap();
+                if state.generation != generation {
+                    return;
+                }
 
-                let mut file = std::fs::File::create(&path).expect("failed to open fragment");
-                for buffer in &*buffer_list {
-                    use std::io::prelude::*;
+                state.metadata.finish(&state.path);
 
-                    let map = buffer.map_readable().unwrap();
-                    file.write_all(&map).expect("failed to write fragment");
+                // The muxer already pushed its last fragment and the updated header, all
+                // that's left is to mark the playlist as finished.
+                if let Some(header) = state.pending_header.take() {
+                    if let Err(err) = write_header(&mut state, &header) {
+                        let path = state.path.display();
+                        warn!("failed to write the final header of {}: {:#}", path, err);
+                    }
+                }
+                state.ended = true;
+                finish_segment(&mut state);
+                if state.segments.is_empty() {
+                    return;
                 }
 
-                let date_time = state
-                    .start_date_time
-                    .unwrap()
-                    .checked_add_signed(Duration::nanoseconds(
-                        pts.opt_checked_sub(state.start_time).unwrap().unwrap().nseconds() as i64,
-                    ))
-                    .unwrap();
+                info!("stream ended, finishing {}", state.path.display());
+                update_manifest(&mut state);
+            })
+            .build(),
+    );
+}
 
-                info!("wrote segment: {}", path.display());
+/// Writes the subtitle segments of `stream` for the raw video `appsink` receives, to the TS
+/// directory of the output for the MPEG-TS renditions if `ts`.
+///
+/// The segments are cut at every multiple of the segment duration from the first frame on,
+/// which is where the encoders of the video renditions put their keyframes.
+pub(crate) fn setup_subtitles(
+    appsink: &gst_app::AppSink,
+    stream: &subtitles::SubtitleStream,
+    output: &Output,
+    settings: &Settings,
+    ts: bool,
+) {
+    // Subtitle segments are small enough to always get a file of their own
+    let settings = Settings {
+        single_file: false,
+        ..*settings
+    };
+    let mut state = new_state(&stream.name, output, &settings);
+    if stream.format == subtitles::Format::Webvtt {
+        state.init = String::new();
+        state.webvtt = true;
+    }
+    if ts {
+        state.path = output.ts_path().join(&stream.name);
+        state.ts = true;
+    }
+    let stream = stream.clone();
+    let (state, generation) = claim_state(output, state);
+    {
+        // Taken over from a previous rendition, whose header might be of another language
+        let mut state = state.lock().unwrap();
+        if state.start_time.is_some() && !state.webvtt {
+            state.pending_header = Some(subtitles::imsc1_header(&stream.lang));
+        }
+    }
+    let eos_state = state.clone();
 
-                state.segments.push_back(Segment {
-                    duration,
-                    path: basename.to_string(),
-                    date_time,
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let mut state = state.lock().unwrap();
+                if state.generation != generation {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
+
+                let buffer = sample.buffer().expect("no buffer");
+                let segment = sample
+                    .segment()
+                    .expect("no segment")
+                    .downcast_ref::<gst::ClockTime>()
+                    .expect("no time segment");
+                let Some(pts) = buffer.pts().and_then(|pts| segment.to_running_time(pts)) else {
+                    return Ok(gst::FlowSuccess::Ok);
+                };
+
+                if state.start_time.is_none() {
+                    std::fs::create_dir_all(&state.path).expect("failed to create directory");
+                    if !state.webvtt {
+                        write_header(&mut state, &subtitles::imsc1_header(&stream.lang))
+                            .map_err(|err| fail(sink, err))?;
+                    }
+                }
+                set_start(&mut state, sink, pts);
+                if let Some(header) = state.pending_header.take() {
+                    switch_header(&mut state, &header).map_err(|err| fail(sink, err))?;
+                }
+
+                // The first segment starts at the boundary the frame is closest to
+                let segment_duration = state.settings.segment_duration;
+                let mut time = state.next_time.unwrap_or_else(|| {
+                    state.start_time.unwrap() + segment_duration * state.segment_index as u64
                 });
+                if pts < time + segment_duration {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
+
+                // Every segment the frame is past, in case the pipeline stalled
+                while pts >= time + segment_duration {
+                    add_subtitle_segment(&mut state, &stream, time, segment_duration);
+                    time += segment_duration;
+                }
+                state.next_time = Some(time);
 
                 update_manifest(&mut state);
 
                 Ok(gst::FlowSuccess::Ok)
             })
             .eos(move |_sink| {
-                unreachable!();
+                let mut state = eos_state.lock().unwrap();
+                if state.generation != generation {
+                    return;
+                }
+
+                // The cues of the last partial segment are left out
+                state.ended = true;
+                if state.segments.is_empty() {
+                    return;
+                }
+
+                info!("stream ended, finishing {}", state.path.display());
+                update_manifest(&mut state);
             })
             .build(),
     );
 }
 
+/// Writes the MPEG-TS segments of the `mpegtsmux` `appsink` receives from to the TS directory
+/// of the output.
+///
+/// The muxer writes one continuous stream. Segments are cut at the first keyframe from every
+/// multiple of the segment duration on, or at any frame for `video` false, and each starts
+/// with the PAT and PMT of the stream header. TS renditions have no parts and carry no timed
+/// metadata.
+pub(crate) fn setup_ts(
+    appsink: &gst_app::AppSink,
+    name: &str,
+    output: &Output,
+    settings: &Settings,
+    video: bool,
+) {
+    let settings = Settings {
+        part_duration: None,
+        ..*settings
+    };
+    let mut state = new_state(name, output, &settings);
+    state.path = output.ts_path().join(name);
+    state.init = String::new();
+    state.ts = true;
+    let (state, generation) = claim_state(output, state);
+    let eos_state = state.clone();
+
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let mut state = state.lock().unwrap();
+                if state.generation != generation {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
+
+                let buffer = sample.buffer().expect("no buffer");
+                let segment = sample
+                    .segment()
+                    .expect("no segment")
+                    .downcast_ref::<gst::ClockTime>()
+                    .expect("no time segment");
+                let pts = buffer.pts().and_then(|pts| segment.to_running_time(pts));
+                let keyframe = !video || !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+                if let (Some(pts), true) = (pts, keyframe) {
+                    let starts_segment = match state.open_ts_segment {
+                        None => true,
+                        // Backwards as well, e.g. because the source restarted
+                        Some((ref open, _)) => {
+                            let end = ts_segment_end(
+                                state.start_time.unwrap(),
+                                settings.segment_duration,
+                                open.time,
+                            );
+                            pts < open.time || pts + TS_TOLERANCE >= end
+                        }
+                    };
+
+                    if starts_segment {
+                        start_ts_segment(&mut state, sink, pts, sample.caps())
+                            .map_err(|err| fail(sink, err))?;
+                    }
+                }
+
+                // Nothing before the first keyframe can be decoded
+                let Some((_, ref mut data)) = state.open_ts_segment else {
+                    return Ok(gst::FlowSuccess::Ok);
+                };
+                data.extend_from_slice(&buffer.map_readable().unwrap());
+                if pts.is_some() {
+                    state.next_time = pts;
+                }
+
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .eos(move |_sink| {
+                let mut state = eos_state.lock().unwrap();
+                if state.generation != generation {
+                    return;
+                }
+
+                // Up to the newest frame, whose duration the packets don't tell
+                state.ended = true;
+                if let Some(newest) = state.next_time {
+                    if let Err(err) = finish_ts_segment(&mut state, newest) {
+                        warn!("failed to finish {}: {:#}", state.path.display(), err);
+                    }
+                }
+                if state.segments.is_empty() {
+                    return;
+                }
+
+                info!("stream ended, finishing {}", state.path.display());
+                update_manifest(&mut state);
+            })
+            .build(),
+    );
+}
+
+// Finishes the TS segment in progress where the one starting at `pts` begins, unless the
+// timestamps jumped, and starts the latter with the stream header of `caps`
+fn start_ts_segment(
+    state: &mut StreamState,
+    sink: &gst_app::AppSink,
+    pts: gst::ClockTime,
+    caps: Option<&gst::CapsRef>,
+) -> Result<(), Error> {
+    if state.start_time.is_none() {
+        std::fs::create_dir_all(&state.path).expect("failed to create directory");
+    }
+    set_start(state, sink, pts);
+
+    // The finished segment ends with its newest frame when the timestamps jumped
+    let mut end = pts;
+    if let Some(newest) = state.next_time {
+        let gap = pts.max(newest) - pts.min(newest);
+        if gap > MAX_GAP {
+            info!("{} jumped by {} in {}", pts, gap, state.path.display());
+            state.discontinuity = true;
+            end = newest;
+        }
+    }
+    let finished = state.open_ts_segment.is_some();
+    finish_ts_segment(state, end)?;
+
+    // Every segment has to be decodable on its own
+    let mut data = vec![];
+    let streamheader = caps
+        .and_then(|caps| caps.structure(0))
+        .and_then(|structure| structure.get::<gst::Array>("streamheader").ok());
+    for value in streamheader.iter().flat_map(|headers| headers.iter()) {
+        if let Ok(header) = value.get::<gst::Buffer>() {
+            data.extend_from_slice(&header.map_readable().unwrap());
+        }
+    }
+
+    let segment = new_segment(state, pts);
+    state.open_ts_segment = Some((segment, data));
+
+    if finished {
+        update_manifest(state);
+    }
+
+    Ok(())
+}
+
+fn finish_ts_segment(state: &mut StreamState, end: gst::ClockTime) -> Result<(), Error> {
+    let Some((mut segment, data)) = state.open_ts_segment.take() else {
+        return Ok(());
+    };
+    segment.duration = end.saturating_sub(segment.time);
+
+    let data = match segment.key {
+        Some(ref key) => encrypt(state, key, &data)?,
+        None => data,
+    };
+    write_segment(state, &mut segment, &data);
+
+    state.max_segment_duration = state.max_segment_duration.max(segment.duration);
+    state.segments.push_back(segment);
+
+    Ok(())
+}
+
+// Running time the TS segment starting at `time` ends at, the next multiple of the segment
+// duration from the first segment at `start` on
+fn ts_segment_end(
+    start: gst::ClockTime,
+    segment_duration: gst::ClockTime,
+    time: gst::ClockTime,
+) -> gst::ClockTime {
+    let duration = segment_duration.nseconds();
+    // Segments start a little before or after their boundary
+    let index = (time.saturating_sub(start).nseconds() + duration / 2) / duration;
+
+    start + gst::ClockTime::from_nseconds((index + 1) * duration)
+}
+
+// Dates the segments from the origin of the output, anchored by whichever rendition had its
+// first fragment first. Renditions set up later number their segments as if they had been
+// there from the origin on, so the numbers match across renditions.
+fn set_start(state: &mut StreamState, sink: &gst_app::AppSink, pts: gst::ClockTime) {
+    if state.start_time.is_some() {
+        return;
+    }
+
+    let (origin, origin_date_time) = state.origin.anchor(sink.upcast_ref(), pts);
+    state.start_time = Some(origin);
+    state.start_date_time = Some(origin_date_time);
+
+    let duration = state.settings.segment_duration.nseconds();
+    let index = (pts.saturating_sub(origin).nseconds() + duration / 2) / duration;
+    state.segment_index = index as u32;
+    state.media_sequence = index;
+    state.iframe_sequence.media = index;
+}
+
+// The buffers of a fragment, with the `emsg` boxes of the metadata events within it in
+// front of the `moof`. A leading `styp` stays first.
+fn fragment_data(
+    state: &StreamState,
+    buffer_list: &gst::BufferListRef,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) -> Vec<u8> {
+    let mut data = vec![];
+    for buffer in buffer_list {
+        data.extend_from_slice(&buffer.map_readable().unwrap());
+    }
+
+    let events = state.metadata.fragment(&state.path, time, duration);
+    if !events.is_empty() {
+        let pos = if data.get(4..8) == Some(b"styp") {
+            u32::from_be_bytes(data[..4].try_into().unwrap()) as usize
+        } else {
+            0
+        };
+        data.splice(pos..pos, events);
+    }
+
+    data
+}
+
+// A buffer list holding a whole segment
+fn add_segment(
+    state: &mut StreamState,
+    buffer_list: &gst::BufferListRef,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) -> Result<(), Error> {
+    let mut segment = new_segment(state, time);
+    segment.duration = duration;
+
+    let data = fragment_data(state, buffer_list, time, duration);
+    let data = match segment.key {
+        Some(ref key) => encrypt(state, key, &data)?,
+        None => data,
+    };
+    let offset = write_segment(state, &mut segment, &data);
+    if state.iframes {
+        segment.iframe = keyframe_range(&state.path, &data, offset);
+        update_iframe_peak(state, &segment);
+    }
+
+    state.max_segment_duration = state.max_segment_duration.max(duration);
+    state.segments.push_back(segment);
+
+    Ok(())
+}
+
+fn add_subtitle_segment(
+    state: &mut StreamState,
+    stream: &subtitles::SubtitleStream,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) {
+    let mut segment = new_segment(state, time);
+    segment.duration = duration;
+
+    let data = stream.render_segment(segment.number, segment.date_time, time, duration, state.ts);
+    let path = state.path.join(&segment.path);
+    std::fs::write(&path, data).expect("failed to write segment");
+
+    info!("wrote segment: {}", path.display());
+
+    state.max_segment_duration = state.max_segment_duration.max(duration);
+    state.segments.push_back(segment);
+}
+
+// A buffer list holding one chunk of a segment. Each chunk is written to its own part file,
+// and appended to the file of the segment it belongs to.
+fn add_part(
+    state: &mut StreamState,
+    buffer_list: &gst::BufferListRef,
+    starts_segment: bool,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) {
+    if starts_segment {
+        // The previous segment is complete now
+        finish_segment(state);
+
+        let segment = new_segment(state, time);
+        let file = std::fs::File::create(state.path.join(&segment.path))
+            .expect("failed to open fragment");
+
+        state.open_segment = Some(OpenSegment {
+            segment,
+            file,
+            written: 0,
+        });
+    }
+
+    // Parts are numbered independently of segments, so the preload hint for the next part
+    // is right no matter if it starts a new segment or not.
+    let basename = format!("part_{}.fmp4", state.part_index);
+    state.part_index += 1;
+
+    let mut path = state.path.clone();
+    path.push(&basename);
+    let data = fragment_data(state, buffer_list, time, duration);
+    std::fs::write(&path, &data).expect("failed to write part");
+
+    let iframes = state.iframes;
+    let open_segment = state.open_segment.as_mut().expect("part without segment");
+    if iframes && starts_segment {
+        open_segment.segment.iframe = keyframe_range(&state.path, &data, open_segment.written);
+    }
+    open_segment.file.write_all(&data).expect("failed to write fragment");
+    open_segment.written += data.len() as u64;
+    // Lets the server pass the new chunk on to clients downloading the segment already
+    state
+        .progress
+        .write_file(&state.path.join(&open_segment.segment.path), open_segment.written);
+    open_segment.segment.duration += duration;
+    open_segment.segment.parts.push(Part {
+        duration,
+        path: basename,
+        independent: starts_segment,
+    });
+
+    info!("wrote part: {}", path.display());
+
+    state.max_part_duration = state.max_part_duration.max(duration);
+}
+
+// Writes the whole `data` of `segment` to its own file, or appends it to the file of the
+// rendition in single file mode. Returns the offset the data starts at.
+fn write_segment(state: &mut StreamState, segment: &mut Segment, data: &[u8]) -> u64 {
+    let path = state.path.join(&segment.path);
+
+    if !state.settings.single_file {
+        std::fs::write(&path, data).expect("failed to write segment");
+        info!("wrote segment: {}", path.display());
+        return 0;
+    }
+
+    if state.single_file.as_ref().map_or(true, |(name, ..)| *name != segment.path) {
+        // Continued if listed segments are in it already, e.g. after the window changed.
+        // Anything else is left over from a previous run, which numbered from 0 as well.
+        let listed = state.segments.iter().any(|listed| listed.path == segment.path);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(listed)
+            .truncate(!listed)
+            .open(&path)
+            .expect("failed to open file");
+        let size = file.metadata().expect("failed to query file").len();
+        state.single_file = Some((segment.path.clone(), file, size));
+    }
+
+    let (_, file, written) = state.single_file.as_mut().unwrap();
+    let offset = *written;
+    file.write_all(data).expect("failed to write segment");
+    *written += data.len() as u64;
+    segment.byte_range = Some((offset, data.len() as u64));
+
+    info!("wrote segment {} to {} at {}", segment.number, path.display(), offset);
+
+    offset
+}
+
+// Byte range of the keyframe starting the fragment `data`, written at `offset` of its
+// segment
+fn keyframe_range(path: &Path, data: &[u8], offset: u64) -> Option<(u64, u64)> {
+    match mp4::first_sample_range(data) {
+        Ok((start, length)) => Some((offset + start as u64, length as u64)),
+        Err(err) => {
+            warn!("no keyframe for the I-frame playlist of {}: {:#}", path.display(), err);
+            None
+        }
+    }
+}
+
+// Players fetch the keyframe of `segment` within its duration at most, as the previous
+// keyframe also stands for any segments without keyframe that follow
+fn update_iframe_peak(state: &mut StreamState, segment: &Segment) {
+    let Some((_, length)) = segment.iframe else {
+        return;
+    };
+    if segment.duration.is_zero() {
+        return;
+    }
+
+    let bitrate = length * 8 * gst::ClockTime::SECOND.nseconds() / segment.duration.nseconds();
+    if bitrate > state.iframe_peak {
+        state.iframe_peak = bitrate;
+        state.iframe_peak_rose = true;
+    }
+}
+
+// The next segment starting at running time `time`, without duration yet
+fn new_segment(state: &mut StreamState, time: gst::ClockTime) -> Segment {
+    let number = state.segment_index;
+    state.segment_index += 1;
+
+    if let Some(encryption) = state.settings.encryption {
+        // The first key was already needed for the header
+        if number > 0 && number % encryption.key_rotation == 0 {
+            state.key = Some(state.keys.generate());
+        }
+    }
+
+    let date_time = state
+        .start_date_time
+        .unwrap()
+        .checked_add_signed(Duration::nanoseconds(
+            time.nseconds() as i64 - state.start_time.unwrap().nseconds() as i64,
+        ))
+        .unwrap();
+
+    Segment {
+        number,
+        date_time,
+        time,
+        duration: gst::ClockTime::ZERO,
+        path: if state.settings.single_file {
+            let file = number / segments_per_file(&state.settings);
+            format!("{}_{}.{}", SINGLE_FILE, file, segment_extension(state))
+        } else {
+            format!("segment_{}.{}", number, segment_extension(state))
+        },
+        init: state.init.clone(),
+        discontinuity: std::mem::take(&mut state.discontinuity),
+        key: state.key.clone(),
+        default_kid: state.header_key.as_ref().map(|key| key.kid),
+        iframe: None,
+        byte_range: None,
+        parts: vec![],
+    }
+}
+
+// Segments of a file in single file mode, as many as the playlist holds so only the files of
+// the last two windows are around. Playlists that never drop a segment keep a single file.
+fn segments_per_file(settings: &Settings) -> u32 {
+    let segments = match settings.mode {
+        PlaylistMode::Live => settings.window_size as u64,
+        PlaylistMode::Dvr => settings.dvr_window.nseconds() / settings.segment_duration.nseconds(),
+        PlaylistMode::Event | PlaylistMode::LiveToVod => u64::MAX,
+    };
+    segments.clamp(1, u32::MAX as u64) as u32
+}
+
+fn segment_extension(state: &StreamState) -> &'static str {
+    if state.webvtt {
+        "vtt"
+    } else if state.ts {
+        "ts"
+    } else {
+        "fmp4"
+    }
+}
+
+// The following segments refer to `header`, the one of the previous segments stays around for
+// them
+fn switch_header(state: &mut StreamState, header: &[u8]) -> Result<(), Error> {
+    state.init_index += 1;
+    state.init = format!("init_{}.mp4", state.init_index);
+    state.header_key = None;
+    write_header(state, header)?;
+    state.discontinuity = true;
+
+    Ok(())
+}
+
+fn write_header(state: &mut StreamState, header: &[u8]) -> Result<(), Error> {
+    let path = state.path.join(&state.init);
+
+    // Sample encryption needs the protected sample entries, the header stays in clear
+    // otherwise
+    let protected;
+    let header = match state.settings.encryption {
+        Some(encryption) if encryption.method != encryption::Method::Aes128 => {
+            if state.header_key.is_none() {
+                state.header_key = state.key.clone();
+            }
+            let key = state.header_key.as_ref().expect("encrypting without key");
+
+            let (data, track) = encryption::protect_header(key, encryption.method, header)
+                .context("failed to protect header")?;
+            state.track = Some(track);
+            protected = data;
+            &protected
+        }
+        _ => header,
+    };
+
+    info!("writing header to {}", path.display());
+    // Rewritten with the final header at the end of the stream
+    utils::write_atomically(&path, |file| file.write_all(header)).expect("failed to write header");
+
+    Ok(())
+}
+
+// Encrypts a whole segment with `key`
+fn encrypt(state: &StreamState, key: &Key, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let encryption = state.settings.encryption.expect("encrypting without settings");
+
+    match encryption.method {
+        encryption::Method::Aes128 => Ok(encryption::encrypt_segment(key, data)),
+        method => {
+            let track = state.track.expect("fragment before header");
+            encryption::protect_fragment(key, method, track, data)
+                .context("failed to protect fragment")
+        }
+    }
+}
+
+// Fails the branch of `sink` with `err`, nothing it outputs can be packaged anymore
+fn fail(sink: &gst_app::AppSink, err: Error) -> gst::FlowError {
+    gst::element_error!(sink, gst::StreamError::Encrypt, ("{}", err), ["{:?}", err]);
+    gst::FlowError::Error
+}
+
+fn finish_segment(state: &mut StreamState) {
+    let Some(open_segment) = state.open_segment.take() else {
+        return;
+    };
+
+    let path = state.path.join(&open_segment.segment.path);
+    info!("wrote segment: {}", path.display());
+    state.progress.finish_file(&path);
+
+    update_iframe_peak(state, &open_segment.segment);
+    state.max_segment_duration = state.max_segment_duration.max(open_segment.segment.duration);
+    state.segments.push_back(open_segment.segment);
+}
+
 fn update_manifest(state: &mut StreamState) {
+    trim_segments(state);
+    let state = &*state;
+
+    if state.ended && state.settings.mode == PlaylistMode::LiveToVod {
+        let segments = state.archived_segments.iter().chain(state.segments.iter());
+        update_mpd(state, segments.clone());
+        let sequence = IFrameSequence::default();
+        write_iframe_playlist(state, segments.clone(), sequence, Some(MediaPlaylistType::Vod));
+        write_playlist(state, segments, 0, 0, Some(MediaPlaylistType::Vod));
+        return;
+    }
+
+    let playlist_type = match state.settings.mode {
+        PlaylistMode::Event => Some(MediaPlaylistType::Event),
+        _ => None,
+    };
+    update_mpd(state, state.segments.iter());
+    write_iframe_playlist(
+        state,
+        state.segments.iter(),
+        state.iframe_sequence,
+        playlist_type.clone(),
+    );
+    write_playlist(
+        state,
+        state.segments.iter(),
+        state.media_sequence,
+        state.discontinuity_sequence,
+        playlist_type,
+    );
+}
+
+// Hands the same segments the media playlist lists to the DASH manifest
+fn update_mpd<'a>(state: &StreamState, segments: impl Iterator<Item = &'a Segment>) {
+    if state.webvtt || state.ts {
+        return;
+    }
+
+    // The MPD has no way to point players to keys outside of a DRM system
+    if let Some(encryption) = state.settings.encryption {
+        if !encryption.method.is_common() {
+            return;
+        }
+    }
+
+    let segments = segments
+        .map(|segment| dash::Segment {
+            number: segment.number as u64,
+            date_time: segment.date_time,
+            time: segment.time,
+            duration: segment.duration,
+            init: segment.init.clone(),
+            discontinuity: segment.discontinuity,
+            default_kid: segment.default_kid,
+            media_range: segment
+                .byte_range
+                .map(|(offset, length)| (segment.path.clone(), offset, length)),
+        })
+        .collect::<Vec<_>>();
+    if segments.is_empty() {
+        return;
+    }
+
+    let timeline = dash::Timeline {
+        start_date_time: state.start_date_time.unwrap(),
+        segments,
+        ended: state.ended,
+    };
+    state.mpd.lock().unwrap().update(&state.name, timeline);
+}
+
+fn write_playlist<'a>(
+    state: &StreamState,
+    segments: impl Iterator<Item = &'a Segment>,
+    media_sequence: u64,
+    discontinuity_sequence: u64,
+    playlist_type: Option<MediaPlaylistType>,
+) {
     // Now write the manifest
     let mut path = state.path.clone();
     path.push("manifest.m3u8");
 
-    trim_segments(state);
+    let segments = segments.collect::<Vec<_>>();
+    let low_latency = state.settings.part_duration.is_some() && !state.ended;
+    // Low-Latency playlists are listed before the first segment is complete
+    let target_duration = if low_latency {
+        target_duration(state.max_segment_duration.max(state.settings.segment_duration))
+    } else {
+        target_duration(state.max_segment_duration)
+    };
+    let part_target = part_target(state);
+
+    // Parts are only listed for the segments of the last three target durations
+    let first_with_parts = if low_latency {
+        let open_duration = state
+            .open_segment
+            .as_ref()
+            .map_or(gst::ClockTime::ZERO, |open| open.segment.duration);
+        let mut covered = utils::clock_time_to_seconds(open_duration);
+        let recent = segments
+            .iter()
+            .rev()
+            .take_while(|segment| {
+                let keep = covered < 3.0 * target_duration as f64;
+                covered += utils::clock_time_to_seconds(segment.duration);
+                keep
+            })
+            .count();
+        segments.len() - recent
+    } else {
+        segments.len()
+    };
 
     let playlist = MediaPlaylist {
-        version: Some(7),
-        target_duration: 2.0,
-        media_sequence: state.media_sequence,
-        segments: state
-            .segments
+        version: Some(playlist_version(state)),
+        target_duration,
+        media_sequence,
+        discontinuity_sequence,
+        segments: segments
             .iter()
             .enumerate()
-            .map(|(idx, segment)| MediaSegment {
-                uri: segment.path.to_string(),
-                duration: (segment.duration.nseconds() as f64 / gst::ClockTime::SECOND.nseconds() as f64) as f32,
-                map: if idx == 0 {
+            .map(|(idx, segment)| {
+                // Repeated whenever the header changed
+                let map = if state.webvtt || state.ts {
+                    None
+                } else if idx == 0 || segments[idx - 1].init != segment.init {
                     Some(m3u8_rs::Map {
-                        uri: "init.mp4".into(),
+                        uri: segment.init.clone(),
                         ..Default::default()
                     })
                 } else {
                     None
-                },
-                program_date_time: if idx == 0 {
-                    Some(segment.date_time.into())
-                } else {
-                    None
-                },
-                ..Default::default()
+                };
+
+                // The key tag goes after EXT-X-MAP, which m3u8-rs only allows for unknown tags.
+                // A key tag before it would apply to the header, which is never encrypted.
+                let mut unknown_tags = vec![];
+                let key_changed = idx == 0 || segments[idx - 1].key != segment.key;
+                if let Some(ref key) = segment.key {
+                    if key_changed || map.is_some() {
+                        unknown_tags.push(key_tag(state, key));
+                    }
+                }
+
+                unknown_tags.extend(splice_tags(state, segment));
+                unknown_tags.extend(interstitial_tags(state, segment));
+
+                // Written right before the segment they are part of
+                if idx >= first_with_parts {
+                    unknown_tags.extend(segment.parts.iter().map(part_tag));
+                }
+
+                MediaSegment {
+                    uri: segment.path.to_string(),
+                    duration: (segment.duration.nseconds() as f64 / gst::ClockTime::SECOND.nseconds() as f64) as f32,
+                    // Always with the offset, the previous segment might have left the playlist
+                    byte_range: segment.byte_range.map(|(offset, length)| m3u8_rs::ByteRange {
+                        length,
+                        offset: Some(offset),
+                    }),
+                    // The key in effect would otherwise apply to the new header as well
+                    key: if idx > 0 && map.is_some() && segment.key.is_some() {
+                        Some(m3u8_rs::Key {
+                            method: m3u8_rs::KeyMethod::None,
+                            ..Default::default()
+                        })
+                    } else {
+                        None
+                    },
+                    map,
+                    discontinuity: segment.discontinuity,
+                    program_date_time: if idx == 0 || segment.discontinuity {
+                        Some(segment.date_time.into())
+                    } else {
+                        None
+                    },
+                    unknown_tags,
+                    ..Default::default()
+                }
             })
             .collect(),
-        end_list: false,
-        playlist_type: None,
+        end_list: state.ended,
+        playlist_type,
         i_frames_only: false,
         start: None,
         independent_segments: true,
+        unknown_tags: if low_latency {
+            vec![
+                m3u8_rs::ExtTag {
+                    tag: "X-SERVER-CONTROL".into(),
+                    rest: Some(format!(
+                        "CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+                        3.0 * part_target
+                    )),
+                },
+                m3u8_rs::ExtTag {
+                    tag: "X-PART-INF".into(),
+                    rest: Some(format!("PART-TARGET={:.3}", part_target)),
+                },
+            ]
+        } else {
+            vec![]
+        },
         ..Default::default()
     };
 
+    // The parts of the segment in progress and the hint for the next part come after all
+    // complete segments, m3u8-rs has no way to express that.
+    let trailer = if low_latency {
+        let mut trailer = vec![];
+        if let Some(ref open) = state.open_segment {
+            let previous = segments.last();
+            if open.segment.discontinuity {
+                trailer.push("#EXT-X-DISCONTINUITY".to_string());
+            }
+            if previous.map_or(true, |previous| previous.init != open.segment.init) {
+                trailer.push(format!("#EXT-X-MAP:URI=\"{}\"", open.segment.init));
+            }
+            trailer.extend(splice_tags(state, &open.segment).iter().map(ToString::to_string));
+            trailer.extend(
+                interstitial_tags(state, &open.segment).iter().map(ToString::to_string),
+            );
+            trailer.extend(open.segment.parts.iter().map(|part| part_tag(part).to_string()));
+        }
+        trailer.push(format!(
+            "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part_{}.fmp4\"",
+            state.part_index
+        ));
+        trailer
+    } else {
+        vec![]
+    };
+
     info!("writing manifest to {}", path.display());
-    let mut file = std::fs::File::create(path).unwrap();
-    playlist.write_to(&mut file).expect("Failed to write media playlist");
+    utils::write_atomically(&path, |file| {
+        playlist.write_to(file)?;
+        for line in &trailer {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    })
+    .expect("Failed to write media playlist");
+
+    if low_latency {
+        state.progress.update(
+            &path,
+            newest_part(state),
+            std::time::Duration::from_secs(target_duration as u64),
+        );
+    }
 }
 
-fn trim_segments(state: &mut StreamState) {
-    // Arbitrary 5 segments window
-    while state.segments.len() > 5 {
-        let segment = state.segments.pop_front().unwrap();
+// Trick play playlist listing the keyframe every segment starts with, as a byte range of the
+// very same segment files. Segments without keyframe are left out, the keyframe before stands
+// for them unless there's a discontinuity in between.
+fn write_iframe_playlist<'a>(
+    state: &StreamState,
+    segments: impl Iterator<Item = &'a Segment>,
+    sequence: IFrameSequence,
+    playlist_type: Option<MediaPlaylistType>,
+) {
+    if !state.iframes {
+        return;
+    }
+
+    let mut entries: Vec<(gst::ClockTime, MediaSegment)> = vec![];
+    let mut previous: Option<&Segment> = None;
+    let mut discontinuity = sequence.carried;
+    for segment in segments {
+        discontinuity |= segment.discontinuity;
+        let Some((offset, length)) = segment.iframe else {
+            match entries.last_mut() {
+                Some((duration, entry)) if !discontinuity => {
+                    *duration += segment.duration;
+                    entry.duration = utils::clock_time_to_seconds(*duration) as f32;
+                }
+                _ => (),
+            }
+            continue;
+        };
+
+        let map = if previous.map_or(true, |previous| previous.init != segment.init) {
+            Some(m3u8_rs::Map {
+                uri: segment.init.clone(),
+                ..Default::default()
+            })
+        } else {
+            None
+        };
 
-        state.media_sequence += 1;
+        let mut unknown_tags = vec![];
+        if let Some(ref key) = segment.key {
+            if previous.map_or(true, |previous| previous.key != segment.key) || map.is_some() {
+                unknown_tags.push(key_tag(state, key));
+            }
+        }
 
-        state.trimmed_segments.push_back(UnreffedSegment {
-            // HLS spec mandates that segments are removed from the filesystem no sooner
-            // than the duration of the longest playlist + duration of the segment.
-            // This is 15 seconds (12.5 + 2.5) in our case, we use 20 seconds to be on the
-            // safe side
-            removal_time: segment.date_time.checked_add_signed(Duration::seconds(20)).unwrap(),
-            path: segment.path.clone(),
-        });
+        let entry = MediaSegment {
+            uri: segment.path.to_string(),
+            // Until the next keyframe, which starts the next segment
+            duration: utils::clock_time_to_seconds(segment.duration) as f32,
+            byte_range: Some(m3u8_rs::ByteRange {
+                length,
+                offset: Some(offset),
+            }),
+            map,
+            discontinuity,
+            program_date_time: if previous.is_none() || discontinuity {
+                Some(segment.date_time.into())
+            } else {
+                None
+            },
+            unknown_tags,
+            ..Default::default()
+        };
+        entries.push((segment.duration, entry));
+        previous = Some(segment);
+        discontinuity = false;
+    }
+
+    if entries.is_empty() {
+        return;
+    }
+
+    let max_duration = entries.iter().map(|(duration, _)| *duration).max().unwrap();
+    let playlist = MediaPlaylist {
+        version: Some(7),
+        target_duration: target_duration(state.max_segment_duration.max(max_duration)),
+        media_sequence: sequence.media,
+        discontinuity_sequence: sequence.discontinuity,
+        segments: entries.into_iter().map(|(_, entry)| entry).collect(),
+        end_list: state.ended,
+        playlist_type,
+        i_frames_only: true,
+        independent_segments: true,
+        ..Default::default()
+    };
+
+    let path = state.path.join("iframes.m3u8");
+    utils::write_atomically(&path, |file| playlist.write_to(file))
+        .expect("Failed to write I-frame playlist");
+}
+
+// Version 7 covers EXT-X-MAP and everything else of the fMP4 playlists. TS segments only need
+// the decimal EXTINF of version 3, which legacy players still understand, or version 4 for
+// EXT-X-BYTERANGE.
+fn playlist_version(state: &StreamState) -> usize {
+    match (state.ts, state.settings.single_file) {
+        (true, false) => 3,
+        (true, true) => 4,
+        (false, _) => 7,
+    }
+}
+
+fn key_tag(state: &StreamState, key: &Key) -> m3u8_rs::ExtTag {
+    let method = state.settings.encryption.map(|encryption| encryption.method);
+    let rest = match method {
+        // Clear Key through the EME, which gets the key ID out of the `pssh` box
+        Some(method) if method.is_common() => {
+            let mut pssh = vec![];
+            encryption::pssh(&[key.kid]).write(&mut pssh);
+
+            format!(
+                "METHOD={},URI=\"data:text/plain;base64,{}\",KEYFORMAT=\"{}\",KEYFORMATVERSIONS=\"1\"",
+                if method == encryption::Method::Cenc { "SAMPLE-AES-CTR" } else { "SAMPLE-AES" },
+                STANDARD.encode(pssh),
+                encryption::CLEARKEY_URN,
+            )
+        }
+        _ => format!(
+            "METHOD={},URI=\"{}\",IV=0x{}",
+            if method == Some(encryption::Method::SampleAes) { "SAMPLE-AES" } else { "AES-128" },
+            key.uri(),
+            encryption::hex(&key.iv),
+        ),
+    };
+
+    m3u8_rs::ExtTag {
+        tag: "X-KEY".into(),
+        rest: Some(rest),
+    }
+}
+
+// `EXT-X-DATERANGE` and the `EXT-X-CUE-OUT`/`EXT-X-CUE-IN` tags many ad insertion servers
+// still expect, for the splice points `segment` starts at, or `EXT-X-CUE-OUT-CONT` within a
+// break. Both splice points of a break share the ID and start date of their date range.
+fn splice_tags(state: &StreamState, segment: &Segment) -> Vec<m3u8_rs::ExtTag> {
+    let (Some(start_time), Some(start_date_time)) = (state.start_time, state.start_date_time)
+    else {
+        return vec![];
+    };
+
+    let mut tags = vec![];
+    for splice in &state.splices {
+        let out = start_time + splice.out;
+        let back_in = start_time + splice.back_in();
+        let start_date = (start_date_time + Duration::nanoseconds(splice.out.nseconds() as i64))
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        let duration = utils::clock_time_to_seconds(splice.duration);
+
+        if starts_at(segment, out) {
+            tags.push(m3u8_rs::ExtTag {
+                tag: "X-DATERANGE".into(),
+                rest: Some(format!(
+                    "ID=\"splice-{}\",START-DATE=\"{}\",PLANNED-DURATION={:.3},SCTE35-OUT={}",
+                    splice.id,
+                    start_date,
+                    duration,
+                    scte35::hex(&splice.out_section(out))
+                )),
+            });
+            tags.push(m3u8_rs::ExtTag {
+                tag: "X-CUE-OUT".into(),
+                rest: Some(format!("{:.3}", duration)),
+            });
+        }
+
+        // For players and servers picking up the playlist in the middle of the break
+        if within_break(segment, out, back_in) {
+            tags.push(m3u8_rs::ExtTag {
+                tag: "X-CUE-OUT-CONT".into(),
+                rest: Some(format!(
+                    "ElapsedTime={:.3},Duration={:.3}",
+                    utils::clock_time_to_seconds(segment.time - out),
+                    duration
+                )),
+            });
+        }
+
+        if starts_at(segment, back_in) {
+            tags.push(m3u8_rs::ExtTag {
+                tag: "X-DATERANGE".into(),
+                rest: Some(format!(
+                    "ID=\"splice-{}\",START-DATE=\"{}\",DURATION={:.3},SCTE35-IN={}",
+                    splice.id,
+                    start_date,
+                    duration,
+                    scte35::hex(&splice.in_section(back_in))
+                )),
+            });
+            tags.push(m3u8_rs::ExtTag {
+                tag: "X-CUE-IN".into(),
+                rest: None,
+            });
+        }
+    }
+
+    tags
+}
+
+// Whether `segment` starts at `time`, give or take the few milliseconds audio segments are
+// off the boundaries the splice points are on
+fn starts_at(segment: &Segment, time: gst::ClockTime) -> bool {
+    covers(segment, time + gst::ClockTime::from_mseconds(100))
+}
+
+// Whether `segment` is part of the break from `out` to `back_in` without starting it
+fn within_break(segment: &Segment, out: gst::ClockTime, back_in: gst::ClockTime) -> bool {
+    out < segment.time
+        && segment.time < back_in
+        && !starts_at(segment, out)
+        && !starts_at(segment, back_in)
+}
+
+fn covers(segment: &Segment, time: gst::ClockTime) -> bool {
+    segment.time <= time && time < segment.time + segment.duration
+}
+
+// The `EXT-X-DATERANGE` of every interstitial starting within `segment`. Interstitials aren't
+// moved to segment boundaries, players switch over at the exact start date. It's dated from
+// the origin of the output, so the date ranges of all renditions agree.
+fn interstitial_tags(state: &StreamState, segment: &Segment) -> Vec<m3u8_rs::ExtTag> {
+    let (Some(start_time), Some(start_date_time)) = (state.start_time, state.start_date_time)
+    else {
+        return vec![];
+    };
+
+    state
+        .interstitials
+        .iter()
+        .filter_map(|interstitial| {
+            let start = gst::ClockTime::from_nseconds((interstitial.start * 1e9) as u64);
+            if !covers(segment, start_time + start) {
+                return None;
+            }
+
+            let start_date = start_date_time + Duration::nanoseconds(start.nseconds() as i64);
+            Some(m3u8_rs::ExtTag {
+                tag: "X-DATERANGE".into(),
+                rest: Some(interstitial.attributes(start_date)),
+            })
+        })
+        .collect()
+}
+
+fn part_tag(part: &Part) -> m3u8_rs::ExtTag {
+    m3u8_rs::ExtTag {
+        tag: "X-PART".into(),
+        rest: Some(format!(
+            "DURATION={:.5},URI=\"{}\"{}",
+            utils::clock_time_to_seconds(part.duration),
+            part.path,
+            if part.independent { ",INDEPENDENT=YES" } else { "" },
+        )),
+    }
+}
+
+// EXT-X-PART-INF must not change, but chunks can slightly exceed the configured duration when
+// it isn't a multiple of the frame duration.
+fn part_target(state: &StreamState) -> f64 {
+    let configured = state.settings.part_duration.unwrap_or(gst::ClockTime::ZERO);
+    utils::clock_time_to_seconds(configured.max(state.max_part_duration))
+}
+
+// The Media Sequence Number of a segment is its index, as the media sequence is increased
+// once for every segment leaving the playlist.
+fn newest_part(state: &StreamState) -> Position {
+    match state.open_segment {
+        Some(ref open) => Position {
+            msn: state.segment_index as u64 - 1,
+            part: open.segment.parts.len() as u64 - 1,
+        },
+        None => Position {
+            msn: state.segment_index as u64,
+            part: 0,
+        },
+    }
+}
+
+// EXT-X-TARGETDURATION is an integer that every EXTINF, rounded to the nearest integer, must
+// not exceed. Rounding the longest segment up always satisfies that, and as it is the longest
+// segment written so far the value never decreases over the lifetime of the playlist.
+fn target_duration(max_segment_duration: gst::ClockTime) -> f32 {
+    let second = gst::ClockTime::SECOND.nseconds();
+    let seconds = (max_segment_duration.nseconds() + second - 1) / second;
+
+    seconds.max(1) as f32
+}
+
+fn playlist_duration<'a>(segments: impl IntoIterator<Item = &'a Segment>) -> gst::ClockTime {
+    segments
+        .into_iter()
+        .fold(gst::ClockTime::ZERO, |acc, segment| acc + segment.duration)
+}
+
+// Whether the oldest segment has to leave the playlist
+fn should_trim(state: &StreamState) -> bool {
+    match state.settings.mode {
+        PlaylistMode::Live | PlaylistMode::LiveToVod => {
+            state.segments.len() > state.settings.window_size
+        }
+        PlaylistMode::Dvr => {
+            // Always keep the newest segment, however long it is
+            state.segments.len() > 1
+                && playlist_duration(state.segments.iter().skip(1)) >= state.settings.dvr_window
+        }
+        PlaylistMode::Event => false,
+    }
+}
+
+fn trim_segments(state: &mut StreamState) {
+    // Wall clock time at the end of the newest segment, i.e. "now" for the playlist
+    let Some(newest) = state.segments.back() else {
+        // Nothing but parts of the first segment yet
+        return;
+    };
+    let now = newest.date_time + Duration::nanoseconds(newest.duration.nseconds() as i64);
+
+    while should_trim(state) {
+        unlist_oldest(state, now);
     }
 
     while let Some(segment) = state.trimmed_segments.front() {
-        if segment.removal_time < state.segments.front().unwrap().date_time {
+        if segment.removal_time <= now {
             let segment = state.trimmed_segments.pop_front().unwrap();
 
-            let mut path = state.path.clone();
-            path.push(segment.path);
-            info!("deleting {}", path.display());
-            std::fs::remove_file(path).expect("Failed to remove old segment");
+            for path in std::iter::once(segment.path).chain(segment.parts) {
+                let path = state.path.join(path);
+                info!("deleting {}", path.display());
+                std::fs::remove_file(path).expect("Failed to remove old segment");
+            }
+
+            let in_use = kids_in_use(state);
+            for kid in segment.kids.iter().filter(|kid| !in_use.contains(kid)) {
+                state.keys.remove(kid);
+            }
         } else {
             break;
         }
     }
 }
+
+// IDs of the keys players might still need, for the segments that are listed or not deleted
+// yet and for those to come
+fn kids_in_use(state: &StreamState) -> Vec<[u8; 16]> {
+    let segments = state
+        .archived_segments
+        .iter()
+        .chain(&state.segments)
+        .chain(state.open_segment.as_ref().map(|open| &open.segment))
+        .chain(state.open_ts_segment.as_ref().map(|(segment, _)| segment));
+    let listed = segments.flat_map(|segment| {
+        segment.key.as_ref().map(|key| key.kid).into_iter().chain(segment.default_kid)
+    });
+    let current = state.key.iter().chain(&state.header_key).map(|key| key.kid);
+    let trimmed = state.trimmed_segments.iter().flat_map(|trimmed| trimmed.kids.iter().copied());
+    let unlisted = state.unlisted_kids.iter().copied();
+
+    listed.chain(current).chain(trimmed).chain(unlisted).collect()
+}
+
+// Takes the oldest segment out of the playlist, its files are deleted once players can't
+// request them anymore
+fn unlist_oldest(state: &mut StreamState, now: DateTime<Utc>) {
+    let segment = state.segments.pop_front().unwrap();
+
+    state.media_sequence += 1;
+    // The tag of the segment leaves the playlist with it
+    if segment.discontinuity {
+        state.discontinuity_sequence += 1;
+    }
+    // Unless it went to the next segment with a keyframe in the I-frame playlist
+    let sequence = &mut state.iframe_sequence;
+    if segment.iframe.is_some() {
+        sequence.media += 1;
+        if std::mem::take(&mut sequence.carried) || segment.discontinuity {
+            sequence.discontinuity += 1;
+        }
+    } else {
+        sequence.carried |= segment.discontinuity;
+    }
+
+    if state.settings.mode == PlaylistMode::LiveToVod {
+        state.archived_segments.push(segment);
+        return;
+    }
+
+    // HLS spec mandates that segments are removed from the filesystem no sooner
+    // than the duration of the playlist + duration of the segment after they were
+    // removed from the playlist.
+    let delay = playlist_duration(&state.segments) + segment.duration;
+    let removal_time = now + Duration::nanoseconds(delay.nseconds() as i64);
+
+    // In single file mode the file goes along with the last of its segments
+    let kids = segment.key.iter().map(|key| key.kid);
+    let file_in_use = state.settings.single_file
+        && (state.segments.iter().any(|listed| listed.path == segment.path)
+            || state.single_file.as_ref().is_some_and(|(name, ..)| *name == segment.path));
+    if file_in_use {
+        state.unlisted_kids.extend(kids);
+    } else {
+        let kids = std::mem::take(&mut state.unlisted_kids).into_iter().chain(kids).collect();
+        state.trimmed_segments.push_back(UnreffedSegment {
+            removal_time,
+            path: segment.path,
+            parts: segment.parts.into_iter().map(|part| part.path).collect(),
+            kids,
+        });
+    }
+
+    // A header superseded by a new one goes along with the last segment referring to it
+    let init_in_use = segment.init == state.init
+        || state.segments.iter().any(|listed| listed.init == segment.init)
+        || state.open_segment.as_ref().is_some_and(|open| open.segment.init == segment.init);
+    if !init_in_use {
+        state.trimmed_segments.push_back(UnreffedSegment {
+            removal_time,
+            path: segment.init,
+            parts: vec![],
+            kids: segment.default_kid.into_iter().collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_duration_rounds_up() {
+        assert_eq!(target_duration(gst::ClockTime::from_seconds(2)), 2.0);
+        assert_eq!(target_duration(gst::ClockTime::from_mseconds(2001)), 3.0);
+        assert_eq!(target_duration(gst::ClockTime::from_mseconds(1500)), 2.0);
+    }
+
+    #[test]
+    fn target_duration_is_at_least_one_second() {
+        assert_eq!(target_duration(gst::ClockTime::ZERO), 1.0);
+        assert_eq!(target_duration(gst::ClockTime::from_mseconds(200)), 1.0);
+    }
+
+    #[test]
+    fn single_files_cover_a_window() {
+        let settings = Settings {
+            segment_duration: gst::ClockTime::from_seconds(2),
+            window_size: 6,
+            mode: PlaylistMode::Live,
+            dvr_window: gst::ClockTime::from_seconds(60),
+            part_duration: None,
+            encryption: None,
+            single_file: true,
+        };
+        assert_eq!(segments_per_file(&settings), 6);
+
+        let dvr = Settings {
+            mode: PlaylistMode::Dvr,
+            ..settings
+        };
+        assert_eq!(segments_per_file(&dvr), 30);
+
+        let event = Settings {
+            mode: PlaylistMode::Event,
+            ..settings
+        };
+        assert_eq!(segments_per_file(&event), u32::MAX);
+    }
+
+    #[test]
+    fn ts_segments_end_on_boundaries() {
+        let start = gst::ClockTime::from_seconds(10);
+        let duration = gst::ClockTime::from_seconds(2);
+
+        assert_eq!(ts_segment_end(start, duration, start), gst::ClockTime::from_seconds(12));
+        // Keyframes a few nanoseconds early and audio frames a few milliseconds late
+        assert_eq!(
+            ts_segment_end(start, duration, gst::ClockTime::from_nseconds(13_999_999_980)),
+            gst::ClockTime::from_seconds(16)
+        );
+        assert_eq!(
+            ts_segment_end(start, duration, gst::ClockTime::from_mseconds(14_015)),
+            gst::ClockTime::from_seconds(16)
+        );
+    }
+
+    #[test]
+    fn splice_points_start_segments() {
+        // An audio segment slightly off the boundary at 4s
+        let segment = Segment {
+            number: 2,
+            date_time: Utc::now(),
+            time: gst::ClockTime::from_mseconds(4021),
+            duration: gst::ClockTime::from_mseconds(1984),
+            path: "segment_2.fmp4".to_string(),
+            init: "init.mp4".to_string(),
+            discontinuity: false,
+            key: None,
+            default_kid: None,
+            iframe: None,
+            byte_range: None,
+            parts: vec![],
+        };
+
+        assert!(starts_at(&segment, gst::ClockTime::from_seconds(4)));
+        assert!(!starts_at(&segment, gst::ClockTime::from_seconds(6)));
+        assert!(!starts_at(&segment, gst::ClockTime::from_seconds(2)));
+
+        let second = gst::ClockTime::SECOND;
+        assert!(within_break(&segment, second * 2, second * 8));
+        assert!(!within_break(&segment, second * 4, second * 8));
+        assert!(!within_break(&segment, second * 2, second * 4));
+
+        // Interstitials start anywhere within a segment
+        assert!(covers(&segment, gst::ClockTime::from_seconds(5)));
+        assert!(!covers(&segment, gst::ClockTime::from_seconds(4)));
+    }
+}
@@ -0,0 +1,443 @@
+use gst::prelude::*;
+use std::{
+    fmt::Write as _,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Error};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use m3u8_rs::{AlternativeMedia, AlternativeMediaType, MasterPlaylist, VariantStream};
+use serde::Deserialize;
+
+use crate::{
+    dash, encryption::KeyStore, hlscmaf, metadata::MetadataStore, progress::Progress, utils, video,
+};
+
+/// `CLASS` of the date ranges of interstitials.
+pub(crate) const CLASS: &str = "com.apple.hls.interstitial";
+/// Directory of the output the generated assets are written to, one sub-directory each.
+pub(crate) const DIR: &str = "interstitials";
+// Generated assets
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 360;
+const VIDEO_BITRATE: u32 = 1_024_000;
+const AUDIO_RATE: i32 = 48_000;
+// Bitrate `avenc_aac` encodes at by default
+const AUDIO_BITRATE: u64 = 128_000;
+
+/// Seeking a player has to prevent during an interstitial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Restriction {
+    /// Seeking forward within the interstitial
+    Skip,
+    /// Seeking past the interstitial without playing it
+    Jump,
+}
+
+impl FromStr for Restriction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Restriction::Skip),
+            "jump" => Ok(Restriction::Jump),
+            _ => Err(anyhow!("unknown restriction '{}', expected skip or jump", s)),
+        }
+    }
+}
+
+/// When a player plays an interstitial, besides reaching its start date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Cue {
+    /// Before starting the primary content, wherever playback starts
+    Pre,
+    /// After the primary content ended
+    Post,
+    /// Only the first time it is reached
+    Once,
+}
+
+impl FromStr for Cue {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre" => Ok(Cue::Pre),
+            "post" => Ok(Cue::Post),
+            "once" => Ok(Cue::Once),
+            _ => Err(anyhow!("unknown cue '{}', expected pre, post or once", s)),
+        }
+    }
+}
+
+/// An interstitial event, signalled as an `EXT-X-DATERANGE` in the media playlists.
+///
+/// Without `asset_uri` and `asset_list` the asset is generated below `interstitials/` of the
+/// output and served along with the stream.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Interstitial {
+    /// ID of the date range, also the directory of a generated asset
+    pub id: String,
+    /// Seconds from the first frame to the start of the event
+    pub start: f64,
+    /// Expected duration of the interstitial in seconds, the length of a generated asset
+    pub duration: f64,
+    /// Multivariant or media playlist of the asset
+    pub asset_uri: Option<String>,
+    /// JSON list of assets, resolved by the player when reaching the event
+    pub asset_list: Option<String>,
+    /// Signals a generated asset through an asset list instead of its playlist
+    pub serve_list: bool,
+    /// Seconds into the primary content playback resumes at, relative to the start. The
+    /// duration of the interstitial when unset, as for live content
+    pub resume_offset: Option<f64>,
+    pub restrict: Vec<Restriction>,
+    pub cue: Vec<Cue>,
+}
+
+impl Default for Interstitial {
+    fn default() -> Self {
+        Interstitial {
+            id: String::new(),
+            start: 0.0,
+            duration: 10.0,
+            asset_uri: None,
+            asset_list: None,
+            serve_list: false,
+            resume_offset: None,
+            restrict: vec![],
+            cue: vec![],
+        }
+    }
+}
+
+impl FromStr for Interstitial {
+    type Err = Error;
+
+    // Parses `start=30,duration=10,restrict=skip+jump,cue=once`, every key is optional.
+    // Lists are separated by `+`. An empty ID is filled in by the caller.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut interstitial = Interstitial::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "id" => interstitial.id = value.to_string(),
+                "start" => interstitial.start = value.parse()?,
+                "duration" => interstitial.duration = value.parse()?,
+                "asset_uri" => interstitial.asset_uri = Some(value.to_string()),
+                "asset_list" => interstitial.asset_list = Some(value.to_string()),
+                "serve_list" => interstitial.serve_list = value.parse()?,
+                "resume_offset" => interstitial.resume_offset = Some(value.parse()?),
+                "restrict" => interstitial.restrict = parse_list(value)?,
+                "cue" => interstitial.cue = parse_list(value)?,
+                _ => return Err(anyhow!("unknown interstitial property '{}'", key)),
+            }
+        }
+
+        Ok(interstitial)
+    }
+}
+
+impl Interstitial {
+    /// Whether the asset is generated and served locally.
+    pub fn generates_asset(&self) -> bool {
+        self.asset_uri.is_none() && self.asset_list.is_none()
+    }
+
+    /// Attributes of the `EXT-X-DATERANGE` of the event starting at `start_date`, as found in
+    /// the media playlists one directory below the output.
+    pub fn attributes(&self, start_date: DateTime<Utc>) -> String {
+        let local = format!("../{}/{}", DIR, self.id);
+        let asset = match (&self.asset_uri, &self.asset_list) {
+            (Some(uri), _) => format!("X-ASSET-URI=\"{}\"", uri),
+            (None, Some(list)) => format!("X-ASSET-LIST=\"{}\"", list),
+            (None, None) if self.serve_list => format!("X-ASSET-LIST=\"{}/assets.json\"", local),
+            (None, None) => format!("X-ASSET-URI=\"{}/manifest.m3u8\"", local),
+        };
+
+        let mut attributes = format!(
+            "ID=\"{}\",CLASS=\"{}\",START-DATE=\"{}\",DURATION={:.3},{}",
+            self.id,
+            CLASS,
+            start_date.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.duration,
+            asset
+        );
+        if let Some(resume_offset) = self.resume_offset {
+            write!(attributes, ",X-RESUME-OFFSET={:.3}", resume_offset).unwrap();
+        }
+        if !self.restrict.is_empty() {
+            let restrict = self
+                .restrict
+                .iter()
+                .map(|restriction| match restriction {
+                    Restriction::Skip => "SKIP",
+                    Restriction::Jump => "JUMP",
+                })
+                .collect::<Vec<_>>();
+            write!(attributes, ",X-RESTRICT=\"{}\"", restrict.join(",")).unwrap();
+        }
+        if !self.cue.is_empty() {
+            let cue = self
+                .cue
+                .iter()
+                .map(|cue| match cue {
+                    Cue::Pre => "PRE",
+                    Cue::Post => "POST",
+                    Cue::Once => "ONCE",
+                })
+                .collect::<Vec<_>>();
+            write!(attributes, ",CUE=\"{}\"", cue.join(",")).unwrap();
+        }
+
+        attributes
+    }
+}
+
+fn parse_list<T: FromStr<Err = Error>>(value: &str) -> Result<Vec<T>, Error> {
+    value.split('+').map(str::parse).collect()
+}
+
+/// Encodes the asset of `interstitial` into `path`: a VOD multivariant playlist with an
+/// H.264 and an AAC rendition, showing the ID of the event over color bars.
+///
+/// Runs a pipeline of its own to the end, as fast as the encoders go.
+pub(crate) fn generate_asset(
+    path: &Path,
+    interstitial: &Interstitial,
+    segment_duration: gst::ClockTime,
+) -> Result<(), Error> {
+    info!("generating asset of interstitial {} in {}", interstitial.id, path.display());
+    std::fs::create_dir_all(path)?;
+
+    let settings = hlscmaf::Settings {
+        segment_duration,
+        window_size: 5,
+        mode: hlscmaf::PlaylistMode::LiveToVod,
+        dvr_window: segment_duration,
+        part_duration: None,
+        encryption: None,
+        single_file: false,
+    };
+    // Never written, as no rendition of the asset is put on its ladder
+    let mpd = dash::Mpd::new(
+        path.join("manifest.mpd"),
+        settings,
+        dash::Settings {
+            addressing: dash::Addressing::Number,
+            target_latency: gst::ClockTime::ZERO,
+        },
+    );
+    let output = hlscmaf::Output {
+        path: path.to_path_buf(),
+        settings,
+        progress: Arc::new(Progress::default()),
+        mpd: Arc::new(Mutex::new(mpd)),
+        keys: Arc::new(KeyStore::default()),
+        captions: None,
+        splices: vec![],
+        interstitials: vec![],
+        metadata: Arc::new(MetadataStore::default()),
+        format: hlscmaf::SegmentFormat::Cmaf,
+        streams: Default::default(),
+        origin: Default::default(),
+    };
+
+    let frames = (interstitial.duration * video::FRAMERATE as f64).round() as i32;
+    let key_int = (segment_duration.nseconds() * video::FRAMERATE as u64
+        / gst::ClockTime::SECOND.nseconds())
+    .max(1);
+
+    let videotestsrc = gst::ElementFactory::make("videotestsrc")
+        .property("num-buffers", frames)
+        .property_from_str("pattern", "smpte")
+        .build()?;
+    let video_capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst_video::VideoCapsBuilder::new()
+                .format(gst_video::VideoFormat::I420)
+                .width(WIDTH)
+                .height(HEIGHT)
+                .framerate(video::FRAMERATE.into())
+                .build(),
+        )
+        .build()?;
+    let textoverlay = gst::ElementFactory::make("textoverlay")
+        .property("text", format!("Interstitial {}", interstitial.id))
+        .property("font-desc", "Sans 24")
+        .build()?;
+    let x264enc = gst::ElementFactory::make("x264enc")
+        .property("bframes", 0u32)
+        .property("key-int-max", key_int as u32)
+        .property("bitrate", VIDEO_BITRATE / 1000)
+        .property_from_str("tune", "zerolatency")
+        .build()?;
+    let h264parse = gst::ElementFactory::make("h264parse").build()?;
+    let video_mux = gst::ElementFactory::make("isofmp4mux")
+        .property("fragment-duration", segment_duration)
+        .property_from_str("header-update-mode", "update")
+        .property("write-mehd", true)
+        .build()?;
+    // As fast as possible instead of in real time
+    let video_sink = gst_app::AppSink::builder().buffer_list(true).sync(false).build();
+
+    // One buffer per frame, so both end at the same time
+    let audiotestsrc = gst::ElementFactory::make("audiotestsrc")
+        .property("num-buffers", frames)
+        .property("samplesperbuffer", AUDIO_RATE / video::FRAMERATE)
+        .build()?;
+    let audio_capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst_audio::AudioCapsBuilder::new_interleaved().rate(AUDIO_RATE).build(),
+        )
+        .build()?;
+    let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+    let avenc_aac = gst::ElementFactory::make("avenc_aac").build()?;
+    let audio_mux = gst::ElementFactory::make("cmafmux")
+        .property_from_str("header-update-mode", "update")
+        .property("write-mehd", true)
+        .property("fragment-duration", segment_duration)
+        .build()?;
+    let audio_sink = gst_app::AppSink::builder().buffer_list(true).sync(false).build();
+
+    let pipeline = gst::Pipeline::default();
+    let video = [
+        &videotestsrc,
+        &video_capsfilter,
+        &textoverlay,
+        &x264enc,
+        &h264parse,
+        &video_mux,
+        video_sink.upcast_ref(),
+    ];
+    let audio = [
+        &audiotestsrc,
+        &audio_capsfilter,
+        &audioconvert,
+        &avenc_aac,
+        &audio_mux,
+        audio_sink.upcast_ref(),
+    ];
+    pipeline.add_many(video)?;
+    pipeline.add_many(audio)?;
+    gst::Element::link_many(video)?;
+    gst::Element::link_many(audio)?;
+
+    hlscmaf::setup(&video_sink, "video", &output, &settings);
+    hlscmaf::setup(&audio_sink, "audio", &output, &settings);
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        match msg.view() {
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Error(err) => {
+                pipeline.set_state(gst::State::Null)?;
+                bail!(
+                    "failed to generate asset of interstitial {}: {}",
+                    interstitial.id,
+                    err.error()
+                );
+            }
+            _ => (),
+        }
+    }
+
+    let codecs = [&x264enc, &avenc_aac]
+        .iter()
+        .map(|enc| {
+            let caps = enc.static_pad("src").unwrap().current_caps().unwrap();
+            gst_pbutils::codec_utils_caps_get_mime_codec(&caps).map(String::from)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    pipeline.set_state(gst::State::Null)?;
+
+    let playlist = MasterPlaylist {
+        version: Some(7),
+        variants: vec![VariantStream {
+            uri: "video/manifest.m3u8".to_string(),
+            bandwidth: VIDEO_BITRATE as u64 + AUDIO_BITRATE,
+            codecs: Some(codecs.join(",")),
+            resolution: Some(m3u8_rs::Resolution {
+                width: WIDTH as u64,
+                height: HEIGHT as u64,
+            }),
+            audio: Some("audio".to_string()),
+            ..Default::default()
+        }],
+        alternatives: vec![AlternativeMedia {
+            media_type: AlternativeMediaType::Audio,
+            uri: Some("audio/manifest.m3u8".to_string()),
+            group_id: "audio".to_string(),
+            name: "audio".to_string(),
+            default: true,
+            autoselect: true,
+            ..Default::default()
+        }],
+        independent_segments: true,
+        ..Default::default()
+    };
+    utils::write_atomically(&path.join("manifest.m3u8"), |file| playlist.write_to(file))?;
+
+    // Asset list URIs are relative to the list
+    let list = serde_json::json!({
+        "ASSETS": [{ "URI": "manifest.m3u8", "DURATION": interstitial.duration }],
+    });
+    std::fs::write(path.join("assets.json"), list.to_string())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_date() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T12:00:30Z").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn signals_generated_assets() {
+        let interstitial = "id=break,start=30,restrict=skip+jump,cue=once"
+            .parse::<Interstitial>()
+            .unwrap();
+
+        assert_eq!(
+            interstitial.attributes(start_date()),
+            "ID=\"break\",CLASS=\"com.apple.hls.interstitial\",\
+             START-DATE=\"2024-01-01T12:00:30.000Z\",DURATION=10.000,\
+             X-ASSET-URI=\"../interstitials/break/manifest.m3u8\",\
+             X-RESTRICT=\"SKIP,JUMP\",CUE=\"ONCE\""
+        );
+    }
+
+    #[test]
+    fn signals_asset_lists() {
+        let interstitial = Interstitial {
+            id: "break".to_string(),
+            serve_list: true,
+            resume_offset: Some(0.0),
+            ..Default::default()
+        };
+        assert!(interstitial.attributes(start_date()).ends_with(
+            "X-ASSET-LIST=\"../interstitials/break/assets.json\",X-RESUME-OFFSET=0.000"
+        ));
+
+        let interstitial = Interstitial {
+            asset_list: Some("https://example.com/assets.json".to_string()),
+            ..interstitial
+        };
+        assert!(!interstitial.generates_asset());
+        assert!(interstitial
+            .attributes(start_date())
+            .contains("X-ASSET-LIST=\"https://example.com/assets.json\""));
+    }
+}
@@ -1,34 +1,100 @@
 use gst::prelude::*;
-use log::info;
+use log::{info, warn};
 
 use std::collections::HashMap;
 use std::path::{PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
-use anyhow::Error;
-use m3u8_rs::{AlternativeMedia, AlternativeMediaType, MasterPlaylist, VariantStream};
+use anyhow::{Context, Error};
+use clap::Parser;
+use m3u8_rs::{
+    AlternativeMedia, AlternativeMediaType, ClosedCaptionGroupId, MasterPlaylist, VariantStream,
+};
 
+mod captions;
+mod cli;
+mod config;
+mod dash;
+mod encryption;
 mod hlscmaf;
+mod interstitials;
+mod metadata;
+mod mp4;
+mod progress;
+mod scte35;
+mod server;
+mod source;
+mod subtitles;
+mod thumbnails;
 mod utils;
 mod video;
 mod audio;
 
+/// Name of the application message posted on the bus on SIGINT/SIGTERM.
+const SHUTDOWN: &str = "yatta-shutdown";
+
 struct State {
     video_streams: Vec<video::VideoStream>,
     audio_streams: Vec<audio::AudioStream>,
+    subtitle_streams: Vec<subtitles::SubtitleStream>,
+    // Embedded into the H.264 and H.265 renditions
+    captions: Option<captions::Captions>,
+    // Peak bit rate of the `iframes.m3u8` next to the media playlist of each video rendition,
+    // which is only listed once its first keyframe was measured
+    iframe_bandwidths: HashMap<String, u64>,
+    // `EXT-X-IMAGE-STREAM-INF` of the thumbnails when enabled
+    image_stream: Option<m3u8_rs::ExtTag>,
     all_mimes: HashMap<String, String>,
     path: PathBuf,
+    format: hlscmaf::SegmentFormat,
+    // Master playlist of the MPEG-TS renditions
+    ts_path: PathBuf,
+    mpd: Arc<Mutex<dash::Mpd>>,
     wrote_manifest: bool,
 }
 
 impl State {
     fn try_write_manifest(&mut self) {
+        // The MPD lists every rendition as soon as its codec is known
+        self.mpd
+            .lock()
+            .unwrap()
+            .set_ladder(&self.video_streams, &self.audio_streams, &self.subtitle_streams, &self.all_mimes);
+
         if self.wrote_manifest || self.all_mimes.len() < self.video_streams.len() + self.audio_streams.len() { return };
         self.write_manifest()
     }
 
     fn write_manifest(&mut self) {
-        let playlist = MasterPlaylist {
+        if self.format.has_cmaf() {
+            let playlist = self.cmaf_master_playlist();
+            utils::write_atomically(&self.path, |file| playlist.write_to(file))
+                .expect("Failed to write master playlist");
+            info!("wrote master manifest to {}", self.path.display());
+        }
+
+        if self.format.has_ts() {
+            let playlist = self.ts_master_playlist();
+            if let Some(dir) = self.ts_path.parent() {
+                std::fs::create_dir_all(dir).expect("failed to create directory");
+            }
+            utils::write_atomically(&self.ts_path, |file| playlist.write_to(file))
+                .expect("Failed to write master playlist");
+            info!("wrote MPEG-TS master manifest to {}", self.ts_path.display());
+        }
+
+        self.wrote_manifest = true;
+    }
+
+    fn cmaf_master_playlist(&self) -> MasterPlaylist {
+        // CODECS covers every rendition a variant can be played with, IMSC1 text included
+        let imsc1 = self
+            .subtitle_streams
+            .iter()
+            .any(|stream| stream.format == subtitles::Format::Imsc1);
+
+        MasterPlaylist {
             version: Some(7),
             variants: self.video_streams.iter().map(|stream| {
                     let mut path = PathBuf::new();
@@ -38,15 +104,51 @@ impl State {
                     VariantStream {
                         uri: path.as_path().display().to_string(),
                         bandwidth: stream.bitrate,
-                        codecs: self.all_mimes.get(&stream.name).map(|s| s.to_string()),
+                        codecs: self.all_mimes.get(&stream.name).map(|s| {
+                            if imsc1 {
+                                format!("{},{}", s, subtitles::IMSC1_CODECS)
+                            } else {
+                                s.to_string()
+                            }
+                        }),
                         resolution: Some(m3u8_rs::Resolution {
                             width: stream.width,
                             height: stream.height,
                         }),
                         audio: Some("audio".to_string()),
+                        subtitles: if self.subtitle_streams.is_empty() {
+                            None
+                        } else {
+                            Some("subtitles".to_string())
+                        },
+                        // AV1 has no SEI to carry them
+                        closed_captions: match self.captions {
+                            Some(_) if stream.codec != "av1" => {
+                                Some(ClosedCaptionGroupId::GroupId("cc".to_string()))
+                            }
+                            _ => None,
+                        },
                         ..Default::default()
                     }
                 })
+                .chain(self.video_streams.iter().filter_map(|stream| {
+                    let bandwidth = *self.iframe_bandwidths.get(&stream.name)?;
+                    let mut path = PathBuf::new();
+                    path.push(&stream.name);
+                    path.push("iframes.m3u8");
+
+                    Some(VariantStream {
+                        is_i_frame: true,
+                        uri: path.as_path().display().to_string(),
+                        bandwidth,
+                        codecs: self.all_mimes.get(&stream.name).cloned(),
+                        resolution: Some(m3u8_rs::Resolution {
+                            width: stream.width,
+                            height: stream.height,
+                        }),
+                        ..Default::default()
+                    })
+                }))
                 .collect(),
             alternatives: self.audio_streams.iter().map(|stream| {
                     let mut path = PathBuf::new();
@@ -65,91 +167,578 @@ impl State {
                         ..Default::default()
                     }
                 })
+                .chain(self.subtitle_streams.iter().map(|stream| {
+                    let mut path = PathBuf::new();
+                    path.push(&stream.name);
+                    path.push("manifest.m3u8");
+
+                    AlternativeMedia {
+                        media_type: AlternativeMediaType::Subtitles,
+                        uri: Some(path.as_path().display().to_string()),
+                        group_id: "subtitles".to_string(),
+                        language: Some(stream.lang.clone()),
+                        name: stream.name.clone(),
+                        default: stream.default,
+                        // Forced subtitles are picked without the viewer asking for them
+                        autoselect: stream.default || stream.forced,
+                        forced: stream.forced,
+                        ..Default::default()
+                    }
+                }))
+                .chain(self.captions.into_iter().flat_map(|_| captions::renditions("cc")))
                 .collect(),
             independent_segments: true,
+            unknown_tags: self.image_stream.iter().cloned().collect(),
             ..Default::default()
-        };
+        }
+    }
 
-        let mut file = std::fs::File::create(&self.path).unwrap();
-        playlist.write_to(&mut file).expect("Failed to write master playlist");
-        info!("wrote master manifest to {}", self.path.display());
-        self.wrote_manifest = true;
+    // For legacy players, without AV1, IMSC1 subtitles, I-frame playlists and thumbnails
+    fn ts_master_playlist(&self) -> MasterPlaylist {
+        let webvtt = self
+            .subtitle_streams
+            .iter()
+            .filter(|stream| stream.format == subtitles::Format::Webvtt)
+            .collect::<Vec<_>>();
+
+        MasterPlaylist {
+            version: Some(3),
+            variants: self
+                .video_streams
+                .iter()
+                .filter(|stream| stream.codec != "av1")
+                .map(|stream| VariantStream {
+                    uri: format!("{}/manifest.m3u8", stream.name),
+                    bandwidth: stream.bitrate,
+                    codecs: self.all_mimes.get(&stream.name).cloned(),
+                    resolution: Some(m3u8_rs::Resolution {
+                        width: stream.width,
+                        height: stream.height,
+                    }),
+                    audio: Some("audio".to_string()),
+                    subtitles: if webvtt.is_empty() {
+                        None
+                    } else {
+                        Some("subtitles".to_string())
+                    },
+                    closed_captions: self
+                        .captions
+                        .map(|_| ClosedCaptionGroupId::GroupId("cc".to_string())),
+                    ..Default::default()
+                })
+                .collect(),
+            alternatives: self
+                .audio_streams
+                .iter()
+                .map(|stream| AlternativeMedia {
+                    media_type: AlternativeMediaType::Audio,
+                    uri: Some(format!("{}/manifest.m3u8", stream.name)),
+                    group_id: "audio".to_string(),
+                    language: Some(stream.lang.clone()),
+                    name: stream.name.clone(),
+                    default: stream.default,
+                    autoselect: stream.default,
+                    channels: Some("2".to_string()),
+                    ..Default::default()
+                })
+                .chain(webvtt.iter().map(|stream| AlternativeMedia {
+                    media_type: AlternativeMediaType::Subtitles,
+                    uri: Some(format!("{}/manifest.m3u8", stream.name)),
+                    group_id: "subtitles".to_string(),
+                    language: Some(stream.lang.clone()),
+                    name: stream.name.clone(),
+                    default: stream.default,
+                    autoselect: stream.default || stream.forced,
+                    forced: stream.forced,
+                    ..Default::default()
+                }))
+                .chain(self.captions.into_iter().flat_map(|_| captions::renditions("cc")))
+                .collect(),
+            independent_segments: true,
+            ..Default::default()
+        }
     }
 }
 
+/// Re-reads the config and adds, removes or replaces the renditions that differ from the
+/// running ladder. Renditions that didn't change keep running untouched.
+///
+/// Renditions are matched by name. One whose settings changed is set up again and continues
+/// the playlists of the previous one, a removed one gets its playlists finished and its files
+/// deleted after a while.
+fn reload(
+    args: &cli::Args,
+    running: &mut config::Config,
+    branches: &mut HashMap<String, utils::Branch>,
+    state: &Arc<Mutex<State>>,
+    pipeline: &gst::Pipeline,
+    source: &mut source::Source,
+    output: &hlscmaf::Output,
+) -> Result<(), Error> {
+    let new = args.config()?;
+
+    if new.input != running.input
+        || new.output != running.output
+        || new.server != running.server
+        || new.hls_settings() != running.hls_settings()
+        || new.dash_settings() != running.dash_settings()
+        || new.captions != running.captions
+        || new.ad_breaks != running.ad_breaks
+        || new.interstitials != running.interstitials
+        || new.metadata != running.metadata
+        || new.thumbnails != running.thumbnails
+    {
+        warn!(
+            "input, output, server, segment, caption, ad break, interstitial, metadata and \
+             thumbnail settings only take effect after a restart"
+        );
+    }
+
+    let (removed_video, set_up_video) = diff(&running.video, &new.video, |stream| &stream.name);
+    let (removed_audio, set_up_audio) = diff(&running.audio, &new.audio, |stream| &stream.name);
+    let (removed_subtitles, set_up_subtitles) =
+        diff(&running.subtitles, &new.subtitles, |stream| &stream.name);
+    let removed = removed_video
+        .into_iter()
+        .chain(removed_audio)
+        .chain(removed_subtitles)
+        .collect::<Vec<_>>();
+
+    if removed.is_empty()
+        && set_up_video.is_empty()
+        && set_up_audio.is_empty()
+        && set_up_subtitles.is_empty()
+    {
+        info!("ladder unchanged");
+        return Ok(());
+    }
+
+    // Replaced renditions might come with another codec
+    let replaced = set_up_video
+        .iter()
+        .map(|stream| &stream.name)
+        .chain(set_up_audio.iter().map(|stream| &stream.name))
+        .filter(|name| branches.contains_key(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+    let previous_mimes = {
+        let mut state = state.lock().unwrap();
+        let previous = state.all_mimes.clone();
+        for name in &replaced {
+            state.all_mimes.remove(name);
+        }
+        previous
+    };
+
+    // The path and settings of the running output stay in use, see the warning above. The new
+    // branch of a replaced rendition takes its playlists over before the previous one is
+    // finished, which would otherwise end them.
+    //
+    // Every new branch is built before anything else changes, so the running ladder stays as
+    // it is if one of them fails.
+    let built = set_up_branches(
+        &set_up_video,
+        &set_up_audio,
+        &set_up_subtitles,
+        state,
+        pipeline,
+        source,
+        output,
+    );
+    let built = match built {
+        Ok(built) => built,
+        Err(err) => {
+            // The playlists of the renditions went to the new branches already. Those that
+            // replaced a running rendition continue with its previous settings after a
+            // discontinuity, the others are deleted again.
+            let video = running
+                .video
+                .iter()
+                .filter(|stream| set_up_video.iter().any(|new| new.name == stream.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let audio = running
+                .audio
+                .iter()
+                .filter(|stream| set_up_audio.iter().any(|new| new.name == stream.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let subtitles = running
+                .subtitles
+                .iter()
+                .filter(|stream| set_up_subtitles.iter().any(|new| new.name == stream.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let added = set_up_video
+                .iter()
+                .map(|stream| &stream.name)
+                .chain(set_up_audio.iter().map(|stream| &stream.name))
+                .chain(set_up_subtitles.iter().map(|stream| &stream.name))
+                .filter(|name| !branches.contains_key(*name));
+            for name in added {
+                hlscmaf::retire(output, name);
+            }
+
+            state.lock().unwrap().all_mimes = previous_mimes;
+            let restored =
+                set_up_branches(&video, &audio, &subtitles, state, pipeline, source, output)
+                    .context("failed to restore the replaced renditions")?;
+            for (name, branch) in restored {
+                replace_branch(branches, pipeline, &name, branch);
+            }
+
+            return Err(err);
+        }
+    };
+
+    for name in &removed {
+        info!("removing stream {}", name);
+        if let Some(branch) = branches.remove(name) {
+            branch.remove(pipeline);
+        }
+        hlscmaf::retire(output, name);
+    }
+
+    {
+        let mut state = state.lock().unwrap();
+        for name in &removed {
+            state.all_mimes.remove(name);
+            state.iframe_bandwidths.remove(name);
+        }
+        for name in &replaced {
+            state.iframe_bandwidths.remove(name);
+        }
+        // Keep the order of the config file in the master playlist
+        state.video_streams = new.video.clone();
+        state.audio_streams = new.audio.clone();
+        state.subtitle_streams = new.subtitles.clone();
+        state.wrote_manifest = false;
+    }
+
+    for (name, branch) in built {
+        replace_branch(branches, pipeline, &name, branch);
+    }
+
+    running.video = new.video;
+    running.audio = new.audio;
+    running.subtitles = new.subtitles;
+
+    // Only removals means all codecs are known already and the master can be written
+    // right away, otherwise this happens once the new encoders negotiated their caps.
+    state.lock().unwrap().try_write_manifest();
+
+    Ok(())
+}
+
+// Sets up a branch for each of the renditions, by name. Those set up already are removed
+// again if one of them fails.
+fn set_up_branches(
+    video: &[video::VideoStream],
+    audio: &[audio::AudioStream],
+    subtitles: &[subtitles::SubtitleStream],
+    state: &Arc<Mutex<State>>,
+    pipeline: &gst::Pipeline,
+    source: &mut source::Source,
+    output: &hlscmaf::Output,
+) -> Result<Vec<(String, utils::Branch)>, Error> {
+    let mut built = vec![];
+    let mut set_up = || -> Result<(), Error> {
+        for stream in video {
+            info!("setting up video stream {}", stream.name);
+            let (width, height) = source.resolution();
+            if stream.width > width || stream.height > height {
+                warn!(
+                    "{} is larger than the {}x{} of the shared video, which only changes on \
+                     restart",
+                    stream.name, width, height
+                );
+            }
+            let branch = stream.setup(state.clone(), pipeline, source, output)?;
+            built.push((stream.name.clone(), branch));
+        }
+
+        for stream in audio {
+            info!("setting up audio stream {}", stream.name);
+            let branch = stream.setup(state.clone(), pipeline, source, output)?;
+            built.push((stream.name.clone(), branch));
+        }
+
+        for stream in subtitles {
+            info!("setting up subtitle stream {}", stream.name);
+            let branch = stream.setup(pipeline, source, output)?;
+            built.push((stream.name.clone(), branch));
+        }
+
+        Ok(())
+    };
+
+    if let Err(err) = set_up() {
+        for (_, branch) in built {
+            branch.remove(pipeline);
+        }
+        return Err(err);
+    }
+
+    Ok(built)
+}
+
+// The names of the renditions of `running` that aren't in `new` at all, and the renditions of
+// `new` that don't run exactly like that yet, i.e. added or changed ones
+fn diff<T: PartialEq + Clone>(
+    running: &[T],
+    new: &[T],
+    name: impl Fn(&T) -> &String,
+) -> (Vec<String>, Vec<T>) {
+    let removed = running
+        .iter()
+        .filter(|stream| !new.iter().any(|other| name(other) == name(stream)))
+        .map(|stream| name(stream).clone())
+        .collect();
+    let set_up = new.iter().filter(|stream| !running.contains(stream)).cloned().collect();
+
+    (removed, set_up)
+}
+
+fn replace_branch(
+    branches: &mut HashMap<String, utils::Branch>,
+    pipeline: &gst::Pipeline,
+    name: &str,
+    branch: utils::Branch,
+) {
+    if let Some(previous) = branches.insert(name.to_string(), branch) {
+        previous.remove(pipeline);
+    }
+}
 
 fn main() -> Result<(), Error> {
     gst::init()?;
     env_logger::init();
 
-    let path = PathBuf::from("hls_live_stream");
+    let args = cli::Args::parse();
+    let mut config = args.config()?;
+    let run_duration = args.duration.map(std::time::Duration::from_secs);
+
     let pipeline = gst::Pipeline::default();
-    std::fs::create_dir_all(&path).expect("failed to create directory");
+    std::fs::create_dir_all(&config.output.path).expect("failed to create directory");
+
+    // Ready before the first playlist points players to them
+    let segment_duration = config.hls_settings().segment_duration;
+    for interstitial in &config.interstitials {
+        if interstitial.generates_asset() {
+            let path = config.output.path.join(interstitials::DIR).join(&interstitial.id);
+            interstitials::generate_asset(&path, interstitial, segment_duration)?;
+        }
+    }
 
-    let mut manifest_path = path.clone();
-    manifest_path.push("manifest.m3u8");
+    let mut mpd = dash::Mpd::new(
+        config.dash_manifest_path(),
+        config.hls_settings(),
+        config.dash_settings(),
+    );
+    mpd.set_splices(config.splices());
+    mpd.set_event_schemes(&config.metadata_schemes());
+    mpd.set_thumbnails(config.thumbnails);
+    let mpd = Arc::new(Mutex::new(mpd));
+
+    // The MPD has no way to point players to keys outside of a DRM system
+    let encryption = config.hls_settings().encryption;
+    if encryption.is_some_and(|encryption| !encryption.method.is_common())
+        && config.output.format.has_cmaf()
+    {
+        warn!(
+            "aes-128 and sample-aes are HLS only, {} won't list the encrypted renditions",
+            config.dash_manifest_path().display()
+        );
+    }
 
     let state = Arc::new(Mutex::new(State {
-        video_streams: vec![
-            video::VideoStream {
-                name: "av1_0".to_string(),
-                codec: "av1".to_string(),
-                bitrate: 1_024_000,
-                width: 256,
-                height: 144,
-            },
-            video::VideoStream {
-                name: "h265_0".to_string(),
-                codec: "h265".to_string(),
-                bitrate: 1_024_000,
-                width: 640,
-                height: 360,
-            },
-            video::VideoStream {
-                name: "h264_0".to_string(),
-                codec: "h264".to_string(),
-                bitrate: 1_024_000,
-                width: 640,
-                height: 360,
-            },
-        ],
-        audio_streams: vec![
-            audio::AudioStream {
-                name: "audio_0".to_string(),
-                lang: "en".to_string(),
-                default: true,
-                wave: "sine".to_string(),
-            },
-        ],
+        video_streams: config.video.clone(),
+        audio_streams: config.audio.clone(),
+        subtitle_streams: config.subtitles.clone(),
+        captions: config.captions,
+        iframe_bandwidths: HashMap::new(),
+        image_stream: config
+            .thumbnails
+            .map(|thumbnails| thumbnails.image_stream_tag(segment_duration)),
         all_mimes: HashMap::new(),
-        path: manifest_path.clone(),
+        path: config.master_playlist_path(),
+        format: config.output.format,
+        ts_path: config.ts_master_playlist_path(),
+        mpd: mpd.clone(),
         wrote_manifest: false,
     }));
 
-    {
-        let state_lock = state.lock().unwrap();
+    // Renditions scale down from the largest one of the initial ladder
+    let width = config.video.iter().map(|stream| stream.width).max().unwrap_or(1280);
+    let height = config.video.iter().map(|stream| stream.height).max().unwrap_or(720);
+    let input = source::Input::parse(config.input.as_deref())?;
+    let mut source = source::Source::new(&pipeline, input, width, height);
 
-        for stream in &state_lock.video_streams {
-            stream.setup(state.clone(), &pipeline, &path)?;
-        }
+    let mut branches = HashMap::new();
+    let output = hlscmaf::Output {
+        path: config.output.path.clone(),
+        settings: config.hls_settings(),
+        progress: Arc::new(progress::Progress::default()),
+        mpd,
+        keys: Arc::new(encryption::KeyStore::default()),
+        captions: config.captions,
+        splices: config.splices(),
+        interstitials: config.interstitials.clone(),
+        metadata: Arc::new(metadata::MetadataStore::new(config.metadata_schemes())),
+        format: config.output.format,
+        streams: Default::default(),
+        origin: Default::default(),
+    };
 
-        for stream in &state_lock.audio_streams {
-            stream.setup(state.clone(), &pipeline, &path)?;
-        }
+    for stream in &config.video {
+        let branch = stream.setup(state.clone(), &pipeline, &mut source, &output)?;
+        branches.insert(stream.name.clone(), branch);
     }
 
-    pipeline.set_state(gst::State::Playing)?;
+    for stream in &config.audio {
+        let branch = stream.setup(state.clone(), &pipeline, &mut source, &output)?;
+        branches.insert(stream.name.clone(), branch);
+    }
+
+    for stream in &config.subtitles {
+        let branch = stream.setup(&pipeline, &mut source, &output)?;
+        branches.insert(stream.name.clone(), branch);
+    }
+
+    if let Some(ref thumbnails) = config.thumbnails {
+        let branch = thumbnails::setup(thumbnails, &pipeline, &mut source, &output)?;
+        branches.insert(thumbnails::NAME.to_string(), branch);
+    }
+
+    // A URI input gets rewound in PAUSED first, see the SOURCE_READY handling below
+    if source.needs_preroll() {
+        pipeline.set_state(gst::State::Paused)?;
+    } else {
+        pipeline.set_state(gst::State::Playing)?;
+    }
+
+    if let Some(ref server) = config.server {
+        server::Server::spawn(
+            &server.address,
+            &config.output.path,
+            &config.output.master_playlist,
+            output.progress.clone(),
+            output.keys.clone(),
+            output.metadata.clone(),
+        )?;
+    }
 
     let bus = pipeline.bus().expect("Pipeline without bus. Shouldn't happen!");
+    if let Some(ref path) = args.config {
+        config::watch(path.clone(), bus.clone());
+    }
+
+    {
+        // The first signal finishes the stream, a second one gives up on it
+        let bus = bus.clone();
+        let signaled = AtomicBool::new(false);
+        ctrlc::set_handler(move || {
+            if signaled.swap(true, Ordering::SeqCst) {
+                eprintln!("Interrupted again, exiting without finishing the stream");
+                std::process::exit(1);
+            }
 
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+            let msg = gst::message::Application::new(gst::Structure::new_empty(SHUTDOWN));
+            let _ = bus.post(msg);
+        })?;
+    }
+
+    let mut deadline = run_duration.map(|duration| std::time::Instant::now() + duration);
+    let mut stopping = false;
+
+    loop {
         use gst::MessageView;
 
+        let timeout = deadline.map(|deadline| {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            gst::ClockTime::from_nseconds(remaining.as_nanos() as u64)
+        });
+
+        let Some(msg) = bus.timed_pop(timeout) else {
+            deadline = None;
+            if !stopping {
+                info!("reached run duration, stopping");
+                stopping = true;
+                pipeline.send_event(gst::event::Eos::new());
+            }
+            continue;
+        };
+
         match msg.view() {
             MessageView::Eos(..) => {
                 println!("EOS");
                 break;
             }
+            MessageView::Application(app)
+                if app.structure().map_or(false, |s| s.name() == SHUTDOWN) =>
+            {
+                // The stream finishes once, no matter what comes first
+                deadline = None;
+                if !stopping {
+                    info!("stopping, finishing the stream");
+                    stopping = true;
+                    // Lets the muxers write their final headers and the playlists get
+                    // their ENDLIST before the pipeline is shut down.
+                    pipeline.send_event(gst::event::Eos::new());
+                }
+            }
+            MessageView::Application(app)
+                if app.structure().map_or(false, |s| s.name() == config::CONFIG_CHANGED) =>
+            {
+                if stopping {
+                    continue;
+                }
+
+                let reloaded = reload(
+                    &args,
+                    &mut config,
+                    &mut branches,
+                    &state,
+                    &pipeline,
+                    &mut source,
+                    &output,
+                );
+                if let Err(err) = reloaded {
+                    warn!("not applying new config: {:#}", err);
+                }
+            }
+            MessageView::Application(app)
+                if app.structure().map_or(false, |s| s.name() == source::SOURCE_READY) =>
+            {
+                // Plays once the rewind is done, see SEEK_DONE
+                source.start_looping();
+            }
+            MessageView::SegmentDone(..) => source.loop_input(),
+            MessageView::Application(app)
+                if app.structure().map_or(false, |s| s.name() == source::SEEK_DONE) =>
+            {
+                let done = app.structure().unwrap();
+                let error = done.get::<String>("error").ok();
+                if done.get::<bool>("rewind").unwrap_or(false) {
+                    // Played once and finished at its end then
+                    if let Some(err) = error {
+                        warn!("failed to rewind the input, it won't loop: {}", err);
+                    }
+                    pipeline.set_state(gst::State::Playing)?;
+                } else if let Some(err) = error {
+                    // Nothing would ever come after the end of the input, the stream is
+                    // finished like on a signal instead
+                    warn!("failed to loop the input, finishing the stream: {}", err);
+                    if !stopping {
+                        stopping = true;
+                        pipeline.send_event(gst::event::Eos::new());
+                    }
+                }
+            }
+            MessageView::Latency(..) => {
+                // Renditions added at runtime change the latency of the pipeline
+                let _ = pipeline.recalculate_latency();
+            }
             MessageView::Error(err) => {
                 pipeline.set_state(gst::State::Null)?;
                 eprintln!(
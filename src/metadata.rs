@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{mp4::Mp4Box, utils};
+
+/// Scheme of the `emsg` boxes carrying ID3 tags, which HLS players read timed metadata from.
+pub(crate) const ID3_SCHEME_ID_URI: &str = "https://aomedia.org/emsg/ID3";
+/// Path of the endpoint metadata is injected through on the built-in server.
+pub(crate) const METADATA_PATH: &str = "/metadata";
+// Of the presentation times and durations in the `emsg` boxes
+const TIMESCALE: u64 = 90_000;
+
+/// A request to inject metadata, the body of a POST to [`METADATA_PATH`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Injection {
+    /// Payload, e.g. a JSON document describing a poll
+    pub data: String,
+    /// Seconds from the first frame to the event. The earliest time all renditions can
+    /// still carry when unset
+    #[serde(default)]
+    pub time: Option<f64>,
+    /// Seconds the event lasts
+    #[serde(default)]
+    pub duration: f64,
+    /// Scheme of the event for DASH players, the first configured one when unset
+    #[serde(default)]
+    pub scheme_id_uri: Option<String>,
+}
+
+/// An injected event, as answered to the request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Event {
+    pub id: u32,
+    /// Seconds from the first frame
+    pub time: f64,
+    pub duration: f64,
+    pub scheme_id_uri: String,
+    #[serde(skip)]
+    data: String,
+    // Running time, which is also the media time of every rendition
+    #[serde(skip)]
+    running_time: gst::ClockTime,
+}
+
+#[derive(Default)]
+struct State {
+    // Running time of the first fragment of any rendition
+    origin: Option<gst::ClockTime>,
+    // End of the newest fragment of any rendition, events before it would miss some
+    newest: Option<gst::ClockTime>,
+    // End of the newest fragment of each running rendition, by its directory
+    renditions: HashMap<PathBuf, gst::ClockTime>,
+    // Events ending before the newest fragment of every rendition are dropped, their ids
+    // aren't reused
+    last_id: u32,
+    events: Vec<Event>,
+}
+
+/// Timed metadata shared by all fMP4 renditions, so every one of them carries the events at
+/// the same presentation time.
+///
+/// Each event goes into the fragment covering its time, once as ID3 tag for HLS and once
+/// with its DASH scheme. Subtitle renditions carry no events.
+#[derive(Default)]
+pub(crate) struct MetadataStore {
+    // Schemes DASH players are told about, the first one is the default
+    schemes: Vec<String>,
+    state: Mutex<State>,
+}
+
+impl MetadataStore {
+    pub fn new(schemes: Vec<String>) -> Self {
+        MetadataStore {
+            schemes,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Whether metadata can be injected at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.schemes.is_empty()
+    }
+
+    pub fn schemes(&self) -> &[String] {
+        &self.schemes
+    }
+
+    /// Schedules the event of `injection`.
+    ///
+    /// Fails for times some rendition already wrote the fragment of, as well as before the
+    /// first fragment.
+    pub fn inject(&self, injection: Injection) -> Result<Event, Error> {
+        let scheme_id_uri = match injection.scheme_id_uri {
+            Some(scheme) if self.schemes.contains(&scheme) => scheme,
+            Some(scheme) => bail!("unknown scheme '{}'", scheme),
+            None => self.schemes.first().cloned().ok_or_else(|| anyhow!("metadata disabled"))?,
+        };
+        if injection.duration.is_nan() || injection.duration < 0.0 {
+            bail!("duration can't be negative, got {}", injection.duration);
+        }
+        // The `emsg` boxes only have 32 bits for it
+        if injection.duration * TIMESCALE as f64 > u32::MAX as f64 {
+            bail!(
+                "duration can't exceed {:.0}s, got {}",
+                u32::MAX as f64 / TIMESCALE as f64,
+                injection.duration
+            );
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let (Some(origin), Some(newest)) = (state.origin, state.newest) else {
+            bail!("no fragment written yet");
+        };
+
+        let running_time = match injection.time {
+            Some(time) if time >= 0.0 => origin + utils::seconds_to_clock_time(time),
+            Some(time) => bail!("time can't be negative, got {}", time),
+            None => newest,
+        };
+        if running_time < newest {
+            bail!(
+                "time {:.3} was already written, the earliest possible is {:.3}",
+                utils::clock_time_to_seconds(running_time - origin),
+                utils::clock_time_to_seconds(newest - origin)
+            );
+        }
+
+        state.last_id += 1;
+        let event = Event {
+            id: state.last_id,
+            time: utils::clock_time_to_seconds(running_time - origin),
+            duration: injection.duration,
+            scheme_id_uri,
+            data: injection.data,
+            running_time,
+        };
+        state.events.push(event.clone());
+
+        Ok(event)
+    }
+
+    /// Handles a POST to [`METADATA_PATH`], answering with the scheduled event.
+    pub fn request(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let injection: Injection =
+            serde_json::from_slice(body).context("invalid metadata request")?;
+        let event = self.inject(injection)?;
+
+        Ok(serde_json::to_vec(&event)?)
+    }
+
+    /// The `emsg` boxes of the events within the fragment of the rendition written to
+    /// `path` from `time` on, lasting `duration`. No later events can go into the fragment
+    /// anymore.
+    pub fn fragment(
+        &self,
+        path: &Path,
+        time: gst::ClockTime,
+        duration: gst::ClockTime,
+    ) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        let end = time + duration;
+        state.origin.get_or_insert(time);
+        state.newest = state.newest.max(Some(end));
+        state.renditions.insert(path.to_path_buf(), end);
+
+        let mut boxes = vec![];
+        for event in &state.events {
+            if time <= event.running_time && event.running_time < end {
+                emsg(event, ID3_SCHEME_ID_URI, &id3_tag(&event.data)).write(&mut boxes);
+                emsg(event, &event.scheme_id_uri, event.data.as_bytes()).write(&mut boxes);
+            }
+        }
+        state.prune();
+
+        boxes
+    }
+
+    /// Called once the rendition written to `path` ended, the events no longer wait for it.
+    pub fn finish(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.renditions.remove(path);
+        state.prune();
+    }
+}
+
+impl State {
+    // Drops the events every rendition is past already
+    fn prune(&mut self) {
+        let Some(oldest) = self.renditions.values().min().copied() else {
+            return;
+        };
+
+        self.events.retain(|event| {
+            event.running_time + utils::seconds_to_clock_time(event.duration) >= oldest
+        });
+    }
+}
+
+// Version 1 `emsg`, with the presentation time on the media timeline, see ISO/IEC 23009-1
+// section 5.10.3.3
+fn emsg(event: &Event, scheme_id_uri: &str, message: &[u8]) -> Mp4Box {
+    let mut data = vec![];
+    data.extend_from_slice(&(TIMESCALE as u32).to_be_bytes());
+    data.extend_from_slice(&ticks(event.running_time).to_be_bytes());
+    let duration = ticks(utils::seconds_to_clock_time(event.duration)) as u32;
+    data.extend_from_slice(&duration.to_be_bytes());
+    data.extend_from_slice(&event.id.to_be_bytes());
+    data.extend_from_slice(scheme_id_uri.as_bytes());
+    data.push(0);
+    // No value
+    data.push(0);
+    data.extend_from_slice(message);
+
+    Mp4Box::full(b"emsg", 1, 0, &data)
+}
+
+// ID3v2.4 tag with `data` as the value of a single `TXXX` frame
+fn id3_tag(data: &str) -> Vec<u8> {
+    // UTF-8, then an empty description
+    let mut frame = vec![0x03, 0x00];
+    frame.extend_from_slice(data.as_bytes());
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(10 + frame.len() as u32));
+    tag.extend_from_slice(b"TXXX");
+    tag.extend_from_slice(&syncsafe(frame.len() as u32));
+    // No frame flags
+    tag.extend_from_slice(&[0, 0]);
+    tag.extend_from_slice(&frame);
+
+    tag
+}
+
+// Sizes in ID3 tags have seven bits per byte
+fn syncsafe(size: u32) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| ((size >> shift) as u8) & 0x7f)
+}
+
+fn ticks(time: gst::ClockTime) -> u64 {
+    (time.nseconds() as u128 * TIMESCALE as u128 / gst::ClockTime::SECOND.nseconds() as u128)
+        as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> MetadataStore {
+        let store = MetadataStore::new(vec!["urn:example:poll".to_string()]);
+        fragment(&store, "h264_0", 10);
+        store
+    }
+
+    // The 2s fragment of `rendition` from `time` on
+    fn fragment(store: &MetadataStore, rendition: &str, time: u64) -> Vec<u8> {
+        let duration = gst::ClockTime::from_seconds(2);
+        store.fragment(Path::new(rendition), gst::ClockTime::from_seconds(time), duration)
+    }
+
+    #[test]
+    fn schedules_events() {
+        let store = store();
+
+        // Right after the newest fragment
+        let event = store.request(br#"{"data": "poll"}"#).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&event).unwrap(),
+            serde_json::json!({
+                "id": 1,
+                "time": 2.0,
+                "duration": 0.0,
+                "scheme_id_uri": "urn:example:poll",
+            })
+        );
+
+        assert!(store.request(br#"{"data": "poll", "time": 1.0}"#).is_err());
+        assert!(store.request(br#"{"data": "poll", "scheme_id_uri": "urn:other"}"#).is_err());
+        assert!(MetadataStore::default().request(br#"{"data": "poll"}"#).is_err());
+        // Longer than 32 bits of 90kHz ticks
+        assert!(store.request(br#"{"data": "poll", "duration": 50000.0}"#).is_err());
+    }
+
+    #[test]
+    fn drops_events_every_rendition_is_past() {
+        let store = store();
+        fragment(&store, "h265_0", 10);
+        let injection = Injection {
+            data: "score".to_string(),
+            time: Some(3.0),
+            duration: 0.5,
+            scheme_id_uri: None,
+        };
+        store.inject(injection.clone()).unwrap();
+
+        assert!(!fragment(&store, "h264_0", 12).is_empty());
+        assert_eq!(store.state.lock().unwrap().events.len(), 1);
+        assert!(!fragment(&store, "h265_0", 12).is_empty());
+        assert!(store.state.lock().unwrap().events.is_empty());
+
+        // Ids aren't reused
+        let injection = Injection {
+            time: None,
+            ..injection
+        };
+        assert_eq!(store.inject(injection).unwrap().id, 2);
+    }
+
+    #[test]
+    fn writes_events_into_their_fragment() {
+        let store = store();
+        store
+            .inject(Injection {
+                data: "score".to_string(),
+                time: Some(3.0),
+                duration: 5.0,
+                scheme_id_uri: None,
+            })
+            .unwrap();
+
+        assert!(fragment(&store, "h264_0", 14).is_empty());
+
+        let data = fragment(&store, "h264_0", 12);
+        let boxes = Mp4Box::parse_all(&data).unwrap();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(&boxes[0].data[..4], &[1, 0, 0, 0]);
+        // Presentation time at 13s and a duration of 5s
+        assert_eq!(&boxes[0].data[8..16], &(13 * TIMESCALE).to_be_bytes());
+        assert_eq!(&boxes[0].data[16..20], &(5 * TIMESCALE as u32).to_be_bytes());
+
+        let id3 = &boxes[0].data[24 + ID3_SCHEME_ID_URI.len() + 2..];
+        assert_eq!(&id3[..10], b"ID3\x04\x00\x00\x00\x00\x00\x11");
+        assert!(id3.ends_with(b"TXXX\x00\x00\x00\x07\x00\x00\x03\x00score"));
+        assert!(boxes[1].data.ends_with(b"urn:example:poll\x00\x00score"));
+    }
+
+    #[test]
+    fn encodes_syncsafe_sizes() {
+        assert_eq!(syncsafe(0x7f), [0, 0, 0, 0x7f]);
+        assert_eq!(syncsafe(0x80), [0, 0, 1, 0]);
+        assert_eq!(syncsafe(0x0fff_ffff), [0x7f, 0x7f, 0x7f, 0x7f]);
+    }
+}
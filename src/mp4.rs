@@ -0,0 +1,324 @@
+use anyhow::{bail, Error};
+
+/// Four character code of a box.
+pub(crate) type FourCC = [u8; 4];
+
+/// An ISO BMFF box, parsed just deep enough to insert or rewrite the boxes encryption needs,
+/// or built from scratch for tracks no GStreamer muxer writes.
+///
+/// Boxes of known container types have their children parsed, everything else is kept as
+/// opaque payload. Sizes are recomputed when serializing, so boxes can be changed freely.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Mp4Box {
+    pub fourcc: FourCC,
+    /// Payload up to the children, or the whole payload of a leaf box
+    pub data: Vec<u8>,
+    pub children: Vec<Mp4Box>,
+}
+
+// Containers and the size of the fields they have before their children
+fn container_prefix(fourcc: &FourCC) -> Option<usize> {
+    match fourcc {
+        b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" | b"mvex" | b"moof" | b"traf"
+        | b"edts" | b"dinf" | b"sinf" | b"schi" => Some(0),
+        // Version, flags and entry count
+        b"stsd" => Some(8),
+        // SampleEntry and VisualSampleEntry fields
+        b"avc1" | b"avc3" | b"hvc1" | b"hev1" | b"av01" | b"encv" => Some(78),
+        // SampleEntry and AudioSampleEntry fields
+        b"mp4a" | b"enca" => Some(28),
+        _ => None,
+    }
+}
+
+impl Mp4Box {
+    pub fn new(fourcc: &FourCC, data: Vec<u8>) -> Self {
+        Mp4Box {
+            fourcc: *fourcc,
+            data,
+            children: vec![],
+        }
+    }
+
+    /// A box with version and flags in front of `data`.
+    pub fn full(fourcc: &FourCC, version: u8, flags: u32, data: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(4 + data.len());
+        payload.push(version);
+        payload.extend_from_slice(&flags.to_be_bytes()[1..]);
+        payload.extend_from_slice(data);
+
+        Mp4Box::new(fourcc, payload)
+    }
+
+    pub fn with_children(mut self, children: Vec<Mp4Box>) -> Self {
+        self.children = children;
+        self
+    }
+
+    /// Parses all top-level boxes of `data`.
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Mp4Box>, Error> {
+        let mut boxes = vec![];
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let (mp4_box, size) = Mp4Box::parse(&data[pos..])?;
+            boxes.push(mp4_box);
+            pos += size;
+        }
+
+        Ok(boxes)
+    }
+
+    // Returns the box at the start of `data` and its size
+    fn parse(data: &[u8]) -> Result<(Mp4Box, usize), Error> {
+        if data.len() < 8 {
+            bail!("truncated box header");
+        }
+
+        let mut size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        let fourcc: FourCC = data[4..8].try_into().unwrap();
+        let mut header = 8;
+
+        match size {
+            // Extends to the end
+            0 => size = data.len(),
+            1 => {
+                if data.len() < 16 {
+                    bail!("truncated large box header");
+                }
+                size = u64::from_be_bytes(data[8..16].try_into().unwrap()) as usize;
+                header = 16;
+            }
+            _ => (),
+        }
+
+        if size < header || size > data.len() {
+            bail!("invalid size {} of {} box", size, String::from_utf8_lossy(&fourcc));
+        }
+
+        let payload = &data[header..size];
+        let mp4_box = match container_prefix(&fourcc) {
+            Some(prefix) if prefix <= payload.len() => Mp4Box {
+                fourcc,
+                data: payload[..prefix].to_vec(),
+                children: Mp4Box::parse_all(&payload[prefix..])?,
+            },
+            _ => Mp4Box::new(&fourcc, payload.to_vec()),
+        };
+
+        Ok((mp4_box, size))
+    }
+
+    pub fn size(&self) -> usize {
+        8 + self.data.len() + self.children.iter().map(Mp4Box::size).sum::<usize>()
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.size() as u32).to_be_bytes());
+        out.extend_from_slice(&self.fourcc);
+        out.extend_from_slice(&self.data);
+        for child in &self.children {
+            child.write(out);
+        }
+    }
+
+    pub fn child(&self, fourcc: &FourCC) -> Option<&Mp4Box> {
+        self.children.iter().find(|child| &child.fourcc == fourcc)
+    }
+
+    pub fn child_mut(&mut self, fourcc: &FourCC) -> Option<&mut Mp4Box> {
+        self.children.iter_mut().find(|child| &child.fourcc == fourcc)
+    }
+
+    /// Follows `path` down from this box.
+    pub fn find_mut(&mut self, path: &[&FourCC]) -> Option<&mut Mp4Box> {
+        match path.split_first() {
+            None => Some(self),
+            Some((first, rest)) => self.child_mut(first)?.find_mut(rest),
+        }
+    }
+
+    /// Flags of a full box.
+    pub fn flags(&self) -> u32 {
+        u32::from_be_bytes([0, self.data[1], self.data[2], self.data[3]])
+    }
+
+    pub fn read_u32(&self, pos: usize) -> Option<u32> {
+        Some(u32::from_be_bytes(self.data.get(pos..pos + 4)?.try_into().unwrap()))
+    }
+
+    pub fn write_u32(&mut self, pos: usize, value: u32) {
+        self.data[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Serializes `boxes` back to back.
+pub(crate) fn write_all(boxes: &[Mp4Box]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(boxes.iter().map(Mp4Box::size).sum());
+    for mp4_box in boxes {
+        mp4_box.write(&mut out);
+    }
+
+    out
+}
+
+/// Position and size of each sample of a `traf`, relative to the start of its `moof`.
+///
+/// Only `default-base-is-moof` fragments as written by the GStreamer muxers are supported.
+pub(crate) fn sample_ranges(traf: &Mp4Box) -> Result<Vec<(usize, usize)>, Error> {
+    let Some(tfhd) = traf.child(b"tfhd") else {
+        bail!("traf without tfhd");
+    };
+
+    let tfhd_flags = tfhd.flags();
+    if tfhd_flags & 0x1 != 0 {
+        bail!("explicit base data offsets are not supported");
+    }
+
+    // Version/flags and track ID come first, then the optional fields in flag order
+    let mut pos = 8;
+    if tfhd_flags & 0x2 != 0 {
+        pos += 4;
+    }
+    if tfhd_flags & 0x8 != 0 {
+        pos += 4;
+    }
+    let default_size = if tfhd_flags & 0x10 != 0 { tfhd.read_u32(pos) } else { None };
+
+    let mut ranges = vec![];
+    for trun in traf.children.iter().filter(|child| &child.fourcc == b"trun") {
+        let flags = trun.flags();
+        let sample_count = trun.read_u32(4).unwrap_or(0);
+        let mut pos = 8;
+
+        let mut offset = if flags & 0x1 != 0 {
+            let offset = trun.read_u32(pos).unwrap_or(0) as i32;
+            pos += 4;
+            offset as usize
+        } else {
+            // Continues after the previous run
+            ranges.last().map_or(0, |(start, size)| start + size)
+        };
+        if flags & 0x4 != 0 {
+            pos += 4;
+        }
+
+        for _ in 0..sample_count {
+            if flags & 0x100 != 0 {
+                pos += 4;
+            }
+            let size = if flags & 0x200 != 0 {
+                let size = trun.read_u32(pos);
+                pos += 4;
+                size
+            } else {
+                default_size
+            };
+            if flags & 0x400 != 0 {
+                pos += 4;
+            }
+            if flags & 0x800 != 0 {
+                pos += 4;
+            }
+
+            let Some(size) = size else {
+                bail!("trun without sample sizes");
+            };
+            ranges.push((offset, size as usize));
+            offset += size as usize;
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// Start of the first `moof` of `data` and the length up to the end of its first sample, all a
+/// player needs to decode the keyframe a fragment starts with.
+pub(crate) fn first_sample_range(data: &[u8]) -> Result<(usize, usize), Error> {
+    let mut pos = 0;
+    for mp4_box in Mp4Box::parse_all(data)? {
+        if &mp4_box.fourcc != b"moof" {
+            pos += mp4_box.size();
+            continue;
+        }
+
+        let Some(traf) = mp4_box.child(b"traf") else {
+            bail!("moof without traf");
+        };
+        let Some((offset, size)) = sample_ranges(traf)?.first().copied() else {
+            bail!("fragment without samples");
+        };
+        return Ok((pos, offset + size));
+    }
+
+    bail!("no moof")
+}
+
+/// Moves the data offsets of all `trun`s of `moof` by `delta`, after boxes were added to it.
+pub(crate) fn shift_data_offsets(moof: &mut Mp4Box, delta: i32) {
+    for traf in moof.children.iter_mut().filter(|child| &child.fourcc == b"traf") {
+        for trun in traf.children.iter_mut().filter(|child| &child.fourcc == b"trun") {
+            if trun.flags() & 0x1 != 0 {
+                let offset = trun.read_u32(8).unwrap() as i32;
+                trun.write_u32(8, (offset + delta) as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trun(offset: i32, sizes: &[u32]) -> Mp4Box {
+        let mut data = (sizes.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&offset.to_be_bytes());
+        for size in sizes {
+            data.extend_from_slice(&size.to_be_bytes());
+        }
+
+        Mp4Box::full(b"trun", 0, 0x201, &data)
+    }
+
+    fn moof(sizes: &[u32]) -> Mp4Box {
+        let tfhd = Mp4Box::full(b"tfhd", 0, 0x20000, &1u32.to_be_bytes());
+        let mut traf = Mp4Box::new(b"traf", vec![]);
+        traf.children = vec![tfhd, trun(100, sizes)];
+
+        let mut moof = Mp4Box::new(b"moof", vec![]);
+        moof.children = vec![traf];
+        moof
+    }
+
+    #[test]
+    fn roundtrips_boxes() {
+        let mut data = vec![];
+        moof(&[10, 20]).write(&mut data);
+        Mp4Box::new(b"mdat", vec![0; 30]).write(&mut data);
+
+        let boxes = Mp4Box::parse_all(&data).unwrap();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(&boxes[0].children[0].fourcc, b"traf");
+        assert_eq!(write_all(&boxes), data);
+    }
+
+    #[test]
+    fn computes_sample_ranges() {
+        let mut moof = moof(&[10, 20]);
+        assert_eq!(sample_ranges(&moof.children[0]).unwrap(), vec![(100, 10), (110, 20)]);
+
+        shift_data_offsets(&mut moof, 8);
+        assert_eq!(sample_ranges(&moof.children[0]).unwrap(), vec![(108, 10), (118, 20)]);
+    }
+
+    #[test]
+    fn finds_first_sample() {
+        let mut data = vec![];
+        Mp4Box::new(b"styp", vec![0; 8]).write(&mut data);
+        moof(&[10, 20]).write(&mut data);
+        Mp4Box::new(b"mdat", vec![0; 30]).write(&mut data);
+
+        assert_eq!(first_sample_range(&data).unwrap(), (16, 110));
+        assert!(first_sample_range(&data[..16]).is_err());
+    }
+}
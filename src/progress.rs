@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A Partial Segment of a media playlist, ordered by Media Sequence Number first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Position {
+    pub msn: u64,
+    pub part: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Wait {
+    /// The playlist contains the requested position
+    Ready,
+    /// The request is too far into the future to ever be held
+    TooFarAhead,
+    TimedOut,
+}
+
+struct Entry {
+    // The newest part in the playlist
+    position: Position,
+    target_duration: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    playlists: HashMap<PathBuf, Entry>,
+    // Segments still being written, with the number of bytes written so far
+    files: HashMap<PathBuf, u64>,
+}
+
+/// Tracks how far the media playlists of each rendition got, so the HTTP server can hold
+/// blocking playlist reloads until the requested part is there.
+///
+/// It also tracks the segments that are still being written in Low-Latency mode, so the
+/// server can send them with chunked transfer encoding while they grow.
+#[derive(Default)]
+pub(crate) struct Progress {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Progress {
+    /// Called whenever the playlist at `path` was rewritten.
+    pub fn update(&self, path: &Path, position: Position, target_duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.playlists.insert(
+            path.to_path_buf(),
+            Entry {
+                position,
+                target_duration,
+            },
+        );
+        self.cond.notify_all();
+    }
+
+    /// Holds the caller until the playlist at `path` contains `position`, as required for
+    /// `_HLS_msn`/`_HLS_part` requests.
+    ///
+    /// Playlists not written by us are treated as ready and served as they are.
+    pub fn wait_for(&self, path: &Path, position: Position) -> Wait {
+        let mut state = self.state.lock().unwrap();
+        let Some(entry) = state.playlists.get(path) else {
+            return Wait::Ready;
+        };
+
+        // The spec allows to refuse anything beyond the next two segments right away and
+        // to give up after three target durations.
+        if position.msn > entry.position.msn + 2 {
+            return Wait::TooFarAhead;
+        }
+        let deadline = Instant::now() + entry.target_duration * 3;
+
+        loop {
+            match state.playlists.get(path) {
+                Some(entry) if entry.position >= position => return Wait::Ready,
+                None => return Wait::Ready,
+                _ => (),
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Wait::TimedOut;
+            }
+
+            state = self.cond.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Called whenever data was appended to the segment at `path`, `written` being its size.
+    pub fn write_file(&self, path: &Path, written: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(path.to_path_buf(), written);
+        self.cond.notify_all();
+    }
+
+    /// Called once the segment at `path` is complete.
+    pub fn finish_file(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.files.remove(path);
+        self.cond.notify_all();
+    }
+
+    pub fn is_writing(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().files.contains_key(path)
+    }
+
+    /// Holds the caller until more than `offset` bytes of the segment at `path` are written.
+    ///
+    /// Returns `false` once the segment is complete, or if nothing was appended for longer
+    /// than `timeout`.
+    pub fn wait_for_data(&self, path: &Path, offset: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            match state.files.get(path) {
+                Some(written) if *written > offset => return true,
+                None => return false,
+                _ => (),
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return false;
+            }
+
+            state = self.cond.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use gst::prelude::*;
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+
+use crate::{encryption, hlscmaf, utils};
+
+/// Scheme of the DASH events carrying the binary splice info sections.
+pub(crate) const SCHEME_ID_URI: &str = "urn:scte:scte35:2014:xml+bin";
+// Of the times in the splice info sections
+const TIMESCALE: u64 = 90_000;
+// `splice_insert()`
+const SPLICE_INSERT: u8 = 0x05;
+
+/// An ad break, relative to the first frame of the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdBreak {
+    /// Seconds from the first frame to the start of the break
+    pub start: f64,
+    /// Length of the break, in seconds
+    pub duration: f64,
+}
+
+impl FromStr for AdBreak {
+    type Err = Error;
+
+    // Parses `start=60,duration=30`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ad_break = AdBreak::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "start" => ad_break.start = value.parse()?,
+                "duration" => ad_break.duration = value.parse()?,
+                _ => return Err(anyhow!("unknown ad break property '{}'", key)),
+            }
+        }
+
+        Ok(ad_break)
+    }
+}
+
+/// An ad break with its splice points moved to segment boundaries, so every rendition cuts
+/// its segments right there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Splice {
+    /// `splice_event_id`, also identifies the date ranges
+    pub id: u32,
+    /// Running time from the origin of the output to the splice out of the network
+    pub out: gst::ClockTime,
+    pub duration: gst::ClockTime,
+}
+
+impl Splice {
+    /// The splices of `ad_breaks`, at the segment boundaries closest to the configured times.
+    /// Breaks are at least one segment long.
+    pub fn schedule(ad_breaks: &[AdBreak], segment_duration: gst::ClockTime) -> Vec<Splice> {
+        let segments = |seconds: f64| {
+            let count = (seconds / utils::clock_time_to_seconds(segment_duration)).round() as u64;
+            gst::ClockTime::from_nseconds(segment_duration.nseconds() * count)
+        };
+
+        ad_breaks
+            .iter()
+            .enumerate()
+            .map(|(idx, ad_break)| Splice {
+                id: idx as u32 + 1,
+                out: segments(ad_break.start),
+                duration: segments(ad_break.duration).max(segment_duration),
+            })
+            .collect()
+    }
+
+    /// Running time from the origin of the output to the splice back into the network.
+    pub fn back_in(&self) -> gst::ClockTime {
+        self.out + self.duration
+    }
+
+    /// The splice info section going out of the network at media time `time`.
+    pub fn out_section(&self, time: gst::ClockTime) -> Vec<u8> {
+        splice_info_section(self.id, true, time, Some(self.duration))
+    }
+
+    /// The splice info section going back into the network at media time `time`.
+    pub fn in_section(&self, time: gst::ClockTime) -> Vec<u8> {
+        splice_info_section(self.id, false, time, None)
+    }
+}
+
+/// `0x` and the hex digits of a splice info section, as in the `SCTE35-OUT` and `SCTE35-IN`
+/// attributes of HLS.
+pub(crate) fn hex(section: &[u8]) -> String {
+    format!("0x{}", encryption::hex(section).to_uppercase())
+}
+
+/// Makes `enc` put keyframes at the splice points, the muxers then start new segments
+/// there.
+///
+/// The splice points are requested all at once as soon as the running time of the first
+/// frame is known. They are relative to the `origin` of the output, so encoders set up later
+/// on cut at the same points, except for those already past.
+pub(crate) fn force_keyframes(
+    enc: &gst::Element,
+    splices: &[Splice],
+    origin: &Arc<hlscmaf::Origin>,
+) {
+    if splices.is_empty() {
+        return;
+    }
+
+    let points =
+        splices.iter().flat_map(|splice| [splice.out, splice.back_in()]).collect::<Vec<_>>();
+    let origin = origin.clone();
+    enc.static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(time) = utils::running_time(pad, buffer) else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        let enc = pad.parent_element().unwrap();
+        let (start, _) = origin.anchor(&enc, time);
+        let srcpad = enc.static_pad("src").unwrap();
+        for point in points.iter().map(|point| start + *point).filter(|point| *point >= time) {
+            let event = gst_video::UpstreamForceKeyUnitEvent::builder()
+                .running_time(point)
+                .all_headers(true)
+                .build();
+            srcpad.send_event(event);
+        }
+
+        gst::PadProbeReturn::Remove
+    });
+}
+
+// A `splice_insert()` splicing out of or back into the network at `time`, see SCTE 35
+// section 9.
+fn splice_info_section(
+    id: u32,
+    out_of_network: bool,
+    time: gst::ClockTime,
+    duration: Option<gst::ClockTime>,
+) -> Vec<u8> {
+    let mut command = id.to_be_bytes().to_vec();
+    // Not cancelled
+    command.push(0x7f);
+    // Splice of the whole program at a given time
+    command.push(((out_of_network as u8) << 7) | 0x40 | ((duration.is_some() as u8) << 5) | 0x0f);
+    command.extend_from_slice(&time_field(time));
+    if let Some(duration) = duration {
+        // With auto return
+        command.extend_from_slice(&time_field(duration));
+    }
+    // Unique program ID, avail number and expected avails
+    command.extend_from_slice(&[0, 0, 0, 0]);
+
+    // Length filled in below, then protocol version, no encryption and no PTS adjustment,
+    // control word index, and tier with the length of the command
+    let mut section = vec![0xfc, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    section.extend_from_slice(&[0xff, 0xf0 | (command.len() >> 8) as u8, command.len() as u8]);
+    section.push(SPLICE_INSERT);
+    section.extend_from_slice(&command);
+    // No descriptors
+    section.extend_from_slice(&[0, 0]);
+
+    // Everything after the length, including the CRC
+    let length = section.len() - 3 + 4;
+    section[1] = 0x30 | (length >> 8) as u8;
+    section[2] = length as u8;
+
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+// 33 bits of 90kHz ticks behind a set flag and reserved bits, used for both `splice_time()`
+// and `break_duration()`
+fn time_field(time: gst::ClockTime) -> [u8; 5] {
+    let ticks = (time.nseconds() as u128 * TIMESCALE as u128
+        / gst::ClockTime::SECOND.nseconds() as u128) as u64
+        & 0x1_ffff_ffff;
+    let low = (ticks as u32).to_be_bytes();
+
+    [0xfe | (ticks >> 32) as u8, low[0], low[1], low[2], low[3]]
+}
+
+// CRC-32/MPEG-2
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xffff_ffff, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_mpeg_crc() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn writes_splice_inserts() {
+        let splice = Splice {
+            id: 7,
+            out: gst::ClockTime::from_seconds(60),
+            duration: gst::ClockTime::from_seconds(30),
+        };
+
+        let out = splice.out_section(gst::ClockTime::from_seconds(61));
+        assert_eq!(out.len(), 40);
+        assert_eq!(&out[..3], &[0xfc, 0x30, 37]);
+        assert_eq!(out[13], SPLICE_INSERT);
+        assert_eq!(&out[14..18], &7u32.to_be_bytes());
+        // Out of the network, with a duration
+        assert_eq!(out[19], 0xef);
+        assert_eq!(&out[20..25], &[0xfe, 0x00, 0x53, 0xc5, 0x50]);
+        assert_eq!(&out[25..30], &[0xfe, 0x00, 0x29, 0x32, 0xe0]);
+        // The CRC of a section including its CRC is zero
+        assert_eq!(crc32(&out), 0);
+
+        let back_in = splice.in_section(gst::ClockTime::from_seconds(91));
+        assert_eq!(back_in.len(), 35);
+        assert_eq!(back_in[19], 0x4f);
+        assert_eq!(crc32(&back_in), 0);
+    }
+
+    #[test]
+    fn moves_splices_to_segment_boundaries() {
+        let ad_breaks = ["start=61,duration=29", "start=120,duration=0.5"]
+            .iter()
+            .map(|spec| spec.parse().unwrap())
+            .collect::<Vec<AdBreak>>();
+
+        let splices = Splice::schedule(&ad_breaks, gst::ClockTime::from_seconds(4));
+        assert_eq!(splices[0].out, gst::ClockTime::from_seconds(60));
+        assert_eq!(splices[0].back_in(), gst::ClockTime::from_seconds(88));
+        assert_eq!(splices[1].id, 2);
+        assert_eq!(splices[1].duration, gst::ClockTime::from_seconds(4));
+    }
+}
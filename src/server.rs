@@ -0,0 +1,416 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    encryption::{KeyStore, LICENSE_PATH},
+    metadata::{MetadataStore, METADATA_PATH},
+    progress::{Position, Progress, Wait},
+};
+
+use anyhow::{anyhow, Error};
+use log::{debug, info};
+use tiny_http::{Header, Method, Request, Response, ResponseBox, StatusCode};
+
+// Playlists change with every segment and every other name, `segment_0` included, is written
+// again by the next run, so nothing is cached for longer than a playlist refresh
+const CACHE_CONTROL: &str = "max-age=1";
+// Keys shouldn't end up in shared caches
+const KEY_CACHE_CONTROL: &str = "private, no-store";
+// Keys are served below this path instead of the output directory
+const KEY_PREFIX: &str = "/keys/";
+// Gives up on a segment that stopped growing, e.g. because the stream was stopped
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+// License requests and metadata events are far smaller, anything larger is refused
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Embedded HTTP/1.1 origin serving the output directory.
+pub(crate) struct Server {
+    root: PathBuf,
+    // Served for `/`
+    index: String,
+    progress: Arc<Progress>,
+    keys: Arc<KeyStore>,
+    metadata: Arc<MetadataStore>,
+}
+
+impl Server {
+    /// Starts listening on `address` and serves `root` from a background thread, one thread
+    /// per request.
+    ///
+    /// Blocking playlist reloads wait on `progress` for the requested part. The keys of
+    /// encrypted segments are looked up in `keys`, injected metadata goes to `metadata`.
+    pub fn spawn(
+        address: &str,
+        root: &Path,
+        index: &str,
+        progress: Arc<Progress>,
+        keys: Arc<KeyStore>,
+        metadata: Arc<MetadataStore>,
+    ) -> Result<(), Error> {
+        let server = tiny_http::Server::http(address)
+            .map_err(|err| anyhow!("failed to listen on {}: {}", address, err))?;
+        info!("serving {} on http://{}", root.display(), server.server_addr());
+
+        let handler = Arc::new(Server {
+            root: root.into(),
+            index: index.into(),
+            progress,
+            keys,
+            metadata,
+        });
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let handler = handler.clone();
+                std::thread::spawn(move || handler.handle(request));
+            }
+        });
+
+        Ok(())
+    }
+
+    fn handle(&self, mut request: Request) {
+        debug!("{} {}", request.method(), request.url());
+
+        let response = match request.method().clone() {
+            Method::Get | Method::Head if request.url().starts_with(KEY_PREFIX) => {
+                self.serve_key(request.url())
+            }
+            Method::Get | Method::Head => {
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .map(|header| header.value.to_string());
+                self.serve_file(request.url(), range.as_deref())
+            }
+            Method::Post if request.url() == LICENSE_PATH => self.serve_license(&mut request),
+            Method::Post if request.url() == METADATA_PATH && self.metadata.is_enabled() => {
+                self.inject_metadata(&mut request)
+            }
+            // CORS preflight
+            Method::Options => Response::empty(204).boxed(),
+            _ => Response::empty(405).boxed(),
+        };
+
+        let response = with_cors(response);
+        if let Err(err) = request.respond(response) {
+            debug!("failed to send response: {}", err);
+        }
+    }
+
+    // Complete files can be requested partially, single file renditions are only ever
+    // fetched by byte range
+    fn serve_file(&self, url: &str, range: Option<&str>) -> ResponseBox {
+        let Some(path) = self.resolve(url) else {
+            return Response::empty(404).boxed();
+        };
+
+        if let Some(position) = blocking_reload(url) {
+            match self.progress.wait_for(&path, position) {
+                Wait::Ready => (),
+                Wait::TooFarAhead => return Response::empty(400).boxed(),
+                Wait::TimedOut => return Response::empty(503).boxed(),
+            }
+        }
+
+        // Segments still being written are sent as they grow, chunk by chunk
+        if self.progress.is_writing(&path) {
+            if let Ok(file) = File::open(&path) {
+                let reader = GrowingFile {
+                    file,
+                    path: path.clone(),
+                    offset: 0,
+                    progress: self.progress.clone(),
+                };
+                let headers = vec![
+                    header("Content-Type", content_type(&path)),
+                    header("Cache-Control", CACHE_CONTROL),
+                ];
+                // Without a length tiny_http uses chunked transfer encoding
+                return Response::new(StatusCode(200), headers, reader, None, None).boxed();
+            }
+        }
+
+        let Ok(mut file) = File::open(&path) else {
+            return Response::empty(404).boxed();
+        };
+        // Anything appended meanwhile isn't sent, the length has to match
+        let Ok(len) = file.metadata().map(|metadata| metadata.len()) else {
+            return Response::empty(404).boxed();
+        };
+        let mut headers = vec![
+            header("Content-Type", content_type(&path)),
+            header("Cache-Control", CACHE_CONTROL),
+            header("Accept-Ranges", "bytes"),
+        ];
+
+        match range.and_then(|range| byte_range(range, len)) {
+            None => {
+                Response::new(StatusCode(200), headers, file.take(len), Some(len as usize), None)
+                    .boxed()
+            }
+            Some((first, last)) if first <= last && first < len => {
+                if file.seek(SeekFrom::Start(first)).is_err() {
+                    return Response::empty(404).boxed();
+                }
+                let length = last - first + 1;
+                headers.push(header("Content-Range", &format!("bytes {}-{}/{}", first, last, len)));
+                let reader = file.take(length);
+                Response::new(StatusCode(206), headers, reader, Some(length as usize), None).boxed()
+            }
+            Some(_) => Response::empty(416)
+                .with_header(header("Content-Range", &format!("bytes */{}", len)))
+                .boxed(),
+        }
+    }
+
+    fn serve_key(&self, url: &str) -> ResponseBox {
+        let kid = url.trim_start_matches(KEY_PREFIX);
+        let kid = kid.split(['?', '#']).next().unwrap_or_default();
+
+        match self.keys.get(kid) {
+            Some(key) => Response::from_data(key.to_vec())
+                .with_header(header("Content-Type", "application/octet-stream"))
+                .with_header(header("Cache-Control", KEY_CACHE_CONTROL))
+                .boxed(),
+            None => Response::empty(404).boxed(),
+        }
+    }
+
+    // Clear Key license requests of the EME
+    fn serve_license(&self, request: &mut Request) -> ResponseBox {
+        let body = match read_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        match self.keys.license(&body) {
+            Ok(license) => Response::from_data(license)
+                .with_header(header("Content-Type", "application/json"))
+                .with_header(header("Cache-Control", KEY_CACHE_CONTROL))
+                .boxed(),
+            Err(err) => {
+                debug!("refusing license request: {:#}", err);
+                Response::empty(400).boxed()
+            }
+        }
+    }
+
+    // Timed metadata for the upcoming segments, the error tells what's wrong with the event
+    fn inject_metadata(&self, request: &mut Request) -> ResponseBox {
+        let body = match read_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        match self.metadata.request(&body) {
+            Ok(event) => Response::from_data(event)
+                .with_header(header("Content-Type", "application/json"))
+                .boxed(),
+            Err(err) => {
+                debug!("refusing metadata: {:#}", err);
+                Response::from_string(format!("{:#}", err)).with_status_code(400).boxed()
+            }
+        }
+    }
+
+    // Maps the URL to a file below the root, refusing anything that would escape it as well
+    // as the temporary files playlists are written through
+    fn resolve(&self, url: &str) -> Option<PathBuf> {
+        let path = percent_decode(url.split(['?', '#']).next().unwrap_or_default())?;
+        let path = Path::new(path.trim_start_matches('/'));
+
+        if path.as_os_str().is_empty() {
+            return Some(self.root.join(&self.index));
+        }
+
+        if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            return None;
+        }
+
+        Some(self.root.join(path))
+    }
+}
+
+// Reads a segment until the packager finished writing it, waiting for each new chunk when
+// reaching the end of what's there so far.
+struct GrowingFile {
+    file: File,
+    path: PathBuf,
+    offset: u64,
+    progress: Arc<Progress>,
+}
+
+impl Read for GrowingFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 {
+                self.offset += read as u64;
+                return Ok(read);
+            }
+
+            if !self.progress.wait_for_data(&self.path, self.offset, CHUNK_TIMEOUT) {
+                // Whatever was appended right before the segment was finished
+                let read = self.file.read(buf)?;
+                self.offset += read as u64;
+                return Ok(read);
+            }
+        }
+    }
+}
+
+// The part a Low-Latency HLS client asks the playlist to contain with `_HLS_msn` and
+// `_HLS_part`. Without `_HLS_part` the whole segment has to be complete, i.e. the playlist has
+// to list the first part of the following one.
+fn blocking_reload(url: &str) -> Option<Position> {
+    let (_, query) = url.split_once('?')?;
+
+    let mut msn = None;
+    let mut part = None;
+    for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        match key {
+            "_HLS_msn" => msn = value.parse::<u64>().ok(),
+            "_HLS_part" => part = value.parse::<u64>().ok(),
+            _ => (),
+        }
+    }
+
+    match (msn?, part) {
+        (msn, Some(part)) => Some(Position { msn, part }),
+        (msn, None) => Some(Position { msn: msn + 1, part: 0 }),
+    }
+}
+
+// The body of a POST request, or the response refusing it when it can't be read or is larger
+// than `MAX_BODY_SIZE`
+fn read_body(request: &mut Request) -> Result<Vec<u8>, ResponseBox> {
+    let mut body = vec![];
+    if request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut body).is_err() {
+        return Err(Response::empty(400).boxed());
+    }
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err(Response::empty(413).boxed());
+    }
+
+    Ok(body)
+}
+
+// The first and last byte of a single range `bytes=first-last`, `bytes=first-` or `bytes=-suffix`,
+// with the last one capped at the end of the file. `None` when the header doesn't ask for a
+// single byte range, the whole file is sent then.
+fn byte_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    let (first, last) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let end = len.saturating_sub(1);
+
+    match (first.trim(), last.trim()) {
+        ("", suffix) => Some((len.saturating_sub(suffix.parse().ok()?), end)),
+        (first, "") => Some((first.parse().ok()?, end)),
+        (first, last) => Some((first.parse().ok()?, last.parse::<u64>().ok()?.min(end))),
+    }
+}
+
+// Decodes the `%XX` escapes of a URL path, `None` if they don't make up valid UTF-8
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("mpd") => "application/dash+xml",
+        Some("mp4") | Some("fmp4") | Some("m4s") => "video/mp4",
+        Some("ts") => "video/mp2t",
+        // Interstitial asset lists
+        Some("json") => "application/json",
+        // Thumbnail sprites
+        Some("jpg") => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn with_cors(response: ResponseBox) -> ResponseBox {
+    response
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+        .with_header(header("Access-Control-Allow-Methods", "GET, HEAD, POST, OPTIONS"))
+        .with_header(header("Access-Control-Allow-Headers", "*"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_blocking_reload() {
+        assert_eq!(blocking_reload("/h264_0/manifest.m3u8"), None);
+        assert_eq!(blocking_reload("/h264_0/manifest.m3u8?_HLS_part=2"), None);
+        assert_eq!(
+            blocking_reload("/h264_0/manifest.m3u8?_HLS_msn=10&_HLS_part=2"),
+            Some(Position { msn: 10, part: 2 })
+        );
+        assert_eq!(
+            blocking_reload("/h264_0/manifest.m3u8?_HLS_msn=10"),
+            Some(Position { msn: 11, part: 0 })
+        );
+    }
+
+    #[test]
+    fn resolves_urls_below_root() {
+        let server = Server {
+            root: PathBuf::from("/srv"),
+            index: "manifest.m3u8".to_string(),
+            progress: Default::default(),
+            keys: Default::default(),
+            metadata: Default::default(),
+        };
+
+        assert_eq!(server.resolve("/"), Some(PathBuf::from("/srv/manifest.m3u8")));
+        assert_eq!(
+            server.resolve("/h264%5F0/segment_1.fmp4?x=1"),
+            Some(PathBuf::from("/srv/h264_0/segment_1.fmp4"))
+        );
+        assert_eq!(server.resolve("/%2e%2e/etc/passwd"), None);
+        assert_eq!(server.resolve("/h264_0/manifest.m3u8.tmp"), None);
+        assert_eq!(server.resolve("/h264_0/%zz"), None);
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(byte_range("bytes=100-199", 1000), Some((100, 199)));
+        assert_eq!(byte_range("bytes=900-1999", 1000), Some((900, 999)));
+        assert_eq!(byte_range("bytes=100-", 1000), Some((100, 999)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(byte_range("bytes=-2000", 1000), Some((0, 999)));
+        // Not satisfiable
+        assert_eq!(byte_range("bytes=1000-", 1000), Some((1000, 999)));
+        // Served whole
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+        assert_eq!(byte_range("bytes=-", 1000), None);
+    }
+}
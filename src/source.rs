@@ -0,0 +1,322 @@
+use gst::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Error};
+use log::{info, warn};
+
+/// Name of the application message posted on the bus once a URI input exposed all its
+/// streams and can be rewound for looping.
+pub(crate) const SOURCE_READY: &str = "yatta-source-ready";
+/// Name of the application message posted on the bus once a seek of the URI input went
+/// through or failed for good. It carries whether it was the first `rewind` and the `error`
+/// if it failed.
+pub(crate) const SEEK_DONE: &str = "yatta-seek-done";
+// A decoder busy with something else occasionally refuses a seek, it gets a few more tries
+const SEEK_ATTEMPTS: u32 = 3;
+const SEEK_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where the content of all renditions comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Input {
+    /// `videotestsrc` and one `audiotestsrc` per wave
+    Test,
+    /// A file or URI decoded with `uridecodebin` and looped forever
+    Uri(String),
+}
+
+impl Input {
+    /// Anything with a scheme is taken as a URI, everything else as a local path.
+    pub fn parse(input: Option<&str>) -> Result<Self, Error> {
+        let Some(input) = input else {
+            return Ok(Input::Test);
+        };
+
+        if input.contains("://") {
+            return Ok(Input::Uri(input.to_string()));
+        }
+
+        let path = std::fs::canonicalize(input)
+            .with_context(|| format!("can't open input {}", input))?;
+        let uri = gst::glib::filename_to_uri(&path, None)
+            .with_context(|| format!("can't convert {} to a URI", path.display()))?;
+
+        Ok(Input::Uri(uri.to_string()))
+    }
+}
+
+/// The live sources shared by all renditions.
+///
+/// There is a single video source and one audio source per wave, each followed by a `tee`.
+/// Every rendition requests a pad from the `tee` and scales or resamples from there, so all
+/// renditions of the ladder carry the very same frames.
+pub(crate) struct Source {
+    pipeline: gst::Pipeline,
+    input: Input,
+    width: u64,
+    height: u64,
+    video_tee: Option<gst::Element>,
+    audio_tees: HashMap<String, gst::Element>,
+    // The decoded pad of a URI input the looping seeks are sent to
+    seek_pad: Arc<Mutex<Option<gst::Pad>>>,
+}
+
+impl Source {
+    /// `width` and `height` are the resolution of the shared video, renditions scale down
+    /// from it.
+    pub fn new(pipeline: &gst::Pipeline, input: Input, width: u64, height: u64) -> Self {
+        Source {
+            pipeline: pipeline.clone(),
+            input,
+            width,
+            height,
+            video_tee: None,
+            audio_tees: HashMap::new(),
+            seek_pad: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Resolution of the shared video, as set up at the start.
+    pub fn resolution(&self) -> (u64, u64) {
+        (self.width, self.height)
+    }
+
+    /// A URI input has to be rewound with a segment seek in PAUSED before it can play, see
+    /// [`Source::start_looping`]. Test sources can go to PLAYING right away.
+    pub fn needs_preroll(&self) -> bool {
+        matches!(self.input, Input::Uri(_))
+    }
+
+    /// Requests a new branch of the shared video, creating the source on first use.
+    pub fn video_pad(&mut self) -> Result<gst::Pad, Error> {
+        if self.video_tee.is_none() {
+            match self.input.clone() {
+                Input::Test => {
+                    let src = gst::ElementFactory::make("videotestsrc")
+                        .property("is-live", true)
+                        .build()?;
+                    let capsfilter = self.video_capsfilter()?;
+
+                    self.video_tee = Some(self.add_source(&[src, capsfilter])?);
+                }
+                Input::Uri(uri) => self.add_uri_source(&uri)?,
+            }
+        }
+
+        request_pad(self.video_tee.as_ref().unwrap())
+    }
+
+    /// Requests a new branch of the shared audio with the given wave, creating the source on
+    /// first use. The wave is meaningless for a URI input, all renditions share its audio.
+    pub fn audio_pad(&mut self, wave: &str) -> Result<gst::Pad, Error> {
+        let key = match self.input {
+            Input::Test => wave,
+            Input::Uri(_) => "",
+        };
+
+        if !self.audio_tees.contains_key(key) {
+            match self.input.clone() {
+                Input::Test => {
+                    let src = gst::ElementFactory::make("audiotestsrc")
+                        .property("is-live", true)
+                        .property_from_str("wave", wave)
+                        .build()?;
+
+                    let tee = self.add_source(&[src])?;
+                    self.audio_tees.insert(key.to_string(), tee);
+                }
+                Input::Uri(uri) => self.add_uri_source(&uri)?,
+            }
+        }
+
+        request_pad(&self.audio_tees[key])
+    }
+
+    /// Rewinds the URI input with a flushing segment seek. Segment seeks make the demuxer
+    /// post SEGMENT_DONE instead of pushing EOS at the end, which is what allows looping.
+    ///
+    /// The outcome is posted as [`SEEK_DONE`].
+    pub fn start_looping(&self) {
+        self.seek(gst::SeekFlags::FLUSH | gst::SeekFlags::SEGMENT, true);
+    }
+
+    /// Called on SEGMENT_DONE to play the URI input again from the start.
+    ///
+    /// The seek is non-flushing, so the new segment is queued right after the previous one
+    /// and its running time, and thereby every timestamp downstream, keeps increasing. The
+    /// outcome is posted as [`SEEK_DONE`].
+    pub fn loop_input(&self) {
+        info!("input finished, looping");
+        self.seek(gst::SeekFlags::SEGMENT, false);
+    }
+
+    // Seeks from its own thread, the retries would hold up signals and reloads on the bus
+    // thread otherwise
+    fn seek(&self, flags: gst::SeekFlags, rewind: bool) {
+        let seek_pad = self.seek_pad.clone();
+        let bus = self.pipeline.bus().expect("Pipeline without bus. Shouldn't happen!");
+
+        std::thread::spawn(move || {
+            let mut attempt = 1;
+            let result = loop {
+                match try_seek(&seek_pad, flags) {
+                    Err(err) if attempt < SEEK_ATTEMPTS => {
+                        warn!("seeking the input failed, retrying: {:#}", err);
+                        std::thread::sleep(SEEK_RETRY_DELAY);
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            };
+
+            let mut done = gst::Structure::builder(SEEK_DONE).field("rewind", rewind);
+            if let Err(err) = result {
+                done = done.field("error", format!("{:#}", err));
+            }
+            let _ = bus.post(gst::message::Application::new(done.build()));
+        });
+    }
+
+    fn video_capsfilter(&self) -> Result<gst::Element, Error> {
+        Ok(gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                gst_video::VideoCapsBuilder::new()
+                    .format(gst_video::VideoFormat::I420)
+                    .width(self.width as i32)
+                    .height(self.height as i32)
+                    .framerate(crate::video::FRAMERATE.into())
+                    .build(),
+            )
+            .build()?)
+    }
+
+    // Creates both the video and the audio tee, whichever the input actually has
+    fn add_uri_source(&mut self, uri: &str) -> Result<(), Error> {
+        let decodebin = gst::ElementFactory::make("uridecodebin")
+            .property("uri", uri)
+            .build()?;
+
+        // The input isn't live, `clocksync` paces it to the clock as if it was
+        let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+        let videoscale = gst::ElementFactory::make("videoscale").build()?;
+        let videorate = gst::ElementFactory::make("videorate").build()?;
+        let capsfilter = self.video_capsfilter()?;
+        let video_sync = gst::ElementFactory::make("clocksync").build()?;
+        let video_sink = videoconvert.static_pad("sink").unwrap();
+        let video_tee =
+            self.add_source(&[videoconvert, videoscale, videorate, capsfilter, video_sync])?;
+
+        let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+        let audioresample = gst::ElementFactory::make("audioresample").build()?;
+        let audio_sync = gst::ElementFactory::make("clocksync").build()?;
+        let audio_sink = audioconvert.static_pad("sink").unwrap();
+        let audio_tee = self.add_source(&[audioconvert, audioresample, audio_sync])?;
+
+        let video_input = video_sink.clone();
+        let audio_input = audio_sink.clone();
+        let seek_pad = self.seek_pad.clone();
+        decodebin.connect_pad_added(move |_decodebin, pad| {
+            let Some(caps) = pad.current_caps() else { return };
+            let name = caps.structure(0).unwrap().name();
+
+            let sinkpad = if name.starts_with("video/") {
+                &video_sink
+            } else if name.starts_with("audio/") {
+                &audio_sink
+            } else {
+                return;
+            };
+
+            // Only the first stream of each type is used
+            if sinkpad.is_linked() {
+                return;
+            }
+
+            if let Err(err) = pad.link(sinkpad) {
+                warn!("failed to link {} stream of the input: {}", name, err);
+                return;
+            }
+
+            seek_pad.lock().unwrap().get_or_insert_with(|| pad.clone());
+        });
+
+        let video_branches = video_tee.clone();
+        let audio_branches = audio_tee.clone();
+        decodebin.connect_no_more_pads(move |decodebin| {
+            // Renditions of a type the input lacks would never write a single segment
+            let missing = [
+                ("video", &video_input, &video_branches),
+                ("audio", &audio_input, &audio_branches),
+            ]
+            .into_iter()
+            .find(|(_, input, tee)| !input.is_linked() && tee.num_src_pads() > 0);
+            if let Some((kind, _, _)) = missing {
+                gst::element_error!(
+                    decodebin,
+                    gst::StreamError::Demux,
+                    ("input has no {} stream for the {} renditions", kind, kind)
+                );
+                return;
+            }
+
+            let msg = gst::message::Application::new(gst::Structure::new_empty(SOURCE_READY));
+            let _ = decodebin.post_message(msg);
+        });
+
+        self.pipeline.add(&decodebin)?;
+        decodebin.sync_state_with_parent()?;
+
+        self.video_tee = Some(video_tee);
+        self.audio_tees.insert(String::new(), audio_tee);
+
+        Ok(())
+    }
+
+    fn add_source(&self, elements: &[gst::Element]) -> Result<gst::Element, Error> {
+        // Renditions come and go at runtime, the source keeps running without any
+        let tee = gst::ElementFactory::make("tee")
+            .property("allow-not-linked", true)
+            .build()?;
+
+        self.pipeline.add_many(elements)?;
+        self.pipeline.add(&tee)?;
+        gst::Element::link_many(elements.iter().chain(std::iter::once(&tee)))?;
+
+        tee.sync_state_with_parent()?;
+        for element in elements.iter().rev() {
+            element.sync_state_with_parent()?;
+        }
+
+        Ok(tee)
+    }
+}
+
+fn try_seek(seek_pad: &Mutex<Option<gst::Pad>>, flags: gst::SeekFlags) -> Result<(), Error> {
+    let seek_pad = seek_pad.lock().unwrap();
+    let Some(ref pad) = *seek_pad else {
+        return Err(anyhow!("input has no decoded streams to seek"));
+    };
+
+    let seek = gst::event::Seek::new(
+        1.0,
+        flags,
+        gst::SeekType::Set,
+        gst::ClockTime::ZERO,
+        gst::SeekType::None,
+        gst::ClockTime::NONE,
+    );
+    if !pad.send_event(seek) {
+        return Err(anyhow!("input refused to seek"));
+    }
+
+    Ok(())
+}
+
+fn request_pad(tee: &gst::Element) -> Result<gst::Pad, Error> {
+    tee.request_pad_simple("src_%u")
+        .ok_or_else(|| anyhow!("failed to request pad from {}", tee.name()))
+}
@@ -0,0 +1,557 @@
+use gst::prelude::*;
+use std::{fmt::Write as _, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::{
+    dash, hlscmaf,
+    mp4::{self, Mp4Box},
+    source, utils,
+};
+
+/// `CODECS` of IMSC1 text tracks.
+pub(crate) const IMSC1_CODECS: &str = "stpp.ttml.im1t";
+// Same as the DASH manifest, so media times convert exactly
+const TIMESCALE: u64 = 90_000;
+const TRACK_ID: u32 = 1;
+// `mpegtsmux` adds an hour to the running time for the PTS, in its 90 kHz clock
+const MPEGTS_BASE: u64 = 90_000 * 60 * 60;
+
+/// How the subtitle segments are packaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Format {
+    /// Plain WebVTT files, listed in the HLS playlists only
+    #[default]
+    Webvtt,
+    /// IMSC1 text profile TTML in fragmented MP4 (`stpp`), listed in the DASH manifest too
+    Imsc1,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webvtt" => Ok(Format::Webvtt),
+            "imsc1" => Ok(Format::Imsc1),
+            _ => Err(anyhow!("unknown subtitle format '{}', expected webvtt or imsc1", s)),
+        }
+    }
+}
+
+/// What the generated cues show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Cues {
+    /// The wall clock time, one cue per second
+    #[default]
+    Clock,
+    /// The number of the segment, one cue per segment
+    Counter,
+}
+
+impl FromStr for Cues {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clock" => Ok(Cues::Clock),
+            "counter" => Ok(Cues::Counter),
+            _ => Err(anyhow!("unknown cues '{}', expected clock or counter", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SubtitleStream {
+    pub name: String,
+    pub lang: String,
+    pub default: bool,
+    /// Shown even when the viewer didn't select any subtitles
+    pub forced: bool,
+    pub cues: Cues,
+    pub format: Format,
+}
+
+impl Default for SubtitleStream {
+    fn default() -> Self {
+        SubtitleStream {
+            name: String::new(),
+            lang: "en".to_string(),
+            default: false,
+            forced: false,
+            cues: Cues::default(),
+            format: Format::default(),
+        }
+    }
+}
+
+impl SubtitleStream {
+    /// Sets up the branch timing the cues.
+    ///
+    /// The cues are generated, only the timestamps of the shared video are used so the
+    /// segments are cut at the same running times as those of the video renditions. WebVTT
+    /// streams get separate segments for the MPEG-TS renditions, timed to their PTS.
+    pub fn setup(
+        &self,
+        pipeline: &gst::Pipeline,
+        source: &mut source::Source,
+        output: &hlscmaf::Output,
+    ) -> Result<utils::Branch, Error> {
+        // Subtitle segments have neither parts nor sample encryption
+        let settings = &hlscmaf::Settings {
+            part_duration: None,
+            encryption: None,
+            ..output.settings
+        };
+
+        let queue = gst::ElementFactory::make("queue").build()?;
+        pipeline.add(&queue)?;
+
+        // The MPEG-TS renditions only take WebVTT
+        let mut trees = vec![];
+        if output.format.has_cmaf() {
+            trees.push(false);
+        }
+        if output.format.has_ts() && self.format == Format::Webvtt {
+            trees.push(true);
+        }
+
+        let mut heads = vec![];
+        for ts in trees {
+            // Without a live source nothing would ever preroll the sink before its first frame
+            let appsink = gst_app::AppSink::builder().async_(false).build();
+            pipeline.add(&appsink)?;
+
+            hlscmaf::setup_subtitles(&appsink, self, output, settings, ts);
+
+            heads.push(appsink.upcast());
+        }
+
+        let mut elements = vec![queue.clone()];
+        elements.extend(utils::fan_out(pipeline, &queue, &heads)?);
+        elements.extend(heads);
+
+        utils::Branch::new(source.video_pad()?, elements)
+    }
+
+    /// Renders segment `number`, which starts at running time `time` and wall clock time
+    /// `date_time`, in the format of the stream. WebVTT segments of the MPEG-TS renditions
+    /// are `ts`.
+    pub fn render_segment(
+        &self,
+        number: u32,
+        date_time: DateTime<Utc>,
+        time: gst::ClockTime,
+        duration: gst::ClockTime,
+        ts: bool,
+    ) -> Vec<u8> {
+        match self.format {
+            Format::Webvtt => {
+                render_webvtt(self.cues, number, date_time, time, duration, ts).into_bytes()
+            }
+            Format::Imsc1 => {
+                let ttml = render_ttml(self.cues, &self.lang, number, date_time, time, duration);
+                imsc1_fragment(number, time, duration, ttml.as_bytes())
+            }
+        }
+    }
+}
+
+impl FromStr for SubtitleStream {
+    type Err = Error;
+
+    // Parses `lang=en,cues=clock,format=webvtt,default=true,forced=false`, every key is
+    // optional.
+    // An empty name is filled in by the caller once all renditions are known.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stream = SubtitleStream::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "name" => stream.name = value.to_string(),
+                "lang" => stream.lang = value.to_string(),
+                "default" => stream.default = value.parse()?,
+                "forced" => stream.forced = value.parse()?,
+                "cues" => stream.cues = value.parse()?,
+                "format" => stream.format = value.parse()?,
+                _ => return Err(anyhow!("unknown subtitle stream property '{}'", key)),
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Renders the WebVTT segment `number`, which starts at running time `time` and wall clock
+/// time `date_time`.
+///
+/// Cue times are running times, which the fMP4 segments of the other renditions use as media
+/// time as well, so the timestamp map is the identity. The PTS of the MPEG-TS renditions the
+/// segments are `ts` for start an hour later.
+pub(crate) fn render_webvtt(
+    cues: Cues,
+    number: u32,
+    date_time: DateTime<Utc>,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+    ts: bool,
+) -> String {
+    let mpegts = if ts { MPEGTS_BASE } else { 0 };
+
+    let mut vtt = String::new();
+    writeln!(vtt, "WEBVTT").unwrap();
+    writeln!(vtt, "X-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000", mpegts).unwrap();
+
+    for (start, end, text) in generate_cues(cues, number, date_time, time, duration) {
+        writeln!(vtt).unwrap();
+        writeln!(vtt, "{} --> {}", timestamp(start), timestamp(end)).unwrap();
+        writeln!(vtt, "{}", text).unwrap();
+    }
+
+    vtt
+}
+
+/// Renders the IMSC1 document of segment `number`, see `render_webvtt()`.
+///
+/// Times are media times of the track, which are running times just like in WebVTT.
+pub(crate) fn render_ttml(
+    cues: Cues,
+    lang: &str,
+    number: u32,
+    date_time: DateTime<Utc>,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) -> String {
+    let mut ttml = String::new();
+    writeln!(ttml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        ttml,
+        r#"<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter" xmlns:tts="http://www.w3.org/ns/ttml#styling" ttp:profile="http://www.w3.org/ns/ttml/profile/imsc1/text" xml:lang="{}">"#,
+        dash::escape(lang)
+    )
+    .unwrap();
+    writeln!(ttml, "  <head>").unwrap();
+    writeln!(
+        ttml,
+        r#"    <styling><style xml:id="s0" tts:color="white" tts:backgroundColor="black" tts:textAlign="center"/></styling>"#
+    )
+    .unwrap();
+    writeln!(
+        ttml,
+        r#"    <layout><region xml:id="r0" tts:origin="10% 80%" tts:extent="80% 10%" tts:displayAlign="after"/></layout>"#
+    )
+    .unwrap();
+    writeln!(ttml, "  </head>").unwrap();
+    writeln!(ttml, r#"  <body region="r0" style="s0">"#).unwrap();
+    writeln!(ttml, "    <div>").unwrap();
+    for (start, end, text) in generate_cues(cues, number, date_time, time, duration) {
+        writeln!(
+            ttml,
+            r#"      <p begin="{}" end="{}">{}</p>"#,
+            timestamp(start),
+            timestamp(end),
+            dash::escape(&text)
+        )
+        .unwrap();
+    }
+    writeln!(ttml, "    </div>").unwrap();
+    writeln!(ttml, "  </body>").unwrap();
+    writeln!(ttml, "</tt>").unwrap();
+
+    ttml
+}
+
+// Start, end and text of every cue of a segment
+fn generate_cues(
+    cues: Cues,
+    number: u32,
+    date_time: DateTime<Utc>,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+) -> Vec<(gst::ClockTime, gst::ClockTime, String)> {
+    let end = time + duration;
+    match cues {
+        Cues::Clock => {
+            // A new cue whenever the wall clock reaches the next full second
+            let mut generated = vec![];
+            let mut start = time;
+            while start < end {
+                let into_segment = Duration::nanoseconds((start - time).nseconds() as i64);
+                let wall_clock = date_time + into_segment;
+                let to_next_second = 1_000_000_000 - wall_clock.timestamp_subsec_nanos() as u64;
+                let cue_end = end.min(start + gst::ClockTime::from_nseconds(to_next_second));
+
+                generated.push((start, cue_end, wall_clock.format("%H:%M:%S").to_string()));
+                start = cue_end;
+            }
+            generated
+        }
+        Cues::Counter => vec![(time, end, format!("Segment {}", number))],
+    }
+}
+
+// `hh:mm:ss.ttt`, hours can have more digits. Valid in both WebVTT and TTML.
+fn timestamp(time: gst::ClockTime) -> String {
+    let millis = time.mseconds();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// The CMAF header of an IMSC1 track in `lang`.
+pub(crate) fn imsc1_header(lang: &str) -> Vec<u8> {
+    let mut ftyp = b"cmfc".to_vec();
+    ftyp.extend_from_slice(&0u32.to_be_bytes());
+    for brand in [b"iso6", b"cmfc", b"im1t", b"dash"] {
+        ftyp.extend_from_slice(brand);
+    }
+
+    // Creation and modification time, timescale and duration
+    let mut mvhd = vec![0; 8];
+    mvhd.extend_from_slice(&(TIMESCALE as u32).to_be_bytes());
+    mvhd.extend_from_slice(&[0; 4]);
+    // Rate, volume and reserved fields
+    mvhd.extend_from_slice(&0x1_0000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0x100u16.to_be_bytes());
+    mvhd.extend_from_slice(&[0; 10]);
+    mvhd.extend_from_slice(&unity_matrix());
+    // Predefined fields and the next track ID
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&(TRACK_ID + 1).to_be_bytes());
+
+    // Creation and modification time, track ID, reserved and duration
+    let mut tkhd = vec![0; 8];
+    tkhd.extend_from_slice(&TRACK_ID.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 8]);
+    // Reserved, layer, alternate group, volume and reserved
+    tkhd.extend_from_slice(&[0; 16]);
+    tkhd.extend_from_slice(&unity_matrix());
+    // No width and height, the text is laid out on the video
+    tkhd.extend_from_slice(&[0; 8]);
+
+    let mut mdhd = vec![0; 8];
+    mdhd.extend_from_slice(&(TIMESCALE as u32).to_be_bytes());
+    mdhd.extend_from_slice(&[0; 4]);
+    mdhd.extend_from_slice(&language_code(lang).to_be_bytes());
+    mdhd.extend_from_slice(&[0; 2]);
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(b"subt");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"SubtitleHandler\0");
+
+    // SampleEntry fields, then namespace, schema location and auxiliary MIME types
+    let mut stpp = vec![0; 6];
+    stpp.extend_from_slice(&1u16.to_be_bytes());
+    stpp.extend_from_slice(b"http://www.w3.org/ns/ttml\0\0\0");
+
+    let stsd = Mp4Box::full(b"stsd", 0, 0, &1u32.to_be_bytes())
+        .with_children(vec![Mp4Box::new(b"stpp", stpp)]);
+    let stbl = Mp4Box::new(b"stbl", vec![]).with_children(vec![
+        stsd,
+        Mp4Box::full(b"stts", 0, 0, &0u32.to_be_bytes()),
+        Mp4Box::full(b"stsc", 0, 0, &0u32.to_be_bytes()),
+        Mp4Box::full(b"stsz", 0, 0, &[0; 8]),
+        Mp4Box::full(b"stco", 0, 0, &0u32.to_be_bytes()),
+    ]);
+    // A single self-contained data reference
+    let dref = Mp4Box::full(b"dref", 0, 0, &1u32.to_be_bytes())
+        .with_children(vec![Mp4Box::full(b"url ", 0, 1, &[])]);
+    let minf = Mp4Box::new(b"minf", vec![]).with_children(vec![
+        Mp4Box::full(b"sthd", 0, 0, &[]),
+        Mp4Box::new(b"dinf", vec![]).with_children(vec![dref]),
+        stbl,
+    ]);
+    let mdia = Mp4Box::new(b"mdia", vec![]).with_children(vec![
+        Mp4Box::full(b"mdhd", 0, 0, &mdhd),
+        Mp4Box::full(b"hdlr", 0, 0, &hdlr),
+        minf,
+    ]);
+    let trak = Mp4Box::new(b"trak", vec![])
+        .with_children(vec![Mp4Box::full(b"tkhd", 0, 0x3, &tkhd), mdia]);
+
+    // Track ID, sample description index, then no default duration, size and flags
+    let mut trex = TRACK_ID.to_be_bytes().to_vec();
+    trex.extend_from_slice(&1u32.to_be_bytes());
+    trex.extend_from_slice(&[0; 12]);
+    let mvex = Mp4Box::new(b"mvex", vec![]).with_children(vec![Mp4Box::full(b"trex", 0, 0, &trex)]);
+
+    let moov = Mp4Box::new(b"moov", vec![])
+        .with_children(vec![Mp4Box::full(b"mvhd", 0, 0, &mvhd), trak, mvex]);
+
+    mp4::write_all(&[Mp4Box::new(b"ftyp", ftyp), moov])
+}
+
+/// The CMAF fragment of segment `number` with `document` as its single sample, covering the
+/// whole segment.
+pub(crate) fn imsc1_fragment(
+    number: u32,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+    document: &[u8],
+) -> Vec<u8> {
+    let start = ticks(time);
+    let duration = ticks(time + duration) - start;
+
+    // One sample with data offset, duration and size
+    let mut trun = 1u32.to_be_bytes().to_vec();
+    trun.extend_from_slice(&0u32.to_be_bytes());
+    trun.extend_from_slice(&(duration as u32).to_be_bytes());
+    trun.extend_from_slice(&(document.len() as u32).to_be_bytes());
+
+    let traf = Mp4Box::new(b"traf", vec![]).with_children(vec![
+        Mp4Box::full(b"tfhd", 0, 0x20000, &TRACK_ID.to_be_bytes()),
+        Mp4Box::full(b"tfdt", 1, 0, &start.to_be_bytes()),
+        Mp4Box::full(b"trun", 0, 0x301, &trun),
+    ]);
+    // Sequence numbers start at 1
+    let mut moof = Mp4Box::new(b"moof", vec![])
+        .with_children(vec![Mp4Box::full(b"mfhd", 0, 0, &(number + 1).to_be_bytes()), traf]);
+
+    // The sample starts right after the header of the mdat
+    let offset = moof.size() as i32 + 8;
+    mp4::shift_data_offsets(&mut moof, offset);
+
+    mp4::write_all(&[moof, Mp4Box::new(b"mdat", document.to_vec())])
+}
+
+fn unity_matrix() -> Vec<u8> {
+    [0x1_0000u32, 0, 0, 0, 0x1_0000, 0, 0, 0, 0x4000_0000]
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+// Packed ISO 639-2/T code, `und` for anything else like the two letter codes of BCP 47
+fn language_code(lang: &str) -> u16 {
+    let lang: &[u8] = if lang.len() == 3 && lang.bytes().all(|letter| letter.is_ascii_lowercase()) {
+        lang.as_bytes()
+    } else {
+        b"und"
+    };
+
+    lang.iter().fold(0, |code, letter| (code << 5) | (letter - 0x60) as u16)
+}
+
+fn ticks(time: gst::ClockTime) -> u64 {
+    (time.nseconds() as u128 * TIMESCALE as u128 / gst::ClockTime::SECOND.nseconds() as u128)
+        as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T12:00:00.500Z").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn renders_clock_cues() {
+        let vtt = render_webvtt(
+            Cues::Clock,
+            3,
+            date_time(),
+            gst::ClockTime::from_seconds(3601),
+            gst::ClockTime::from_seconds(2),
+            false,
+        );
+
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\
+             X-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\
+             \n\
+             01:00:01.000 --> 01:00:01.500\n\
+             12:00:00\n\
+             \n\
+             01:00:01.500 --> 01:00:02.500\n\
+             12:00:01\n\
+             \n\
+             01:00:02.500 --> 01:00:03.000\n\
+             12:00:02\n"
+        );
+    }
+
+    #[test]
+    fn renders_counter_cues() {
+        let vtt = render_webvtt(
+            Cues::Counter,
+            3,
+            date_time(),
+            gst::ClockTime::from_mseconds(6500),
+            gst::ClockTime::from_seconds(2),
+            false,
+        );
+
+        assert!(vtt.ends_with("\n00:00:06.500 --> 00:00:08.500\nSegment 3\n"));
+    }
+
+    #[test]
+    fn maps_cues_to_mpegts_timestamps() {
+        let vtt = render_webvtt(
+            Cues::Counter,
+            3,
+            date_time(),
+            gst::ClockTime::from_mseconds(6500),
+            gst::ClockTime::from_seconds(2),
+            true,
+        );
+
+        assert!(vtt.starts_with("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:324000000,LOCAL:00:00:00.000\n"));
+        assert!(vtt.ends_with("\n00:00:06.500 --> 00:00:08.500\nSegment 3\n"));
+    }
+
+    #[test]
+    fn renders_ttml_cues() {
+        let ttml = render_ttml(
+            Cues::Clock,
+            "en",
+            3,
+            date_time(),
+            gst::ClockTime::from_seconds(3601),
+            gst::ClockTime::from_seconds(1),
+        );
+
+        assert!(ttml.contains(r#"xml:lang="en""#));
+        assert!(ttml.contains(r#"<p begin="01:00:01.000" end="01:00:01.500">12:00:00</p>"#));
+        assert!(ttml.contains(r#"<p begin="01:00:01.500" end="01:00:02.000">12:00:01</p>"#));
+    }
+
+    #[test]
+    fn packages_imsc1_fragments() {
+        let document = b"<tt/>";
+        let fragment = imsc1_fragment(
+            3,
+            gst::ClockTime::from_seconds(2),
+            gst::ClockTime::from_seconds(2),
+            document,
+        );
+
+        let boxes = Mp4Box::parse_all(&fragment).unwrap();
+        assert_eq!(&boxes[1].fourcc, b"mdat");
+
+        let traf = boxes[0].child(b"traf").unwrap();
+        let tfdt = traf.child(b"tfdt").unwrap();
+        assert_eq!(&tfdt.data[4..], &180_000u64.to_be_bytes());
+
+        let ranges = mp4::sample_ranges(traf).unwrap();
+        let (offset, size) = ranges[0];
+        assert_eq!(&fragment[offset..offset + size], document);
+    }
+
+    #[test]
+    fn packs_language_codes() {
+        assert_eq!(language_code("eng"), 0x15c7);
+        assert_eq!(language_code("en"), language_code("und"));
+    }
+}
@@ -0,0 +1,630 @@
+use gst::prelude::*;
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    dash,
+    hlscmaf::{self, PlaylistMode},
+    source, utils,
+};
+
+/// Directory of the sprites and their playlist, also the ID of the DASH representation.
+pub(crate) const NAME: &str = "thumbnails";
+// Rough size of the JPEG sprites, for the advertised bandwidth
+const BITS_PER_PIXEL: f64 = 1.0;
+
+/// Thumbnails of the video, tiled into JPEG sprites covering a few segments each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Thumbnails {
+    /// Size of each thumbnail
+    pub width: u64,
+    pub height: u64,
+    /// Thumbnails per row of a sprite
+    pub columns: u32,
+    /// Rows of a sprite
+    pub rows: u32,
+    /// Segments covered by each sprite
+    pub segments: u32,
+}
+
+impl Default for Thumbnails {
+    fn default() -> Self {
+        Thumbnails {
+            width: 160,
+            height: 90,
+            columns: 5,
+            rows: 2,
+            segments: 5,
+        }
+    }
+}
+
+impl FromStr for Thumbnails {
+    type Err = Error;
+
+    // Parses `width=160,height=90,columns=5,rows=2,segments=5`, every key is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thumbnails = Thumbnails::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "width" => thumbnails.width = value.parse()?,
+                "height" => thumbnails.height = value.parse()?,
+                "columns" => thumbnails.columns = value.parse()?,
+                "rows" => thumbnails.rows = value.parse()?,
+                "segments" => thumbnails.segments = value.parse()?,
+                _ => return Err(anyhow!("unknown thumbnails property '{}'", key)),
+            }
+        }
+
+        Ok(thumbnails)
+    }
+}
+
+impl Thumbnails {
+    pub fn tiles(&self) -> u32 {
+        self.columns * self.rows
+    }
+
+    /// Size of a whole sprite.
+    pub fn sprite_size(&self) -> (u64, u64) {
+        (self.width * self.columns as u64, self.height * self.rows as u64)
+    }
+
+    /// `LAYOUT` of `EXT-X-TILES`, also the value of the DASH `thumbnail_tile` property.
+    pub fn layout(&self) -> String {
+        format!("{}x{}", self.columns, self.rows)
+    }
+
+    pub fn sprite_duration(&self, segment_duration: gst::ClockTime) -> gst::ClockTime {
+        segment_duration * self.segments as u64
+    }
+
+    pub fn tile_duration(&self, segment_duration: gst::ClockTime) -> gst::ClockTime {
+        self.sprite_duration(segment_duration) / self.tiles() as u64
+    }
+
+    /// Estimated bitrate of the sprites.
+    pub fn bandwidth(&self, segment_duration: gst::ClockTime) -> u64 {
+        let (width, height) = self.sprite_size();
+        let seconds = utils::clock_time_to_seconds(self.sprite_duration(segment_duration));
+        ((width * height) as f64 * BITS_PER_PIXEL / seconds).ceil() as u64
+    }
+
+    /// `EXT-X-IMAGE-STREAM-INF` pointing the master playlist to the image playlist.
+    pub fn image_stream_tag(&self, segment_duration: gst::ClockTime) -> m3u8_rs::ExtTag {
+        let (width, height) = self.sprite_size();
+
+        m3u8_rs::ExtTag {
+            tag: "X-IMAGE-STREAM-INF".into(),
+            rest: Some(format!(
+                "BANDWIDTH={},RESOLUTION={}x{},CODECS=\"jpeg\",URI=\"{}/manifest.m3u8\"",
+                self.bandwidth(segment_duration),
+                width,
+                height,
+                NAME
+            )),
+        }
+    }
+
+    // Number of sprites the image playlist lists, all of them when `None`
+    fn window(&self, settings: &hlscmaf::Settings) -> Option<usize> {
+        let sprite_duration = self.sprite_duration(settings.segment_duration).nseconds();
+        let window = match settings.mode {
+            PlaylistMode::Live | PlaylistMode::LiveToVod => {
+                settings.segment_duration.nseconds() * settings.window_size as u64
+            }
+            PlaylistMode::Dvr => settings.dvr_window.nseconds(),
+            PlaylistMode::Event => return None,
+        };
+
+        Some(((window + sprite_duration - 1) / sprite_duration).max(1) as usize)
+    }
+}
+
+struct Sprite {
+    number: u64,
+    date_time: DateTime<Utc>,
+    time: gst::ClockTime,
+    duration: gst::ClockTime,
+}
+
+// The sprite thumbnails are currently tiled into
+struct OpenSprite {
+    number: u64,
+    frame: gst_video::VideoFrame<gst_video::video_frame::Writable>,
+    // Tiles up to the newest one filled in
+    tiles: u32,
+    filled: Vec<bool>,
+}
+
+// Tiles the thumbnails into sprites on the streaming thread
+struct State {
+    thumbnails: Thumbnails,
+    settings: hlscmaf::Settings,
+    path: PathBuf,
+    sprite_info: gst_video::VideoInfo,
+    start_time: Option<gst::ClockTime>,
+    start_date_time: Option<DateTime<Utc>>,
+    open: Option<OpenSprite>,
+    origin: Arc<hlscmaf::Origin>,
+    encoder: mpsc::Sender<Job>,
+}
+
+// Encodes and lists the sprites on a thread of its own, which keeps the JPEG encoder from
+// holding up the thumbnails
+struct Sprites {
+    thumbnails: Thumbnails,
+    settings: hlscmaf::Settings,
+    path: PathBuf,
+    mpd: Arc<Mutex<dash::Mpd>>,
+    start_date_time: Option<DateTime<Utc>>,
+    sprites: VecDeque<Sprite>,
+    // Of the oldest sprite, sprite numbers skip those that were never written
+    media_sequence: u64,
+    ended: bool,
+}
+
+enum Job {
+    Sprite {
+        sprite: Sprite,
+        // The raw tiles
+        sample: gst::Sample,
+        start_date_time: DateTime<Utc>,
+    },
+    End,
+}
+
+/// Sets up the branch scaling the shared video down to thumbnails and tiling them into
+/// `thumbnails/sprite_N.jpg`, each covering [`Thumbnails::segments`] segments from the origin
+/// of the renditions on.
+///
+/// Every finished sprite updates the HLS image playlist and the DASH manifest.
+pub(crate) fn setup(
+    thumbnails: &Thumbnails,
+    pipeline: &gst::Pipeline,
+    source: &mut source::Source,
+    output: &hlscmaf::Output,
+) -> Result<utils::Branch, Error> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let videoscale = gst::ElementFactory::make("videoscale").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst_video::VideoCapsBuilder::new()
+                .format(gst_video::VideoFormat::Rgb)
+                .width(thumbnails.width as i32)
+                .height(thumbnails.height as i32)
+                .pixel_aspect_ratio(gst::Fraction::new(1, 1))
+                .build(),
+        )
+        .build()?;
+    // Without a live source nothing would ever preroll the sink before its first frame
+    let appsink = gst_app::AppSink::builder().async_(false).build();
+
+    let elements = [
+        &queue,
+        &videoconvert,
+        &videoscale,
+        &capsfilter,
+        appsink.upcast_ref(),
+    ];
+    pipeline.add_many(elements)?;
+    gst::Element::link_many(elements)?;
+
+    let (encoder, jobs) = mpsc::channel();
+    let sprites = Sprites {
+        thumbnails: *thumbnails,
+        settings: output.settings,
+        path: output.path.join(NAME),
+        mpd: output.mpd.clone(),
+        start_date_time: None,
+        sprites: VecDeque::new(),
+        media_sequence: 0,
+        ended: false,
+    };
+    std::thread::spawn(move || encode_sprites(sprites, jobs));
+
+    let (width, height) = thumbnails.sprite_size();
+    let state = Arc::new(Mutex::new(State {
+        thumbnails: *thumbnails,
+        settings: output.settings,
+        path: output.path.join(NAME),
+        sprite_info: gst_video::VideoInfo::builder(
+            gst_video::VideoFormat::Rgb,
+            width as u32,
+            height as u32,
+        )
+        .build()?,
+        start_time: None,
+        start_date_time: None,
+        open: None,
+        origin: output.origin.clone(),
+        encoder,
+    }));
+    let eos_state = state.clone();
+
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let mut state = state.lock().unwrap();
+
+                let buffer = sample.buffer().expect("no buffer");
+                let caps = sample.caps().expect("no caps");
+                let info = gst_video::VideoInfo::from_caps(caps).expect("invalid caps");
+                let segment = sample
+                    .segment()
+                    .expect("no segment")
+                    .downcast_ref::<gst::ClockTime>()
+                    .expect("no time segment");
+                let Some(pts) = buffer.pts().and_then(|pts| segment.to_running_time(pts)) else {
+                    return Ok(gst::FlowSuccess::Ok);
+                };
+
+                // Numbered from the origin of the renditions, like their segments
+                if state.start_time.is_none() {
+                    std::fs::create_dir_all(&state.path).expect("failed to create directory");
+                    let (origin, origin_date_time) = state.origin.anchor(sink.upcast_ref(), pts);
+                    state.start_time = Some(origin);
+                    state.start_date_time = Some(origin_date_time);
+                }
+
+                let tile_duration = state.thumbnails.tile_duration(state.settings.segment_duration);
+                let offset = pts.saturating_sub(state.start_time.unwrap());
+                let index = offset.nseconds() / tile_duration.nseconds();
+                let tiles = state.thumbnails.tiles() as u64;
+                let (number, tile) = (index / tiles, (index % tiles) as u32);
+
+                if state.open.as_ref().is_some_and(|open| open.number != number) {
+                    finish_sprite(&mut state, false);
+                }
+                if state.open.is_none() {
+                    // Black wherever no frame came in, e.g. after a gap in the input
+                    let buffer = gst::Buffer::from_mut_slice(vec![0; state.sprite_info.size()]);
+                    let frame =
+                        gst_video::VideoFrame::from_buffer_writable(buffer, &state.sprite_info)
+                            .expect("failed to map sprite");
+                    state.open = Some(OpenSprite {
+                        number,
+                        frame,
+                        tiles: 0,
+                        filled: vec![false; tiles as usize],
+                    });
+                }
+
+                let thumbnails = state.thumbnails;
+                let open = state.open.as_mut().unwrap();
+                if !open.filled[tile as usize] {
+                    let thumbnail =
+                        gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &info)
+                            .expect("failed to map thumbnail");
+                    copy_tile(&thumbnails, &thumbnail, &mut open.frame, tile);
+                    open.filled[tile as usize] = true;
+                    open.tiles = open.tiles.max(tile + 1);
+                }
+
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .eos(move |_sink| {
+                let mut state = eos_state.lock().unwrap();
+
+                finish_sprite(&mut state, true);
+                if state.encoder.send(Job::End).is_err() {
+                    warn!("sprite encoder stopped, {} isn't finished", state.path.display());
+                }
+            })
+            .build(),
+    );
+
+    utils::Branch::new(source.video_pad()?, vec![
+        queue,
+        videoconvert,
+        videoscale,
+        capsfilter,
+        appsink.upcast(),
+    ])
+}
+
+// Copies `thumbnail` into `tile` of the sprite, counting row by row from the top left
+fn copy_tile(
+    thumbnails: &Thumbnails,
+    thumbnail: &gst_video::VideoFrameRef<&gst::BufferRef>,
+    sprite: &mut gst_video::VideoFrame<gst_video::video_frame::Writable>,
+    tile: u32,
+) {
+    // Three bytes per pixel
+    let row_length = thumbnails.width as usize * 3;
+    let x = (tile % thumbnails.columns) as usize * row_length;
+    let y = (tile / thumbnails.columns) as usize * thumbnails.height as usize;
+
+    let src_stride = thumbnail.plane_stride()[0] as usize;
+    let dst_stride = sprite.plane_stride()[0] as usize;
+    let src = thumbnail.plane_data(0).unwrap();
+    let dst = sprite.plane_data_mut(0).unwrap();
+
+    for row in 0..thumbnails.height as usize {
+        let src = &src[row * src_stride..][..row_length];
+        dst[(y + row) * dst_stride + x..][..row_length].copy_from_slice(src);
+    }
+}
+
+// Passes the open sprite, if any, on to the encoder thread. It covers the whole sprite duration
+// unless it's the `last` one, which only covers what was left of the stream.
+fn finish_sprite(state: &mut State, last: bool) {
+    let Some(open) = state.open.take() else {
+        return;
+    };
+
+    let caps = state.sprite_info.to_caps().unwrap();
+    let sample = gst::Sample::builder().buffer(&open.frame.into_buffer()).caps(&caps).build();
+
+    let segment_duration = state.settings.segment_duration;
+    let sprite_duration = state.thumbnails.sprite_duration(segment_duration);
+    let offset = sprite_duration * open.number;
+    let start_date_time = state.start_date_time.unwrap();
+    let sprite = Sprite {
+        number: open.number,
+        date_time: start_date_time + Duration::nanoseconds(offset.nseconds() as i64),
+        time: state.start_time.unwrap() + offset,
+        duration: if last {
+            state.thumbnails.tile_duration(segment_duration) * open.tiles as u64
+        } else {
+            sprite_duration
+        },
+    };
+
+    let job = Job::Sprite {
+        sprite,
+        sample,
+        start_date_time,
+    };
+    if state.encoder.send(job).is_err() {
+        warn!("sprite encoder stopped, dropping sprite {}", open.number);
+    }
+}
+
+// Encodes and lists the sprites in the order they were finished in, until the branch is gone
+fn encode_sprites(mut sprites: Sprites, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        match job {
+            Job::Sprite {
+                sprite,
+                sample,
+                start_date_time,
+            } => {
+                sprites.start_date_time = Some(start_date_time);
+                // Players show nothing for the time of a missing sprite, as after a gap
+                if let Err(err) = write_sprite(&sprites, sprite.number, &sample) {
+                    warn!("failed to write sprite {}: {:#}", sprite.number, err);
+                    continue;
+                }
+                sprites.sprites.push_back(sprite);
+                trim_sprites(&mut sprites);
+            }
+            Job::End => sprites.ended = true,
+        }
+
+        write_playlist(&mut sprites);
+    }
+}
+
+fn write_sprite(sprites: &Sprites, number: u64, sample: &gst::Sample) -> Result<(), Error> {
+    let jpeg = gst_video::convert_sample(
+        sample,
+        &gst::Caps::builder("image/jpeg").build(),
+        gst::ClockTime::from_seconds(5),
+    )?;
+    let buffer = jpeg.buffer().ok_or_else(|| anyhow!("no JPEG"))?;
+    let map = buffer.map_readable()?;
+
+    let path = sprites.path.join(format!("sprite_{}.jpg", number));
+    std::fs::write(&path, map.as_slice())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    info!("wrote sprite: {}", path.display());
+
+    Ok(())
+}
+
+// Deletes the sprites that left the window, keeping one more than listed for players still
+// loading the playlist. The VOD playlist of `PlaylistMode::LiveToVod` needs all of them.
+fn trim_sprites(state: &mut Sprites) {
+    let Some(window) = state.thumbnails.window(&state.settings) else {
+        return;
+    };
+    if state.settings.mode == PlaylistMode::LiveToVod {
+        return;
+    }
+
+    while state.sprites.len() > window + 1 {
+        let sprite = state.sprites.pop_front().unwrap();
+        state.media_sequence += 1;
+        let path = state.path.join(format!("sprite_{}.jpg", sprite.number));
+        info!("deleting {}", path.display());
+        if let Err(err) = std::fs::remove_file(&path) {
+            warn!("failed to remove {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn write_playlist(state: &mut Sprites) {
+    let window = match state.thumbnails.window(&state.settings) {
+        // The whole session once it's over
+        _ if state.ended && state.settings.mode == PlaylistMode::LiveToVod => None,
+        window => window,
+    };
+    let skip = window.map_or(0, |window| state.sprites.len().saturating_sub(window));
+    let sprites = state.sprites.iter().skip(skip).collect::<Vec<_>>();
+    let Some(first) = sprites.first() else {
+        return;
+    };
+
+    let playlist = render_playlist(
+        &state.thumbnails,
+        state.settings.segment_duration,
+        state.media_sequence + skip as u64,
+        &sprites,
+        state.ended,
+        (state.ended && state.settings.mode == PlaylistMode::LiveToVod)
+            || state.settings.mode == PlaylistMode::Event,
+    );
+    let path = state.path.join("manifest.m3u8");
+    let written =
+        utils::write_atomically(&path, |file| std::io::Write::write_all(file, playlist.as_bytes()));
+    if let Err(err) = written {
+        warn!("failed to write {}: {}", path.display(), err);
+    }
+
+    let timeline = dash::Timeline {
+        start_date_time: state.start_date_time.unwrap(),
+        segments: sprites
+            .iter()
+            .map(|sprite| dash::Segment {
+                number: sprite.number,
+                date_time: sprite.date_time,
+                time: sprite.time,
+                duration: sprite.duration,
+                init: String::new(),
+                discontinuity: false,
+                default_kid: None,
+                media_range: None,
+            })
+            .collect(),
+        ended: state.ended,
+    };
+    state.mpd.lock().unwrap().update(NAME, timeline);
+}
+
+// Image media playlist as Roku and others read it, m3u8-rs has no notion of one. Sprites
+// following a gap in the numbers, e.g. because the input stalled, are dated anew.
+fn render_playlist(
+    thumbnails: &Thumbnails,
+    segment_duration: gst::ClockTime,
+    media_sequence: u64,
+    sprites: &[&Sprite],
+    ended: bool,
+    whole: bool,
+) -> String {
+    let sprite_duration = thumbnails.sprite_duration(segment_duration);
+    let second = gst::ClockTime::SECOND.nseconds();
+    let target_duration = (sprite_duration.nseconds() + second - 1) / second;
+
+    let mut playlist = String::new();
+    writeln!(playlist, "#EXTM3U").unwrap();
+    writeln!(playlist, "#EXT-X-VERSION:7").unwrap();
+    writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration).unwrap();
+    writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", media_sequence).unwrap();
+    if whole {
+        let playlist_type = if ended { "VOD" } else { "EVENT" };
+        writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:{}", playlist_type).unwrap();
+    }
+    writeln!(playlist, "#EXT-X-IMAGES-ONLY").unwrap();
+
+    for (idx, sprite) in sprites.iter().enumerate() {
+        if idx == 0 || sprites[idx - 1].number + 1 != sprite.number {
+            writeln!(
+                playlist,
+                "#EXT-X-PROGRAM-DATE-TIME:{}",
+                sprite.date_time.to_rfc3339_opts(SecondsFormat::Millis, true)
+            )
+            .unwrap();
+        }
+        writeln!(playlist, "#EXTINF:{:.3},", utils::clock_time_to_seconds(sprite.duration))
+            .unwrap();
+        writeln!(
+            playlist,
+            "#EXT-X-TILES:RESOLUTION={}x{},LAYOUT={},DURATION={:.3}",
+            thumbnails.width,
+            thumbnails.height,
+            thumbnails.layout(),
+            utils::clock_time_to_seconds(thumbnails.tile_duration(segment_duration))
+        )
+        .unwrap();
+        writeln!(playlist, "sprite_{}.jpg", sprite.number).unwrap();
+    }
+
+    if ended {
+        writeln!(playlist, "#EXT-X-ENDLIST").unwrap();
+    }
+
+    playlist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: PlaylistMode) -> hlscmaf::Settings {
+        hlscmaf::Settings {
+            segment_duration: gst::ClockTime::from_seconds(2),
+            window_size: 6,
+            mode,
+            dvr_window: gst::ClockTime::from_seconds(60),
+            part_duration: None,
+            encryption: None,
+            single_file: false,
+        }
+    }
+
+    #[test]
+    fn covers_the_window() {
+        let thumbnails = "segments=5".parse::<Thumbnails>().unwrap();
+        assert_eq!(thumbnails.window(&settings(PlaylistMode::Live)), Some(2));
+        assert_eq!(thumbnails.window(&settings(PlaylistMode::Dvr)), Some(6));
+        assert_eq!(thumbnails.window(&settings(PlaylistMode::Event)), None);
+        assert_eq!(thumbnails.bandwidth(gst::ClockTime::from_seconds(2)), 14_400);
+    }
+
+    #[test]
+    fn renders_image_playlists() {
+        let thumbnails = Thumbnails::default();
+        // Sprites 5 and 6 are missing, e.g. because the input stalled
+        let sprites = [3, 4, 7]
+            .into_iter()
+            .map(|number| Sprite {
+                number,
+                date_time: DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc)
+                    + Duration::seconds(number as i64 * 10),
+                time: gst::ClockTime::from_seconds(number * 10),
+                duration: gst::ClockTime::from_seconds(10),
+            })
+            .collect::<Vec<_>>();
+        let sprites = sprites.iter().collect::<Vec<_>>();
+
+        let segment_duration = gst::ClockTime::from_seconds(2);
+        let playlist = render_playlist(&thumbnails, segment_duration, 1, &sprites, false, false);
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:10\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-IMAGES-ONLY\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-01-01T12:00:30.000Z\n\
+             #EXTINF:10.000,\n\
+             #EXT-X-TILES:RESOLUTION=160x90,LAYOUT=5x2,DURATION=1.000\n\
+             sprite_3.jpg\n\
+             #EXTINF:10.000,\n\
+             #EXT-X-TILES:RESOLUTION=160x90,LAYOUT=5x2,DURATION=1.000\n\
+             sprite_4.jpg\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-01-01T12:01:10.000Z\n\
+             #EXTINF:10.000,\n\
+             #EXT-X-TILES:RESOLUTION=160x90,LAYOUT=5x2,DURATION=1.000\n\
+             sprite_7.jpg\n"
+        );
+    }
+}
@@ -1,8 +1,195 @@
 use gst::prelude::*;
-use std::sync::{Arc, Mutex};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use log::warn;
 
 use crate::State;
 
+// How long a removed branch gets to push out what it has left
+const EOS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The elements making up one rendition inside the pipeline, so it can be taken out again
+/// while the others keep running.
+pub(crate) struct Branch {
+    // The pad of the shared source's tee feeding the first element
+    tee_pad: gst::Pad,
+    elements: Vec<gst::Element>,
+}
+
+impl Branch {
+    /// Links `tee_pad` to the first of `elements`, which are expected to be added to the
+    /// pipeline and linked to each other already.
+    pub fn new(tee_pad: gst::Pad, elements: Vec<gst::Element>) -> Result<Self, Error> {
+        // When added to a running pipeline the branch has to be running before the tee
+        // pushes into it, otherwise the flushing queue would stop the shared source.
+        // Downstream first so no element pushes into a sink that isn't ready yet.
+        for element in elements.iter().rev() {
+            element.sync_state_with_parent()?;
+        }
+
+        let sinkpad = elements[0].static_pad("sink").unwrap();
+        tee_pad.link(&sinkpad)?;
+
+        Ok(Branch { tee_pad, elements })
+    }
+
+    /// Finishes the branch with an EOS, so its playlists get their end, and takes it out of the
+    /// pipeline once every sink got it or a few seconds passed.
+    pub fn remove(self, pipeline: &gst::Pipeline) {
+        let (sender, receiver) = mpsc::channel();
+
+        let sinks = self
+            .elements
+            .iter()
+            .filter(|element| element.is::<gst_app::AppSink>())
+            .collect::<Vec<_>>();
+        for sink in &sinks {
+            let sender = sender.clone();
+            sink.static_pad("sink").unwrap().add_probe(
+                gst::PadProbeType::EVENT_DOWNSTREAM,
+                move |_pad, info| match info.data {
+                    Some(gst::PadProbeData::Event(ref event))
+                        if event.type_() == gst::EventType::Eos =>
+                    {
+                        let _ = sender.send(());
+                        gst::PadProbeReturn::Remove
+                    }
+                    _ => gst::PadProbeReturn::Ok,
+                },
+            );
+        }
+
+        // Only detach once the tee isn't pushing into the branch, the other renditions
+        // keep flowing meanwhile.
+        let (detached, on_detached) = mpsc::channel();
+        let head = self.elements[0].static_pad("sink").unwrap();
+        self.tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _info| {
+            if let Some(peer) = pad.peer() {
+                let _ = pad.unlink(&peer);
+            }
+            if let Some(tee) = pad.parent_element() {
+                tee.release_request_pad(pad);
+            }
+
+            head.send_event(gst::event::Eos::new());
+            let _ = detached.send(());
+
+            gst::PadProbeReturn::Remove
+        });
+
+        let deadline = Instant::now() + EOS_TIMEOUT;
+        let mut finished = on_detached.recv_timeout(EOS_TIMEOUT).is_ok();
+        for _ in &sinks {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            finished &= receiver.recv_timeout(timeout).is_ok();
+        }
+        if !finished {
+            warn!("branch didn't finish in time, removing it anyway");
+        }
+
+        for element in &self.elements {
+            let _ = element.set_state(gst::State::Null);
+        }
+        let _ = pipeline.remove_many(&self.elements);
+    }
+}
+
+/// Writes through a temporary file that is then renamed over `path`, so concurrent readers
+/// like the HTTP server never see a half written playlist.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    write(&mut file)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Feeds the output of `upstream` into each of `heads`, through a `tee` with a queue per head
+/// when there's more than one. Returns the elements added for that, in the pipeline already.
+pub(crate) fn fan_out(
+    pipeline: &gst::Pipeline,
+    upstream: &gst::Element,
+    heads: &[gst::Element],
+) -> Result<Vec<gst::Element>, Error> {
+    if let [head] = heads {
+        upstream.link(head)?;
+        return Ok(vec![]);
+    }
+
+    let tee = gst::ElementFactory::make("tee").build()?;
+    pipeline.add(&tee)?;
+    upstream.link(&tee)?;
+
+    let mut elements = vec![tee.clone()];
+    for head in heads {
+        let queue = gst::ElementFactory::make("queue").build()?;
+        pipeline.add(&queue)?;
+        gst::Element::link_many([&tee, &queue, head])?;
+        elements.push(queue);
+    }
+
+    Ok(elements)
+}
+
+/// Adds `[parser !] mpegtsmux ! appsink` for the MPEG-TS segments of a rendition, linked to
+/// each other. `parser` converts the encoded stream to the byte-stream the muxer takes.
+pub(crate) fn ts_mux(
+    pipeline: &gst::Pipeline,
+    parser: Option<&str>,
+) -> Result<(Vec<gst::Element>, gst_app::AppSink), Error> {
+    let mut elements = vec![];
+    if let Some(parser) = parser {
+        elements.push(gst::ElementFactory::make(parser).build()?);
+    }
+    elements.push(gst::ElementFactory::make("mpegtsmux").build()?);
+    // Without a live source nothing would ever preroll the sink before its first packet
+    let appsink = gst_app::AppSink::builder().async_(false).build();
+    elements.push(appsink.clone().upcast());
+
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+
+    Ok((elements, appsink))
+}
+
+/// Running time of `buffer` flowing through `pad`, going by the segment of the pad.
+pub(crate) fn running_time(pad: &gst::Pad, buffer: &gst::BufferRef) -> Option<gst::ClockTime> {
+    let event = pad.sticky_event::<gst::event::Segment>(0)?;
+    let segment = event.segment().downcast_ref::<gst::ClockTime>()?;
+    segment.to_running_time(buffer.pts()?)
+}
+
+pub(crate) fn seconds_to_clock_time(seconds: f64) -> gst::ClockTime {
+    gst::ClockTime::from_nseconds((seconds * gst::ClockTime::SECOND.nseconds() as f64) as u64)
+}
+
+pub(crate) fn clock_time_to_seconds(time: gst::ClockTime) -> f64 {
+    time.nseconds() as f64 / gst::ClockTime::SECOND.nseconds() as f64
+}
+
+// Splits a `key=value,key=value` rendition description as given on the command line.
+pub(crate) fn parse_spec(spec: &str) -> Result<Vec<(&str, &str)>, Error> {
+    spec.split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| anyhow!("expected key=value, got '{}'", item))
+        })
+        .collect()
+}
+
 pub(crate) fn probe_encoder(state: Arc<Mutex<State>>, enc: gst::Element, name: String) {
     enc.static_pad("src").unwrap().add_probe(
         gst::PadProbeType::EVENT_DOWNSTREAM,
@@ -1,29 +1,86 @@
 use gst::prelude::*;
-use std::{sync::{Mutex, Arc}, path::Path};
+use std::{sync::{Mutex, Arc}, str::FromStr};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
 
-use crate::{State, hlscmaf, utils};
+use crate::{State, captions, hlscmaf, scte35, source, utils};
 
+pub(crate) const FRAMERATE: i32 = 30;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub(crate) struct VideoStream {
     pub name: String,
     pub codec: String,
     pub bitrate: u64,
     pub width: u64,
     pub height: u64,
+    /// Overrides the segment duration of the output, in seconds. Not with ad breaks, their
+    /// splice points need the segment boundaries of all renditions to line up
+    pub segment_duration: Option<f64>,
+    /// Overrides the window size of the output
+    pub window_size: Option<usize>,
+}
+
+impl Default for VideoStream {
+    fn default() -> Self {
+        VideoStream {
+            name: String::new(),
+            codec: "h264".to_string(),
+            bitrate: 1_024_000,
+            width: 640,
+            height: 360,
+            segment_duration: None,
+            window_size: None,
+        }
+    }
 }
 
 impl VideoStream {
+    pub fn default_ladder() -> Vec<Self> {
+        vec![
+            VideoStream {
+                name: "av1_0".to_string(),
+                codec: "av1".to_string(),
+                bitrate: 1_024_000,
+                width: 256,
+                height: 144,
+                ..Default::default()
+            },
+            VideoStream {
+                name: "h265_0".to_string(),
+                codec: "h265".to_string(),
+                bitrate: 1_024_000,
+                width: 640,
+                height: 360,
+                ..Default::default()
+            },
+            VideoStream {
+                name: "h264_0".to_string(),
+                codec: "h264".to_string(),
+                bitrate: 1_024_000,
+                width: 640,
+                height: 360,
+                ..Default::default()
+            },
+        ]
+    }
+
     pub fn setup(
         &self,
         state: Arc<Mutex<State>>,
         pipeline: &gst::Pipeline,
-        path: &Path,
-    ) -> Result<(), Error> {
-        let src = gst::ElementFactory::make("videotestsrc")
-            .property("is-live", true)
-            .build()?;
+        source: &mut source::Source,
+        output: &hlscmaf::Output,
+    ) -> Result<utils::Branch, Error> {
+        let settings = &output
+            .settings
+            .with_overrides(self.segment_duration, self.window_size);
 
+        let queue = gst::ElementFactory::make("queue").build()?;
+        let videoscale = gst::ElementFactory::make("videoscale").build()?;
+        let videorate = gst::ElementFactory::make("videorate").build()?;
         let raw_capsfilter = gst::ElementFactory::make("capsfilter")
             .property(
                 "caps",
@@ -31,64 +88,126 @@ impl VideoStream {
                     .format(gst_video::VideoFormat::I420)
                     .width(self.width as i32)
                     .height(self.height as i32)
-                    .framerate(30.into())
+                    .framerate(FRAMERATE.into())
                     .build(),
             )
             .build()?;
-        let timeoverlay = gst::ElementFactory::make("timeoverlay").build()?;
+        // Running time keeps increasing when a URI input loops, unlike the buffer timestamps
+        let timeoverlay = gst::ElementFactory::make("timeoverlay")
+            .property_from_str("time-mode", "running-time")
+            .build()?;
         let codec_burn_in = gst::ElementFactory::make("textoverlay")
             .property("text", &self.codec)
             .property("font-desc", "Sans 24")
             .build()?;
-        let Ok((enc, parser, capsfilter)) = Self::setup_codec(self) else { todo!() };
-
-        let mux = gst::ElementFactory::make("isofmp4mux")
-            .property("fragment-duration", 2000.mseconds())
-            .property_from_str("header-update-mode", "update")
-            .property("write-mehd", true)
-            .build()?;
-        let appsink = gst_app::AppSink::builder().buffer_list(true).build();
+        let (enc, parser, capsfilter) = Self::setup_codec(self, settings)?;
+        // Only the H.264 and H.265 encoders write captions into SEI
+        if let Some(captions) = output.captions.filter(|_| self.codec != "av1") {
+            captions::setup(captions, &self.name, &enc);
+        }
+        scte35::force_keyframes(&enc, &output.splices, &output.origin);
 
         pipeline.add_many([
-            &src,
+            &queue,
+            &videoscale,
+            &videorate,
             &raw_capsfilter,
             &timeoverlay,
             &codec_burn_in,
             &enc,
             &parser,
             &capsfilter,
-            &mux,
-            appsink.upcast_ref(),
         ])?;
 
         gst::Element::link_many([
-            &src,
+            &queue,
+            &videoscale,
+            &videorate,
             &raw_capsfilter,
             &timeoverlay,
             &codec_burn_in,
             &enc,
             &parser,
             &capsfilter,
-            &mux,
-            appsink.upcast_ref(),
         ])?;
 
-        utils::probe_encoder(state, enc, self.name.clone());
+        utils::probe_encoder(state.clone(), enc.clone(), self.name.clone());
+
+        let mut elements = vec![
+            queue,
+            videoscale,
+            videorate,
+            raw_capsfilter,
+            timeoverlay,
+            codec_burn_in,
+            enc,
+            parser,
+            capsfilter.clone(),
+        ];
+
+        // The encoded stream goes to the fMP4 muxer, the MPEG-TS muxer or both of them. HLS
+        // doesn't know AV1 in MPEG-TS.
+        let mut heads = vec![];
+        if output.format.has_cmaf() {
+            let mux = gst::ElementFactory::make("isofmp4mux")
+                .property("fragment-duration", settings.segment_duration)
+                .property_from_str("header-update-mode", "update")
+                .property("write-mehd", true)
+                .build()?;
+            // Low-Latency HLS parts
+            if let Some(part_duration) = settings.part_duration {
+                mux.set_property("chunk-duration", part_duration);
+            }
+            // Without a live source nothing would ever preroll the sink before its first
+            // fragment
+            let appsink = gst_app::AppSink::builder().buffer_list(true).async_(false).build();
 
-        hlscmaf::setup(&appsink, &self.name, path);
+            pipeline.add_many([&mux, appsink.upcast_ref()])?;
+            mux.link(&appsink)?;
 
-        Ok(())
+            let name = self.name.clone();
+            let on_iframe_peak = Box::new(move |peak| {
+                let mut state = state.lock().unwrap();
+                state.iframe_bandwidths.insert(name.clone(), peak);
+                // Written again if it was already, otherwise once all codecs are known
+                state.wrote_manifest = false;
+                state.try_write_manifest();
+            });
+            hlscmaf::setup_video(&appsink, &self.name, output, settings, on_iframe_peak);
+
+            heads.push(mux.clone());
+            elements.extend([mux, appsink.upcast()]);
+        }
+        if output.format.has_ts() && self.codec != "av1" {
+            // The fMP4 muxer takes the stream as avc or hvc1, the MPEG-TS muxer as byte-stream
+            let parser = format!("{}parse", self.codec);
+            let (ts_elements, appsink) = utils::ts_mux(pipeline, Some(&parser))?;
+
+            hlscmaf::setup_ts(&appsink, &self.name, output, settings, true);
+
+            heads.push(ts_elements[0].clone());
+            elements.extend(ts_elements);
+        }
+        elements.extend(utils::fan_out(pipeline, &capsfilter, &heads)?);
+
+        utils::Branch::new(source.video_pad()?, elements)
     }
 
-    fn setup_codec(&self) -> Result<(gst::Element, gst::Element, gst::Element), Error> {
+    fn setup_codec(&self, settings: &hlscmaf::Settings) -> Result<(gst::Element, gst::Element, gst::Element), Error> {
         let mut _enc: gst::Element;
         let mut _parser: gst::Element;
         let mut _capsfilter: gst::Element;
 
+        // One keyframe per segment so the muxer can cut at the configured duration
+        let key_int = (settings.segment_duration.nseconds() * FRAMERATE as u64
+            / gst::ClockTime::SECOND.nseconds())
+        .max(1);
+
         match self.codec.as_ref() {
             "h264" => {
                 _enc = gst::ElementFactory::make("x264enc")
                     .property("bframes", 0u32)
+                    .property("key-int-max", key_int as u32)
                     .property("bitrate", self.bitrate as u32 / 1000u32)
                     .property_from_str("tune", "zerolatency")
                     .build()?;
@@ -105,6 +224,7 @@ impl VideoStream {
             }
             "h265" => {
                 _enc = gst::ElementFactory::make("x265enc")
+                    .property("key-int-max", key_int as i32)
                     .property("bitrate", self.bitrate as u32 / 1000u32)
                     .property_from_str("tune", "zerolatency")
                     .build()?;
@@ -123,7 +243,7 @@ impl VideoStream {
                 _enc = gst::ElementFactory::make("rav1enc")
                 .property("speed-preset", 10u32)
                 .property("low-latency", true)
-                .property("max-key-frame-interval", 60u64)
+                .property("max-key-frame-interval", key_int)
                 .property("bitrate", self.bitrate as i32)
                 .build()?;
                 _parser = gst::ElementFactory::make("av1parse").build()?;
@@ -137,7 +257,32 @@ impl VideoStream {
                     .build()?;
                 Ok((_enc, _parser, _capsfilter))
             }
-            &_ => todo!()
+            codec => Err(anyhow!("unknown video codec '{}'", codec)),
         }
     }
 }
+
+impl FromStr for VideoStream {
+    type Err = Error;
+
+    // Parses `codec=h264,bitrate=1024000,width=640,height=360`, every key is optional.
+    // An empty name is filled in by the caller once all renditions are known.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stream = VideoStream::default();
+
+        for (key, value) in utils::parse_spec(s)? {
+            match key {
+                "name" => stream.name = value.to_string(),
+                "codec" => stream.codec = value.to_string(),
+                "bitrate" => stream.bitrate = value.parse()?,
+                "width" => stream.width = value.parse()?,
+                "height" => stream.height = value.parse()?,
+                "segment_duration" => stream.segment_duration = Some(value.parse()?),
+                "window_size" => stream.window_size = Some(value.parse()?),
+                _ => return Err(anyhow!("unknown video stream property '{}'", key)),
+            }
+        }
+
+        Ok(stream)
+    }
+}